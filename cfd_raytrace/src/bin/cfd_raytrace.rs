@@ -0,0 +1,578 @@
+use anyhow::Context;
+use cfd_raytrace::{
+    binary::{self, Precision},
+    config::{InputConfig, InterpolationConfig, OutputConfig, RayTracingConfig},
+    gmt::{Gmt, Source},
+    storage, Column, CompressedCsvReader, CsvSchema, ExportFormat, FromCompressedCsv, GridSampling,
+    LoadOptions, OpdFile, Quadrature, RayGeometry, RayTracer, RigidTransform, RunConfig,
+    TemperatureVelocityField, Unit,
+};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::{io::Cursor, time::Instant};
+
+/// GMT CFD optical turbulence ray tracing
+///
+/// Files are given as storage URLs: `path/to/file`, `file:///path/to/file`
+/// or `s3://bucket/key?region=us-east-2` (with the `s3` feature)
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Ray traces the CFD optical turbulence, saving the OPD
+    Trace(Trace),
+    /// Runs the ray tracing described in a TOML or YAML run configuration file
+    Run {
+        /// run configuration file URL
+        config: String,
+        /// index of the CFD csv.gz file, overrides the configuration file
+        #[arg(long, env = "AWS_BATCH_JOB_ARRAY_INDEX")]
+        cfd_index: Option<usize>,
+        /// prints the run configuration without running it
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Ray traces as described in a run configuration file with the ray tracing step
+    /// halved several times, printing the OPD change at each step
+    Convergence {
+        /// run configuration file URL
+        config: String,
+        /// index of the CFD csv.gz file, overrides the configuration file
+        #[arg(long, env = "AWS_BATCH_JOB_ARRAY_INDEX")]
+        cfd_index: Option<usize>,
+        /// number of ray tracing steps
+        #[arg(long, default_value_t = 4)]
+        n_step: usize,
+    },
+    /// Generates the GMT ray tracing parameters npz file
+    Pupil {
+        /// npz file URL
+        output: String,
+        /// exit pupil sampling
+        #[arg(long, default_value_t = 1031)]
+        n_px: usize,
+        /// source angle from the optical axis [arcmin]
+        #[arg(long, default_value_t = 0.)]
+        zenith: f64,
+        /// source azimuth angle [deg]
+        #[arg(long, default_value_t = 0.)]
+        azimuth: f64,
+        /// ray tracing parameters npz file URL to compare the generated parameters with
+        #[arg(long)]
+        compare: Option<String>,
+    },
+    /// Lists the arrays in a Numpy npz ray tracing parameters file
+    InspectNpz {
+        /// npz file URL
+        url: String,
+    },
+    /// Lists the columns and the samples range of a compressed CFD csv file
+    InspectCsv {
+        /// csv.gz file URL
+        url: String,
+        /// number of samples to print
+        #[arg(short, long, default_value_t = 0)]
+        rows: usize,
+    },
+    /// Converts files: CFD csv.gz to binary cache (.cfd)
+    /// and OPD (.bin) to pickle (.pkl), Numpy (.npy, .npz) or FITS (.fits)
+    Convert {
+        /// input file URL
+        input: String,
+        /// output file URL, defaults to the input with the new extension
+        output: Option<String>,
+        /// floating point precision of the CFD binary cache
+        #[arg(long, value_enum, default_value_t = PrecisionArg::Double)]
+        precision: PrecisionArg,
+        /// OPD export format, defaults to the output file extension or to pickle
+        #[arg(long, value_enum)]
+        format: Option<FormatArg>,
+        /// run configuration file URL, the CFD csv columns are read from its `schema` section,
+        /// the default columns are used otherwise
+        #[arg(long)]
+        config: Option<String>,
+    },
+    /// Prints the metadata of an OPD file
+    InspectOpd {
+        /// OPD file URL
+        url: String,
+    },
+    /// Prints the statistics of OPD files
+    Stats {
+        /// OPD file URLs
+        #[arg(required = true)]
+        urls: Vec<String>,
+    },
+}
+
+#[derive(Args)]
+struct Trace {
+    /// ray tracing parameters npz file URL
+    #[arg(long, env = "RAY_TRACER_URL")]
+    rays: String,
+    /// CFD csv.gz file URL or, with `--cfd-index`, the URL prefix of the CFD csv.gz files
+    #[arg(long, env = "CFD_URL")]
+    cfd: String,
+    /// index of the CFD csv.gz file among the files starting with the `--cfd` prefix
+    #[arg(long, env = "AWS_BATCH_JOB_ARRAY_INDEX")]
+    cfd_index: Option<usize>,
+    /// OPD directory URL, defaults to the CFD file directory
+    #[arg(short, long, env = "OPD_URL")]
+    output: Option<String>,
+    /// OPD file name template with the placeholders `{stem}`, `{index}` and `{n_px}`
+    #[arg(long, default_value = "{stem}.bin")]
+    name: String,
+    /// CFD data interpolation method
+    #[arg(long, value_enum, default_value_t = Interpolation::Shepard)]
+    interpolation: Interpolation,
+    /// Shepard interpolation radius [m]
+    #[arg(long, default_value_t = 0.5)]
+    shepard_radius: f64,
+    /// inverse distance weights power of the Shepard and k-nearest interpolations
+    #[arg(long, default_value_t = 2.)]
+    idw_power: f64,
+    /// number of nearest neighbors of the k-nearest and adaptive interpolations
+    #[arg(long, default_value_t = 8)]
+    k_nearest: usize,
+    /// scale factor of the distance to the k-th nearest neighbor of the adaptive interpolation
+    #[arg(long, default_value_t = 1.)]
+    adaptive_scale: f64,
+    /// support radius of the Wendland and Gaussian interpolations [m]
+    #[arg(long, default_value_t = 0.5)]
+    rbf_radius: f64,
+    /// standard deviation of the Gaussian interpolation [m]
+    #[arg(long, default_value_t = 0.2)]
+    gaussian_sigma: f64,
+    /// voxel grid resolution [m]
+    #[arg(long, default_value_t = 0.25)]
+    grid_resolution: f64,
+    /// ray tracing step [m]
+    #[arg(long, default_value_t = 0.25)]
+    ray_tracing_step: f64,
+    /// integration rule of the refraction index along each ray
+    #[arg(long, value_enum, default_value_t = QuadratureArg::Riemann)]
+    quadrature: QuadratureArg,
+    /// wavelength [micron]
+    #[arg(long, default_value_t = 0.5)]
+    wavelength: f64,
+    /// number of threads, defaults to the number of CPUs
+    #[arg(long)]
+    n_thread: Option<usize>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Interpolation {
+    /// Shepard interpolation within `--shepard-radius`
+    Shepard,
+    /// Nearest neighbor interpolation
+    Nearest,
+    /// Inverse distance weighting of the `--k-nearest` nearest neighbors
+    KNearest,
+    /// Shepard interpolation within `--adaptive-scale` times the distance
+    /// to the `--k-nearest` nearest neighbor
+    Adaptive,
+    /// Wendland radial basis function within `--rbf-radius`
+    Wendland,
+    /// Gaussian radial basis function of `--gaussian-sigma` within `--rbf-radius`
+    Gaussian,
+    /// Barycentric interpolation within the Delaunay tetrahedralization of the CFD samples
+    Delaunay,
+    /// Trilinear interpolation on a voxel grid of `--grid-resolution`
+    Trilinear,
+    /// Tricubic interpolation on a voxel grid of `--grid-resolution`
+    Tricubic,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum QuadratureArg {
+    /// Sum of the samples at the end of each step
+    Riemann,
+    /// Trapezoidal rule
+    Trapezoidal,
+    /// Composite Simpson's rule
+    Simpson,
+}
+impl From<QuadratureArg> for Quadrature {
+    fn from(quadrature: QuadratureArg) -> Self {
+        match quadrature {
+            QuadratureArg::Riemann => Quadrature::Riemann,
+            QuadratureArg::Trapezoidal => Quadrature::Trapezoidal,
+            QuadratureArg::Simpson => Quadrature::Simpson,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum PrecisionArg {
+    Single,
+    Double,
+}
+impl From<PrecisionArg> for Precision {
+    fn from(precision: PrecisionArg) -> Self {
+        match precision {
+            PrecisionArg::Single => Precision::Single,
+            PrecisionArg::Double => Precision::Double,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum FormatArg {
+    Pkl,
+    Npy,
+    Npz,
+    Fits,
+}
+impl From<FormatArg> for ExportFormat {
+    fn from(format: FormatArg) -> Self {
+        match format {
+            FormatArg::Pkl => ExportFormat::Pickle,
+            FormatArg::Npy => ExportFormat::Npy,
+            FormatArg::Npz => ExportFormat::Npz,
+            FormatArg::Fits => ExportFormat::Fits,
+        }
+    }
+}
+
+/// Replaces the extension(s) of the object `key` with `extension`
+fn with_extension(key: &str, extension: &str) -> String {
+    let stem = key
+        .strip_suffix(".csv.gz")
+        .unwrap_or_else(|| key.rsplit_once('.').map_or(key, |(stem, _)| stem));
+    format!("{stem}.{extension}")
+}
+
+fn load_opd(url: &str) -> anyhow::Result<OpdFile> {
+    OpdFile::load(url).with_context(|| format!("failed to load {url}"))
+}
+
+impl From<Trace> for RunConfig {
+    fn from(trace: Trace) -> Self {
+        Self {
+            input: InputConfig {
+                cfd: trace.cfd,
+                cfd_index: trace.cfd_index,
+                rays: trace.rays,
+                pointing: None,
+            },
+            schema: Default::default(),
+            ray_tracing: RayTracingConfig {
+                interpolation: match trace.interpolation {
+                    Interpolation::Shepard => InterpolationConfig::Shepard {
+                        radius: trace.shepard_radius,
+                        power: trace.idw_power,
+                    },
+                    Interpolation::Nearest => InterpolationConfig::Nearest,
+                    Interpolation::KNearest => InterpolationConfig::KNearest {
+                        k: trace.k_nearest,
+                        power: trace.idw_power,
+                    },
+                    Interpolation::Adaptive => InterpolationConfig::Adaptive {
+                        k: trace.k_nearest,
+                        scale: trace.adaptive_scale,
+                        power: trace.idw_power,
+                        min_radius: None,
+                        max_radius: None,
+                    },
+                    Interpolation::Wendland => InterpolationConfig::Wendland {
+                        radius: trace.rbf_radius,
+                    },
+                    Interpolation::Gaussian => InterpolationConfig::Gaussian {
+                        radius: trace.rbf_radius,
+                        sigma: trace.gaussian_sigma,
+                    },
+                    Interpolation::Delaunay => InterpolationConfig::Delaunay,
+                    Interpolation::Trilinear => InterpolationConfig::Grid {
+                        resolution: trace.grid_resolution,
+                        sampling: GridSampling::Trilinear,
+                    },
+                    Interpolation::Tricubic => InterpolationConfig::Grid {
+                        resolution: trace.grid_resolution,
+                        sampling: GridSampling::Tricubic,
+                    },
+                },
+                step: trace.ray_tracing_step,
+                quadrature: trace.quadrature.into(),
+                wavelength: trace.wavelength,
+                n_thread: trace.n_thread,
+                ..Default::default()
+            },
+            output: OutputConfig {
+                url: trace.output,
+                name: trace.name,
+            },
+        }
+    }
+}
+
+fn run(config: RunConfig) -> anyhow::Result<()> {
+    let now = Instant::now();
+    let (opd_file, key) = config.run()?;
+    println!(
+        "{} OPD samples saved to {key} in {}s",
+        opd_file.opd.values.len(),
+        now.elapsed().as_secs()
+    );
+    if let Some(metadata) = &opd_file.metadata {
+        println!("interpolation: {}", metadata.interpolation);
+    }
+    Ok(())
+}
+
+fn pupil(output: &str, n_px: usize, source: Source, compare: Option<String>) -> anyhow::Result<()> {
+    let geometry = Gmt::default().rays(n_px, source)?;
+    println!(
+        "{} rays within the exit pupil out of {}",
+        geometry.mask.iter().filter(|m| **m).count(),
+        geometry.mask.len()
+    );
+    if let Some(url) = compare {
+        let deviation = geometry.deviation(&RayGeometry::from_npz(&url)?)?;
+        println!(
+            "{} rays not in both exit pupil masks",
+            deviation.mask_mismatch
+        );
+        for (k, (xyz, klm)) in deviation.xyz.iter().zip(&deviation.klm).enumerate() {
+            println!("surface #{k}: max. xyz deviation {xyz:.3e}m, max. klm deviation {klm:.3e}");
+        }
+    }
+    geometry.to_npz_url(output)?;
+    println!("-> {output}");
+    Ok(())
+}
+
+fn inspect_npz(url: &str) -> anyhow::Result<()> {
+    let (storage, key) = storage::from_url(url)?;
+    let mut archive = npyz::npz::NpzArchive::new(Cursor::new(storage.get(&key)?))?;
+    let names: Vec<_> = archive.array_names().map(|x| x.to_owned()).collect();
+    for name in names {
+        if let Some(data) = archive.by_name(&name)? {
+            println!("{name}: {:?} {}", data.shape(), data.dtype().descr());
+        }
+    }
+    let ray_tracer = RayTracer::from_npz_in(storage.as_ref(), &key)?;
+    println!(
+        "{} rays, {} surfaces, {} legs",
+        ray_tracer.n_sample(),
+        ray_tracer.xyz.len(),
+        ray_tracer.n_leg()
+    );
+    Ok(())
+}
+
+/// CFD sample field
+type Field = dyn Fn(&TemperatureVelocityField) -> Option<f64>;
+
+fn inspect_csv(url: &str, rows: usize) -> anyhow::Result<()> {
+    let (storage, key) = storage::from_url(url)?;
+    // the optvol pressure and density columns are loaded if they are present
+    let headers = CompressedCsvReader::new(storage.reader(&key)?)?
+        .headers()
+        .clone();
+    let present = |column: &Column| headers.iter().any(|header| header.trim() == column.header);
+    let mut schema = CsvSchema::default();
+    let pressure = Column::new("Absolute Pressure (Pa)", Unit::Pascal);
+    if present(&pressure) {
+        schema = schema.with_pressure(pressure);
+    }
+    let density = Column::new("Density (kg/m^3)", Unit::KilogramPerCubicMeter);
+    if present(&density) {
+        schema = schema.with_density(density);
+    }
+    let samples = Vec::<TemperatureVelocityField>::from_gz_in(
+        storage.as_ref(),
+        &key,
+        LoadOptions::default().schema(schema),
+    )?;
+    println!("{} samples", samples.len());
+    let range = |value: &Field| {
+        samples
+            .iter()
+            .filter_map(value)
+            .fold(None, |range: Option<(f64, f64)>, x| match range {
+                Some((min, max)) => Some((min.min(x), max.max(x))),
+                None => Some((x, x)),
+            })
+    };
+    let fields: [(&str, &Field); 7] = [
+        ("x [m]", &|s| Some(s.coordinates()[0])),
+        ("y [m]", &|s| Some(s.coordinates()[1])),
+        ("z [m]", &|s| Some(s.coordinates()[2])),
+        ("temperature [K]", &|s| Some(s.temperature())),
+        ("velocity [m/s]", &|s| s.velocity()),
+        ("pressure [Pa]", &|s| s.pressure()),
+        ("density [kg/m^3]", &|s| s.density()),
+    ];
+    for (name, value) in fields {
+        if let Some((min, max)) = range(value) {
+            println!("{name:>16}: [{min:.6}, {max:.6}]");
+        }
+    }
+    samples.iter().take(rows).for_each(|s| println!("{s:?}"));
+    Ok(())
+}
+
+fn convert(
+    input: &str,
+    output: Option<String>,
+    precision: Precision,
+    format: Option<ExportFormat>,
+    schema: CsvSchema,
+) -> anyhow::Result<()> {
+    let (storage, key) = storage::from_url(input)?;
+    let (data, output_key) = if key.ends_with(".csv.gz") {
+        // the samples are kept in the CFD frame
+        let options = LoadOptions::default()
+            .schema(schema)
+            .transform(RigidTransform::identity());
+        let samples =
+            Vec::<TemperatureVelocityField>::from_gz_in(storage.as_ref(), &key, options.clone())?;
+        let mut data = vec![];
+        binary::write_binary(&mut data, &samples, precision, &options)?;
+        (
+            data,
+            binary::cache_path(&key).to_string_lossy().into_owned(),
+        )
+    } else if key.ends_with(".bin") {
+        let format = format
+            .or_else(|| output.as_deref().and_then(ExportFormat::from_key))
+            .unwrap_or(ExportFormat::Pickle);
+        let data = load_opd(input)?.export(format)?;
+        (data, with_extension(&key, format.extension()))
+    } else {
+        anyhow::bail!("cannot convert {input}, expected a .csv.gz or a .bin file")
+    };
+    let (storage, output_key) = match output {
+        Some(url) => storage::from_url(&url)?,
+        None => (storage, output_key),
+    };
+    storage.put(&output_key, &data)?;
+    println!("{input} -> {output_key}");
+    Ok(())
+}
+
+fn inspect_opd(url: &str) -> anyhow::Result<()> {
+    let opd_file = load_opd(url)?;
+    println!("file format version: {}", opd_file.version());
+    println!("n_px: {}", opd_file.n_px());
+    println!("{} OPD samples", opd_file.opd.values.len());
+    match &opd_file.metadata {
+        Some(metadata) => println!("{metadata:#?}"),
+        None => println!("no metadata"),
+    }
+    Ok(())
+}
+
+fn stats(urls: &[String]) -> anyhow::Result<()> {
+    println!(
+        "{:>8} {:>12} {:>12} {:>12}  file",
+        "n", "mean [nm]", "rms [nm]", "pv [nm]"
+    );
+    for url in urls {
+        let opd = load_opd(url)?.opd;
+        let n = opd.values.len();
+        let rms = (opd.values.iter().map(|x| x * x).sum::<f64>() / n as f64).sqrt();
+        let (min, max) = opd
+            .values
+            .iter()
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), &x| {
+                (min.min(x), max.max(x))
+            });
+        println!(
+            "{n:>8} {:>12.3} {:>12.3} {:>12.3}  {url}",
+            opd.mean * 1e9,
+            rms * 1e9,
+            (max - min) * 1e9
+        );
+    }
+    Ok(())
+}
+
+fn convergence(config: &RunConfig, n_step: usize) -> anyhow::Result<()> {
+    let now = Instant::now();
+    let convergence = config.step_convergence(n_step)?;
+    println!(
+        "{:>10} {:>12} {:>12}",
+        "step [m]", "rms [nm]", "change [nm]"
+    );
+    for c in convergence {
+        let change = c
+            .change
+            .map_or_else(|| "-".to_string(), |change| format!("{:.3}", change * 1e9));
+        println!("{:>10.4} {:>12.3} {:>12}", c.step, c.rms * 1e9, change);
+    }
+    println!("ray traced in {}s", now.elapsed().as_secs());
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    match Cli::parse().command {
+        Command::Trace(trace) => run(trace.into()),
+        Command::Run {
+            config,
+            cfd_index,
+            dry_run,
+        } => {
+            let mut config = RunConfig::from_url(config)?;
+            if cfd_index.is_some() {
+                config.input.cfd_index = cfd_index;
+            }
+            if dry_run {
+                print!("{}", config.to_toml()?);
+                Ok(())
+            } else {
+                run(config)
+            }
+        }
+        Command::Convergence {
+            config,
+            cfd_index,
+            n_step,
+        } => {
+            let mut config = RunConfig::from_url(config)?;
+            if cfd_index.is_some() {
+                config.input.cfd_index = cfd_index;
+            }
+            convergence(&config, n_step)
+        }
+        Command::Pupil {
+            output,
+            n_px,
+            zenith,
+            azimuth,
+            compare,
+        } => pupil(
+            &output,
+            n_px,
+            Source::new((zenith / 60.).to_radians(), azimuth.to_radians()),
+            compare,
+        ),
+        Command::InspectNpz { url } => inspect_npz(&url),
+        Command::InspectCsv { url, rows } => inspect_csv(&url, rows),
+        Command::Convert {
+            input,
+            output,
+            precision,
+            format,
+            config,
+        } => {
+            let schema = match config {
+                Some(url) => RunConfig::from_url(url)?.schema,
+                None => CsvSchema::default(),
+            };
+            convert(
+                &input,
+                output,
+                precision.into(),
+                format.map(Into::into),
+                schema,
+            )
+        }
+        Command::InspectOpd { url } => inspect_opd(&url),
+        Command::Stats { urls } => stats(&urls),
+    }
+}
@@ -0,0 +1,595 @@
+//! Binary format for CFD samples
+//!
+//! The binary format is made of a 32 bytes header followed by the samples.
+//! The header contains the magic number `CFDRTBIN`, the format version (u32),
+//! the precision in bytes (u8), the optional fields flags (u8), 2 bytes of padding
+//! the number of samples (u64) and the fingerprint of the csv schema the samples have been loaded with (u64),
+//! all in little endian, see [CsvSchema::fingerprint].
+//! The flags bits 0, 1 and 2 are set if the velocity, the pressure and the density are saved, respectively.
+//! Each sample is saved as the x, y and z coordinates in the CFD frame, the temperature and, optionally,
+//! the velocity, the pressure and the density.
+//! A missing optional field is saved as NaN.
+//! All the samples have the same size in bytes, so the file can be memory mapped.
+//!
+//! The binary cache of a csv file is used only if it is newer than the csv file
+//! and if it has been written with the requested csv schema.
+//! The cache does not depend on the transform and on the R-tree loading mode.
+
+use super::{
+    CsvSchema, Error, LoadMode, LoadOptions, Result, RigidTransform, TemperatureVelocityField,
+};
+use rstar::RTree;
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+const MAGIC: &[u8; 8] = b"CFDRTBIN";
+const VERSION: u32 = 2;
+const HEADER_SIZE: usize = 32;
+const VELOCITY: u8 = 1;
+const PRESSURE: u8 = 2;
+const DENSITY: u8 = 4;
+
+type Field = fn(&TemperatureVelocityField) -> Option<f64>;
+
+/// Floating point precision of the binary format
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Precision {
+    /// f32
+    Single,
+    /// f64
+    #[default]
+    Double,
+}
+impl Precision {
+    fn size(&self) -> usize {
+        match self {
+            Precision::Single => 4,
+            Precision::Double => 8,
+        }
+    }
+    fn encode(&self, value: f64, buffer: &mut Vec<u8>) {
+        match self {
+            Precision::Single => buffer.extend((value as f32).to_le_bytes()),
+            Precision::Double => buffer.extend(value.to_le_bytes()),
+        }
+    }
+    fn decode(&self, bytes: &[u8]) -> f64 {
+        match self {
+            Precision::Single => f32::from_le_bytes(bytes.try_into().unwrap()) as f64,
+            Precision::Double => f64::from_le_bytes(bytes.try_into().unwrap()),
+        }
+    }
+}
+
+/// Binary file header
+#[derive(Debug, Clone, Copy)]
+struct Header {
+    precision: Precision,
+    flags: u8,
+    n_sample: usize,
+    fingerprint: u64,
+}
+impl Header {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE);
+        bytes.extend(MAGIC);
+        bytes.extend(VERSION.to_le_bytes());
+        bytes.push(self.precision.size() as u8);
+        bytes.push(self.flags);
+        bytes.extend([0u8; 2]);
+        bytes.extend((self.n_sample as u64).to_le_bytes());
+        bytes.extend(self.fingerprint.to_le_bytes());
+        bytes
+    }
+    fn decode(bytes: &[u8; HEADER_SIZE]) -> Result<Self> {
+        if &bytes[..8] != MAGIC {
+            return Err(Error::Binary("invalid magic number".into()));
+        }
+        let version = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
+        if version != VERSION {
+            return Err(Error::Binary(format!("unsupported version {version}")));
+        }
+        let precision = match bytes[12] {
+            4 => Precision::Single,
+            8 => Precision::Double,
+            n => return Err(Error::Binary(format!("unsupported precision {n}"))),
+        };
+        Ok(Self {
+            precision,
+            flags: bytes[13],
+            n_sample: u64::from_le_bytes(bytes[16..24].try_into().unwrap()) as usize,
+            fingerprint: u64::from_le_bytes(bytes[24..32].try_into().unwrap()),
+        })
+    }
+    fn has(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+    /// Sample size in bytes
+    fn sample_size(&self) -> usize {
+        self.precision.size() * (4 + self.flags.count_ones() as usize)
+    }
+    fn decode_sample(&self, bytes: &[u8], index: usize) -> TemperatureVelocityField {
+        let size = self.precision.size();
+        let mut values = bytes.chunks(size).map(|x| self.precision.decode(x));
+        let mut next = || values.next().unwrap();
+        let xyz = [next(), next(), next()];
+        let temperature = next();
+        let mut optional = |flag: u8| {
+            if self.has(flag) {
+                Some(next()).filter(|v| !v.is_nan())
+            } else {
+                None
+            }
+        };
+        let velocity = optional(VELOCITY);
+        let pressure = optional(PRESSURE);
+        let density = optional(DENSITY);
+        TemperatureVelocityField::new(temperature, velocity, xyz, index)
+            .with_pressure(pressure)
+            .with_density(density)
+    }
+}
+
+/// Writes the CFD samples in the binary format
+///
+/// An optional field is saved only if at least one sample has it.
+/// `options` are the loading options the samples have been loaded with:
+/// the coordinates are transformed back to the CFD frame with the inverse of their transform
+/// and the fingerprint of their csv schema is saved in the header
+pub fn write_binary<'a, W, I>(
+    mut writer: W,
+    samples: I,
+    precision: Precision,
+    options: &LoadOptions,
+) -> Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = &'a TemperatureVelocityField>,
+{
+    let samples: Vec<_> = samples.into_iter().collect();
+    let fields: [(u8, Field); 3] = [
+        (VELOCITY, TemperatureVelocityField::velocity),
+        (PRESSURE, TemperatureVelocityField::pressure),
+        (DENSITY, TemperatureVelocityField::density),
+    ];
+    let header = Header {
+        precision,
+        flags: fields
+            .iter()
+            .filter(|(_, field)| samples.iter().any(|sample| field(sample).is_some()))
+            .fold(0, |flags, (flag, _)| flags | flag),
+        n_sample: samples.len(),
+        fingerprint: options.schema.fingerprint(),
+    };
+    writer.write_all(&header.encode())?;
+    let inverse = options.transform.inverse();
+    let mut buffer = Vec::with_capacity(header.sample_size());
+    for sample in samples {
+        buffer.clear();
+        inverse
+            .transform_point(sample.coordinates())
+            .into_iter()
+            .chain(Some(sample.temperature()))
+            .for_each(|x| precision.encode(x, &mut buffer));
+        for (flag, field) in &fields {
+            if header.has(*flag) {
+                precision.encode(field(sample).unwrap_or(f64::NAN), &mut buffer);
+            }
+        }
+        writer.write_all(&buffer)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Streaming reader of CFD samples in the binary format
+pub struct BinaryReader<R: Read> {
+    reader: R,
+    header: Header,
+    transform: RigidTransform,
+}
+impl<R: Read> BinaryReader<R> {
+    /// Creates a new reader, reading the header from the stream
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut bytes = [0u8; HEADER_SIZE];
+        reader.read_exact(&mut bytes)?;
+        let header = Header::decode(&bytes)?;
+        Ok(Self {
+            reader,
+            header,
+            transform: RigidTransform::default(),
+        })
+    }
+    /// Sets the transform from the CFD frame to the ray tracing frame
+    pub fn transform(mut self, transform: RigidTransform) -> Self {
+        self.transform = transform;
+        self
+    }
+    /// Returns the number of samples
+    pub fn len(&self) -> usize {
+        self.header.n_sample
+    }
+    /// Returns `true` if there is no sample
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Returns an iterator over the CFD samples
+    pub fn samples(&mut self) -> impl Iterator<Item = Result<TemperatureVelocityField>> + '_ {
+        let header = self.header;
+        let transform = self.transform;
+        let mut bytes = vec![0u8; header.sample_size()];
+        (0..header.n_sample).map(move |index| {
+            self.reader.read_exact(&mut bytes)?;
+            Ok(header.decode_sample(&bytes, index).transformed(&transform))
+        })
+    }
+    /// Collects the CFD samples into a vector
+    pub fn into_vec(mut self) -> Result<Vec<TemperatureVelocityField>> {
+        self.samples().collect()
+    }
+    /// Loads the CFD samples into a R-tree
+    pub fn into_rtree(mut self, mode: LoadMode) -> Result<RTree<TemperatureVelocityField>> {
+        mode.load(self.samples())
+    }
+}
+
+/// Memory mapped CFD samples in the binary format
+#[cfg(feature = "mmap")]
+pub struct MmapBinary {
+    mmap: memmap2::Mmap,
+    header: Header,
+    transform: RigidTransform,
+}
+#[cfg(feature = "mmap")]
+impl MmapBinary {
+    /// Memory maps a binary file
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = File::open(path)?;
+        // Safety: the file is expected not to be modified while it is mapped
+        let mmap = unsafe { memmap2::Mmap::map(&file)? };
+        let header = Header::decode(
+            mmap.get(..HEADER_SIZE)
+                .and_then(|bytes| bytes.try_into().ok())
+                .ok_or_else(|| Error::Binary("truncated header".into()))?,
+        )?;
+        if mmap.len() < HEADER_SIZE + header.n_sample * header.sample_size() {
+            return Err(Error::Binary("truncated samples".into()));
+        }
+        Ok(Self {
+            mmap,
+            header,
+            transform: RigidTransform::default(),
+        })
+    }
+    /// Sets the transform from the CFD frame to the ray tracing frame
+    pub fn transform(mut self, transform: RigidTransform) -> Self {
+        self.transform = transform;
+        self
+    }
+    /// Returns the number of samples
+    pub fn len(&self) -> usize {
+        self.header.n_sample
+    }
+    /// Returns `true` if there is no sample
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Returns the sample #`index`
+    pub fn sample(&self, index: usize) -> Option<TemperatureVelocityField> {
+        (index < self.len()).then(|| {
+            let size = self.header.sample_size();
+            let offset = HEADER_SIZE + index * size;
+            self.header
+                .decode_sample(&self.mmap[offset..offset + size], index)
+                .transformed(&self.transform)
+        })
+    }
+    /// Returns an iterator over the CFD samples
+    pub fn samples(&self) -> impl Iterator<Item = TemperatureVelocityField> + '_ {
+        (0..self.len()).filter_map(|index| self.sample(index))
+    }
+    /// Loads the CFD samples into a R-tree
+    pub fn to_rtree(&self, mode: LoadMode) -> Result<RTree<TemperatureVelocityField>> {
+        mode.load(self.samples().map(Ok))
+    }
+}
+
+/// Interface to save CFD samples in the binary format
+pub trait ToBinary {
+    /// Saves the CFD samples loaded with `options` into a binary file
+    ///
+    /// The samples are saved in the order of their index, see [write_binary]
+    fn to_binary<P: AsRef<Path>>(
+        &self,
+        path: P,
+        precision: Precision,
+        options: &LoadOptions,
+    ) -> Result<()>;
+}
+impl ToBinary for [TemperatureVelocityField] {
+    fn to_binary<P: AsRef<Path>>(
+        &self,
+        path: P,
+        precision: Precision,
+        options: &LoadOptions,
+    ) -> Result<()> {
+        let mut samples: Vec<_> = self.iter().collect();
+        samples.sort_by_key(|sample| sample.index());
+        write_binary(
+            BufWriter::new(File::create(path)?),
+            samples,
+            precision,
+            options,
+        )
+    }
+}
+impl ToBinary for RTree<TemperatureVelocityField> {
+    fn to_binary<P: AsRef<Path>>(
+        &self,
+        path: P,
+        precision: Precision,
+        options: &LoadOptions,
+    ) -> Result<()> {
+        let mut samples: Vec<_> = self.iter().collect();
+        samples.sort_by_key(|sample| sample.index());
+        write_binary(
+            BufWriter::new(File::create(path)?),
+            samples,
+            precision,
+            options,
+        )
+    }
+}
+
+/// Interface to load CFD samples from a binary file
+pub trait FromBinary {
+    /// Loads the CFD samples from a binary file
+    ///
+    /// The csv schema of the loading options is ignored
+    fn from_binary<P: AsRef<Path>>(path: P, options: LoadOptions) -> Result<Self>
+    where
+        Self: Sized;
+}
+impl FromBinary for Vec<TemperatureVelocityField> {
+    /// Loads the CFD samples from a binary file
+    ///
+    /// The csv schema and the loading mode are ignored
+    fn from_binary<P: AsRef<Path>>(path: P, options: LoadOptions) -> Result<Self> {
+        BinaryReader::new(BufReader::new(File::open(path)?))?
+            .transform(options.transform)
+            .into_vec()
+    }
+}
+impl FromBinary for RTree<TemperatureVelocityField> {
+    fn from_binary<P: AsRef<Path>>(path: P, options: LoadOptions) -> Result<Self> {
+        BinaryReader::new(BufReader::new(File::open(path)?))?
+            .transform(options.transform)
+            .into_rtree(options.mode)
+    }
+}
+
+/// Returns the path to the binary cache of a compressed CFD csv file
+///
+/// The cache is next to the csv file with the extension `.cfd`
+/// replacing the `.csv.gz` extension
+pub fn cache_path<P: AsRef<Path>>(path: P) -> PathBuf {
+    let path = path.as_ref();
+    match path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_suffix(".csv.gz"))
+    {
+        Some(stem) => path.with_file_name(format!("{stem}.cfd")),
+        None => path.with_extension("cfd"),
+    }
+}
+
+/// Returns the path to the binary cache of a compressed CFD csv file
+/// if it is up to date and if it has been written with the csv `schema`
+pub(crate) fn fresh_cache<P: AsRef<Path>>(path: P, schema: &CsvSchema) -> Option<PathBuf> {
+    let cache = cache_path(&path);
+    let cache_modified = cache.metadata().and_then(|m| m.modified()).ok()?;
+    if matches!(
+        path.as_ref().metadata().and_then(|m| m.modified()),
+        Ok(csv_modified) if csv_modified > cache_modified
+    ) {
+        return None;
+    }
+    let mut bytes = [0u8; HEADER_SIZE];
+    File::open(&cache).ok()?.read_exact(&mut bytes).ok()?;
+    Header::decode(&bytes)
+        .ok()
+        .filter(|header| header.fingerprint == schema.fingerprint())
+        .map(|_| cache)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing, Column, Unit};
+    use std::{
+        io::Cursor,
+        time::{Duration, SystemTime},
+    };
+
+    fn samples() -> Vec<TemperatureVelocityField> {
+        testing::lattice([-1.; 3], [1.; 3], 0.5, |[x, y, z]| {
+            280. + x + 2. * y + 3. * z
+        })
+        .into_iter()
+        .map(|sample| {
+            let index = sample.index();
+            sample
+                .with_pressure(Some(75e3 + index as f64))
+                .with_density((index % 2 == 0).then_some(1.2))
+        })
+        .collect()
+    }
+
+    fn identity() -> LoadOptions {
+        LoadOptions::default().transform(RigidTransform::identity())
+    }
+
+    fn decode(data: Vec<u8>) -> Vec<TemperatureVelocityField> {
+        BinaryReader::new(Cursor::new(data))
+            .unwrap()
+            .transform(RigidTransform::identity())
+            .into_vec()
+            .unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let samples = samples();
+        let mut data = vec![];
+        write_binary(&mut data, &samples, Precision::Double, &identity()).unwrap();
+        assert_eq!(data.len(), HEADER_SIZE + samples.len() * 8 * 7);
+        let decoded = decode(data);
+        assert_eq!(decoded.len(), samples.len());
+        for (a, b) in samples.iter().zip(&decoded) {
+            assert_eq!(a.index(), b.index());
+            assert_eq!(a.coordinates(), b.coordinates());
+            assert_eq!(a.temperature(), b.temperature());
+            assert_eq!(a.velocity(), b.velocity());
+            assert_eq!(a.pressure(), b.pressure());
+            assert_eq!(a.density(), b.density());
+        }
+    }
+
+    #[test]
+    fn single_precision_round_trip() {
+        let samples: Vec<_> = testing::lattice([0.; 3], [1.; 3], 0.5, |_| 285.);
+        let mut data = vec![];
+        write_binary(&mut data, &samples, Precision::Single, &identity()).unwrap();
+        // no pressure and no density
+        assert_eq!(data.len(), HEADER_SIZE + samples.len() * 4 * 5);
+        for (a, b) in samples.iter().zip(&decode(data)) {
+            assert_eq!(a.coordinates(), b.coordinates());
+            assert_eq!(a.temperature(), b.temperature());
+            assert_eq!(b.pressure(), None);
+        }
+    }
+
+    #[test]
+    fn cfd_frame() {
+        let transform = RigidTransform::pointing(0.5, 1.);
+        let samples: Vec<_> = samples()
+            .into_iter()
+            .map(|sample| sample.transformed(&transform))
+            .collect();
+        let mut data = vec![];
+        write_binary(
+            &mut data,
+            &samples,
+            Precision::Double,
+            &LoadOptions::default().transform(transform),
+        )
+        .unwrap();
+        // the binary file is in the CFD frame
+        for (a, b) in testing::lattice([-1.; 3], [1.; 3], 0.5, |_| 0.)
+            .iter()
+            .zip(decode(data.clone()))
+        {
+            let error = a
+                .coordinates()
+                .iter()
+                .zip(b.coordinates())
+                .map(|(a, b)| (a - b).abs())
+                .fold(0., f64::max);
+            assert!(error < 1e-14);
+        }
+        let decoded = BinaryReader::new(Cursor::new(data))
+            .unwrap()
+            .transform(transform)
+            .into_vec()
+            .unwrap();
+        for (a, b) in samples.iter().zip(&decoded) {
+            let error = a
+                .coordinates()
+                .iter()
+                .zip(b.coordinates())
+                .map(|(a, b)| (a - b).abs())
+                .fold(0., f64::max);
+            assert!(error < 1e-14);
+        }
+    }
+
+    #[test]
+    fn invalid_header() {
+        let mut data = vec![];
+        write_binary(&mut data, &samples(), Precision::Double, &identity()).unwrap();
+        data[0] = b'X';
+        assert!(matches!(
+            BinaryReader::new(Cursor::new(data)),
+            Err(Error::Binary(_))
+        ));
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn mmap_round_trip() {
+        let samples = samples();
+        let path = std::env::temp_dir().join(format!("cfd_raytrace-{}.cfd", std::process::id()));
+        samples[..]
+            .to_binary(&path, Precision::Double, &identity())
+            .unwrap();
+        let mmap = MmapBinary::open(&path)
+            .unwrap()
+            .transform(RigidTransform::identity());
+        assert_eq!(mmap.len(), samples.len());
+        for (a, b) in samples.iter().zip(mmap.samples()) {
+            assert_eq!(a.coordinates(), b.coordinates());
+            assert_eq!(a.density(), b.density());
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn stale_cache() {
+        let dir = std::env::temp_dir().join(format!("cfd_raytrace-cache-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let csv = dir.join("optvol.csv.gz");
+        std::fs::write(&csv, b"").unwrap();
+        assert_eq!(cache_path(&csv), dir.join("optvol.cfd"));
+        // no cache
+        assert_eq!(fresh_cache(&csv, &CsvSchema::default()), None);
+        let cache = cache_path(&csv);
+        samples()[..]
+            .to_binary(&cache, Precision::Single, &identity())
+            .unwrap();
+        File::options()
+            .write(true)
+            .open(&csv)
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(
+            fresh_cache(&csv, &CsvSchema::default()),
+            Some(cache.clone())
+        );
+        // the cache is written with another schema
+        let schema = CsvSchema::default().with_pressure(Column::new("p (Pa)", Unit::Pascal));
+        assert_eq!(fresh_cache(&csv, &schema), None);
+        samples()[..]
+            .to_binary(
+                &cache,
+                Precision::Single,
+                &identity().schema(schema.clone()),
+            )
+            .unwrap();
+        assert_eq!(fresh_cache(&csv, &schema), Some(cache.clone()));
+        assert_eq!(fresh_cache(&csv, &CsvSchema::default()), None);
+        // the csv file is modified after the cache
+        File::options()
+            .write(true)
+            .open(&csv)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(fresh_cache(&csv, &schema), None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,42 +1,95 @@
-use super::Result;
+use super::{
+    binary, storage, CsvSchema, FromBinary, GladstoneDale, Interpolator, RefractiveIndexModel,
+    Result, RigidTransform, ShepardInterpolation, Storage,
+};
+use crate::schema::ColumnIndices;
 use async_trait::async_trait;
 use flate2::read::GzDecoder;
 use rstar::{PointDistance, RTree, RTreeObject, AABB};
-use serde::Deserialize;
-use std::io::{Cursor, Read};
-use std::path::Path;
-
-// M1 vertez z coordinate in OSS reference frame
-const OSS_M1_VERTEX: f64 = 3.9;
+use std::io::Read;
+use std::path::PathBuf;
 
 /// A CFD tempature and velocity sample
-#[derive(Debug, Deserialize)]
+///
+/// All the fields are in SI units.
+/// The missing optional fields are stored as NaN to keep the samples small
+#[derive(Debug, Clone)]
 pub struct TemperatureVelocityField {
-    #[serde(rename = "Temperature (K)")]
     temperature: f64,
-    #[serde(rename = "Velocity: Magnitude (m/s)")]
-    #[allow(dead_code)]
-    velocity: Option<f64>,
-    #[serde(rename = "X (m)")]
-    x: f64,
-    #[serde(rename = "Y (m)")]
-    y: f64,
-    #[serde(rename = "Z (m)")]
-    z: f64,
+    velocity: f64,
+    pressure: f64,
+    density: f64,
+    coordinates: [f64; 3],
+    index: usize,
 }
 
 impl TemperatureVelocityField {
+    pub(crate) fn new(
+        temperature: f64,
+        velocity: Option<f64>,
+        coordinates: [f64; 3],
+        index: usize,
+    ) -> Self {
+        Self {
+            temperature,
+            velocity: velocity.unwrap_or(f64::NAN),
+            pressure: f64::NAN,
+            density: f64::NAN,
+            coordinates,
+            index,
+        }
+    }
+    /// Transforms the coordinates from the CFD frame to the ray tracing frame
+    pub(crate) fn transformed(self, transform: &RigidTransform) -> Self {
+        Self {
+            coordinates: transform.transform_point(self.coordinates),
+            ..self
+        }
+    }
+    pub(crate) fn with_pressure(self, pressure: Option<f64>) -> Self {
+        Self {
+            pressure: pressure.unwrap_or(f64::NAN),
+            ..self
+        }
+    }
+    pub(crate) fn with_density(self, density: Option<f64>) -> Self {
+        Self {
+            density: density.unwrap_or(f64::NAN),
+            ..self
+        }
+    }
     /// Returns the (x,y,z) coordinates
     ///
-    /// The coordinates are given with respect to M1 vertex
+    /// The coordinates are given in the ray tracing frame,
+    /// see [RigidTransform]
     pub fn coordinates(&self) -> [f64; 3] {
-        [self.x, self.y, self.z - OSS_M1_VERTEX]
+        self.coordinates
+    }
+    /// Returns the index of the sample in the CFD csv file
+    pub fn index(&self) -> usize {
+        self.index
+    }
+    /// Returns the temperature in K
+    pub fn temperature(&self) -> f64 {
+        self.temperature
+    }
+    /// Returns the velocity magnitude in m/s
+    pub fn velocity(&self) -> Option<f64> {
+        Some(self.velocity).filter(|x| !x.is_nan())
+    }
+    /// Returns the pressure in Pa
+    pub fn pressure(&self) -> Option<f64> {
+        Some(self.pressure).filter(|x| !x.is_nan())
+    }
+    /// Returns the density in kg/m<sup>3</sup>
+    pub fn density(&self) -> Option<f64> {
+        Some(self.density).filter(|x| !x.is_nan())
     }
     /// Returns the index of refraction
+    ///
+    /// The index of refraction is derived from the default [GladstoneDale] model at 0.5micron
     pub fn refraction_index(&self) -> f64 {
-        let pref = 75000.0;
-        let wlm = 0.5;
-        7.76e-7 * pref * (1. + 0.00752 / (wlm * wlm)) / self.temperature
+        GladstoneDale::default().refraction_index(self, 0.5)
     }
 }
 
@@ -74,65 +127,223 @@ impl PointDistance for TemperatureVelocityField {
     }
 }
 
+/// R-tree loading mode
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LoadMode {
+    /// Collects all the samples before bulk loading the R-tree
+    #[default]
+    Bulk,
+    /// Inserts the samples one at a time into the R-tree while they are decoded
+    ///
+    /// The samples are never all held in memory outside of the R-tree,
+    /// at the expense of a slower loading and of a less balanced R-tree
+    Bounded,
+}
+impl LoadMode {
+    /// Loads the CFD samples into a R-tree
+    pub(crate) fn load<I>(self, samples: I) -> Result<RTree<TemperatureVelocityField>>
+    where
+        I: Iterator<Item = Result<TemperatureVelocityField>>,
+    {
+        match self {
+            LoadMode::Bulk => Ok(RTree::bulk_load(samples.collect::<Result<Vec<_>>>()?)),
+            LoadMode::Bounded => {
+                let mut tree = RTree::new();
+                for sample in samples {
+                    tree.insert(sample?);
+                }
+                Ok(tree)
+            }
+        }
+    }
+}
+
+/// CFD data loading options
+#[derive(Debug, Default, Clone)]
+pub struct LoadOptions {
+    pub(crate) mode: LoadMode,
+    pub(crate) schema: CsvSchema,
+    pub(crate) transform: RigidTransform,
+}
+impl LoadOptions {
+    /// Sets the R-tree loading mode
+    pub fn mode(mut self, mode: LoadMode) -> Self {
+        self.mode = mode;
+        self
+    }
+    /// Sets the csv columns mapping
+    pub fn schema(mut self, schema: CsvSchema) -> Self {
+        self.schema = schema;
+        self
+    }
+    /// Sets the transform from the CFD frame to the ray tracing frame
+    pub fn transform(mut self, transform: RigidTransform) -> Self {
+        self.transform = transform;
+        self
+    }
+}
+
+/// Streaming reader of gzip compressed CFD csv data
+///
+/// The samples are deserialized straight from the gzip decoder
+pub struct CompressedCsvReader<R: Read> {
+    rdr: csv::Reader<GzDecoder<R>>,
+    headers: csv::StringRecord,
+    columns: ColumnIndices,
+    transform: RigidTransform,
+}
+impl<R: Read> CompressedCsvReader<R> {
+    /// Creates a new reader from a gzip compressed csv stream with the default [CsvSchema]
+    pub fn new(reader: R) -> Result<Self> {
+        Self::with_schema(reader, &CsvSchema::default())
+    }
+    /// Creates a new reader from a gzip compressed csv stream with the given [CsvSchema]
+    ///
+    /// Returns an error listing the csv headers if a required column is missing
+    pub fn with_schema(reader: R, schema: &CsvSchema) -> Result<Self> {
+        let mut rdr = csv::Reader::from_reader(GzDecoder::new(reader));
+        let headers = rdr.headers()?.clone();
+        let columns = schema.locate(&headers)?;
+        Ok(Self {
+            rdr,
+            headers,
+            columns,
+            transform: RigidTransform::default(),
+        })
+    }
+    /// Sets the transform from the CFD frame to the ray tracing frame
+    pub fn transform(mut self, transform: RigidTransform) -> Self {
+        self.transform = transform;
+        self
+    }
+    /// Returns the csv headers
+    pub fn headers(&self) -> &csv::StringRecord {
+        &self.headers
+    }
+    /// Returns an iterator over the CFD samples
+    ///
+    /// The samples are indexed according to their order in the csv file
+    pub fn samples(&mut self) -> impl Iterator<Item = Result<TemperatureVelocityField>> + '_ {
+        let headers = &self.headers;
+        let columns = &self.columns;
+        let transform = &self.transform;
+        self.rdr.records().enumerate().map(move |(index, record)| {
+            columns
+                .sample(headers, &record?, index)
+                .map(|sample| sample.transformed(transform))
+        })
+    }
+    /// Collects the CFD samples into a vector
+    pub fn into_vec(mut self) -> Result<Vec<TemperatureVelocityField>> {
+        self.samples().collect()
+    }
+    /// Loads the CFD samples into a R-tree
+    pub fn into_rtree(mut self, mode: LoadMode) -> Result<RTree<TemperatureVelocityField>> {
+        mode.load(self.samples())
+    }
+}
+
 /// Interface to compressed CFD optical turbulence csv file
+///
+/// The csv files are located with a storage URL, see [storage](crate::storage).
+/// The blocking and the async loaders are always available,
+/// both decode the csv file while it is read from the storage, see [Storage::reader]
 #[async_trait]
 pub trait FromCompressedCsv {
-    #[cfg(not(feature = "s3"))]
-    fn from_gz<P>(path: P) -> Result<Self>
+    /// Loads a csv file from a storage `url`
+    fn from_gz<U>(url: U) -> Result<Self>
     where
-        P: AsRef<Path> + std::convert::AsRef<str> + Send,
-        Self: Sized;
-    #[cfg(feature = "s3")]
-    async fn from_gz<P>(path: P) -> Result<Self>
+        U: AsRef<str>,
+        Self: Sized,
+    {
+        Self::from_gz_with(url, LoadOptions::default())
+    }
+    /// Loads a csv file from a storage `url` with the given loading options
+    fn from_gz_with<U>(url: U, options: LoadOptions) -> Result<Self>
+    where
+        U: AsRef<str>,
+        Self: Sized,
+    {
+        let (storage, key) = storage::from_url(url.as_ref())?;
+        Self::from_gz_in(storage.as_ref(), &key, options)
+    }
+    /// Loads the csv file `key` from `storage` with the given loading options
+    fn from_gz_in(storage: &dyn Storage, key: &str, options: LoadOptions) -> Result<Self>
     where
-        P: AsRef<Path> + std::convert::AsRef<str> + Send,
         Self: Sized;
-}
-#[async_trait]
-impl FromCompressedCsv for RTree<TemperatureVelocityField> {
-    #[cfg(not(feature = "s3"))]
-    /// Loads a csv file into a R-Tree
-    fn from_gz<P>(path: P) -> Result<Self>
+    /// Loads a csv file from a storage `url`
+    async fn from_gz_async<U>(url: U) -> Result<Self>
     where
-        P: AsRef<Path> + std::convert::AsRef<str> + Send,
+        U: AsRef<str> + Send,
+        Self: Sized,
     {
-        let file = std::fs::File::open(path)?;
-        let mut decoder = GzDecoder::new(file);
-        let mut bytes = Vec::new();
-        decoder.read_to_end(&mut bytes).unwrap();
-
-        let buff = Cursor::new(bytes);
-        let mut rdr = csv::Reader::from_reader(buff);
-        Ok(RTree::bulk_load(
-            rdr.deserialize()
-                .collect::<std::result::Result<Vec<TemperatureVelocityField>, csv::Error>>()?,
-        ))
-    }
-    #[cfg(feature = "s3")]
-    /// Loads a csv file into a R-Tree
-    async fn from_gz<P>(path: P) -> Result<Self>
+        Self::from_gz_with_async(url, LoadOptions::default()).await
+    }
+    /// Loads a csv file from a storage `url` with the given loading options
+    async fn from_gz_with_async<U>(url: U, options: LoadOptions) -> Result<Self>
     where
-        P: AsRef<Path> + std::convert::AsRef<str> + Send,
+        U: AsRef<str> + Send,
+        Self: Sized,
     {
-        use s3::bucket::Bucket;
-        use s3::creds::Credentials;
-        let bucket_name = "gmto.cfd.2022";
-        let region = "us-east-2".parse()?;
-        let credentials = Credentials::default().map_err(|e| s3::error::S3Error::Credentials(e))?;
-        let bucket = Bucket::new(bucket_name, region, credentials)?;
-        let (data, _) = bucket.get_object(path).await?;
-        let stream = std::io::Cursor::new(data);
-
-        let mut decoder = GzDecoder::new(stream);
-        let mut bytes = Vec::new();
-        decoder.read_to_end(&mut bytes).unwrap();
-
-        let buff = Cursor::new(bytes);
-        let mut rdr = csv::Reader::from_reader(buff);
-        Ok(RTree::bulk_load(
-            rdr.deserialize()
-                .collect::<std::result::Result<Vec<TemperatureVelocityField>, csv::Error>>()?,
-        ))
+        let (storage, key) = storage::from_url(url.as_ref())?;
+        Self::from_gz_in_async(storage.as_ref(), &key, options).await
+    }
+    /// Loads the csv file `key` from `storage` with the given loading options
+    ///
+    /// The csv file is streamed with [from_gz_in](FromCompressedCsv::from_gz_in),
+    /// the decoding blocks the calling task
+    async fn from_gz_in_async(
+        storage: &dyn Storage,
+        key: &str,
+        options: LoadOptions,
+    ) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        Self::from_gz_in(storage, key, options)
+    }
+}
+/// Opens the gzip compressed csv file `key`
+fn open_gz(
+    storage: &dyn Storage,
+    key: &str,
+    options: &LoadOptions,
+) -> Result<CompressedCsvReader<Box<dyn Read + Send>>> {
+    Ok(
+        CompressedCsvReader::with_schema(storage.reader(key)?, &options.schema)?
+            .transform(options.transform),
+    )
+}
+/// Returns the binary cache of the csv file `key` if it is local, up to date
+/// and written with the csv schema of the loading options
+fn fresh_cache(storage: &dyn Storage, key: &str, options: &LoadOptions) -> Option<PathBuf> {
+    storage
+        .local_path(key)
+        .and_then(|path| binary::fresh_cache(path, &options.schema))
+}
+impl FromCompressedCsv for Vec<TemperatureVelocityField> {
+    /// Loads a csv file into a vector, preserving the order of the samples
+    ///
+    /// The samples are loaded from the binary cache if it exists, is up to date
+    /// and has been written with the same csv schema.
+    /// The loading mode is ignored
+    fn from_gz_in(storage: &dyn Storage, key: &str, options: LoadOptions) -> Result<Self> {
+        match fresh_cache(storage, key, &options) {
+            Some(cache) => Self::from_binary(cache, options),
+            None => open_gz(storage, key, &options)?.into_vec(),
+        }
+    }
+}
+impl FromCompressedCsv for RTree<TemperatureVelocityField> {
+    /// Loads a csv file into a R-Tree
+    ///
+    /// The samples are loaded from the binary cache if it exists, is up to date
+    /// and has been written with the same csv schema
+    fn from_gz_in(storage: &dyn Storage, key: &str, options: LoadOptions) -> Result<Self> {
+        match fresh_cache(storage, key, &options) {
+            Some(cache) => Self::from_binary(cache, options),
+            None => open_gz(storage, key, &options)?.into_rtree(options.mode),
+        }
     }
 }
 
@@ -147,24 +358,167 @@ pub trait Shepard {
 }
 impl Shepard for rstar::RTree<TemperatureVelocityField> {
     fn shepard(&self, query_point: &[f64; 3], max_squared_radius: f64) -> Option<f64> {
-        let samples = self.locate_within_distance(query_point.clone(), max_squared_radius);
-        let mut num = None;
-        let mut denom = None;
-        for sample in samples {
-            let d2 = sample.distance_2(&query_point);
-            if d2 > 0f64 {
-                let rbf = d2.recip();
-                *num.get_or_insert(0f64) += rbf * sample.refraction_index();
-                *denom.get_or_insert(0f64) += rbf;
-            } else {
-                return Some(sample.refraction_index());
+        ShepardInterpolation::new(max_squared_radius.sqrt()).refraction_index(self, query_point)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn fixture(n: usize) -> (Vec<TemperatureVelocityField>, Vec<u8>) {
+        let samples = testing::lattice([0.; 3], [n as f64 - 1., 3., 3.], 1., |[x, y, z]| {
+            285. + 0.1 * x - 0.2 * y + 0.3 * z
+        });
+        let gz = testing::csv_gz(&samples);
+        (samples, gz)
+    }
+
+    fn reader(gz: &[u8]) -> CompressedCsvReader<&[u8]> {
+        CompressedCsvReader::new(gz)
+            .unwrap()
+            .transform(RigidTransform::identity())
+    }
+
+    fn sorted(tree: &RTree<TemperatureVelocityField>) -> Vec<TemperatureVelocityField> {
+        let mut samples: Vec<_> = tree.iter().cloned().collect();
+        samples.sort_by_key(|sample| sample.index());
+        samples
+    }
+
+    fn located(tree: &RTree<TemperatureVelocityField>, envelope: &AABB<[f64; 3]>) -> Vec<usize> {
+        let mut indices: Vec<_> = tree
+            .locate_in_envelope(envelope)
+            .map(|sample| sample.index())
+            .collect();
+        indices.sort_unstable();
+        indices
+    }
+
+    #[test]
+    fn sample_size() {
+        // 4 fields, 3 coordinates and the index
+        assert_eq!(std::mem::size_of::<TemperatureVelocityField>(), 64);
+    }
+
+    #[test]
+    fn transformed() {
+        let sample = TemperatureVelocityField::new(285., None, [1., 2., 7.9], 0)
+            .transformed(&RigidTransform::oss());
+        assert_eq!(sample.coordinates(), [1., 2., 4.]);
+        assert_eq!(sample.velocity(), None);
+    }
+
+    #[test]
+    fn streaming() {
+        let (samples, gz) = fixture(8);
+        let vec = reader(&gz).into_vec().unwrap();
+        let bulk = reader(&gz).into_rtree(LoadMode::Bulk).unwrap();
+        let bounded = reader(&gz).into_rtree(LoadMode::Bounded).unwrap();
+        for loaded in [vec, sorted(&bulk), sorted(&bounded)] {
+            assert_eq!(loaded.len(), samples.len());
+            for (loaded, sample) in loaded.iter().zip(&samples) {
+                assert_eq!(loaded.index(), sample.index());
+                assert_eq!(loaded.coordinates(), sample.coordinates());
+                assert_eq!(loaded.temperature(), sample.temperature());
+                assert_eq!(loaded.velocity(), sample.velocity());
             }
         }
-        match (num, denom) {
-            (Some(num), Some(denom)) => Some(num / denom),
-            _ => self
-                .nearest_neighbor(query_point)
-                .map(|nn| nn.refraction_index()),
+        let query = [3.2, 1.4, 2.6];
+        assert_eq!(
+            bulk.nearest_neighbor(&query).unwrap().index(),
+            bounded.nearest_neighbor(&query).unwrap().index()
+        );
+        let envelope = AABB::from_corners([1.5, 0.5, 0.5], [4.5, 2.5, 2.5]);
+        assert_eq!(located(&bulk, &envelope).len(), 3 * 2 * 2);
+        assert_eq!(located(&bulk, &envelope), located(&bounded, &envelope));
+    }
+
+    #[test]
+    fn bounded_memory() {
+        let (samples, gz) = fixture(1024);
+        let load = |mode: LoadMode| {
+            let (tree, peak, retained) =
+                testing::heap_usage(|| reader(&gz).into_rtree(mode).unwrap());
+            assert_eq!(tree.size(), samples.len());
+            (peak, retained)
+        };
+        let (bulk_peak, _) = load(LoadMode::Bulk);
+        let (bounded_peak, bounded_tree) = load(LoadMode::Bounded);
+        // the bounded loading holds the R-tree and the decoder buffers only
+        assert!(bounded_peak < bounded_tree + 128 * 1024);
+        // whereas the bulk loading also holds the decoded samples
+        let samples_size = samples.len() * std::mem::size_of::<TemperatureVelocityField>();
+        assert!(bounded_peak + samples_size / 2 < bulk_peak);
+    }
+
+    #[test]
+    fn cache_schema() {
+        let dir = std::env::temp_dir().join(format!("cfd_raytrace-schema-{}", std::process::id()));
+        let storage = crate::LocalStorage::new(&dir);
+        let (samples, gz) = fixture(2);
+        storage.put("optvol.csv.gz", &gz).unwrap();
+        // a cache of other samples written with a schema without the velocity
+        let schema = CsvSchema {
+            velocity: None,
+            ..Default::default()
+        };
+        let options = LoadOptions::default().transform(RigidTransform::identity());
+        let cached = &samples[..4];
+        let mut data = vec![];
+        binary::write_binary(
+            &mut data,
+            cached,
+            binary::Precision::Double,
+            &options.clone().schema(schema.clone()),
+        )
+        .unwrap();
+        storage.put("optvol.cfd", &data).unwrap();
+        let load = |options: LoadOptions| {
+            Vec::<TemperatureVelocityField>::from_gz_in(&storage, "optvol.csv.gz", options)
+                .unwrap()
+                .len()
+        };
+        assert_eq!(load(options.clone().schema(schema)), cached.len());
+        assert_eq!(load(options), samples.len());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Storage serving its objects through [Storage::reader] only
+    struct ReaderOnly(crate::MemoryStorage);
+    #[async_trait]
+    impl Storage for ReaderOnly {
+        fn get(&self, _key: &str) -> Result<Vec<u8>> {
+            panic!("the object is expected to be streamed")
+        }
+        fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+            self.0.put(key, data)
+        }
+        fn list(&self, prefix: &str) -> Result<Vec<String>> {
+            self.0.list(prefix)
+        }
+        fn reader(&self, key: &str) -> Result<Box<dyn Read + Send>> {
+            self.0.reader(key)
+        }
+    }
+
+    #[test]
+    fn async_streaming() {
+        use std::task::{Context, Poll, Waker};
+        let (samples, gz) = fixture(2);
+        let storage = ReaderOnly(crate::MemoryStorage::new().insert("optvol.csv.gz", gz));
+        let mut future = Vec::<TemperatureVelocityField>::from_gz_in_async(
+            &storage,
+            "optvol.csv.gz",
+            LoadOptions::default(),
+        );
+        match future
+            .as_mut()
+            .poll(&mut Context::from_waker(Waker::noop()))
+        {
+            Poll::Ready(loaded) => assert_eq!(loaded.unwrap().len(), samples.len()),
+            Poll::Pending => panic!("the csv file is expected to be decoded without waiting"),
         }
     }
 }
@@ -0,0 +1,683 @@
+//! Run configuration
+//!
+//! A run is described by a TOML or a YAML file, e.g.
+//! ```toml
+//! [input]
+//! cfd = "s3://gmto.cfd.2022/CASES/zen30az000_OS7/optvol/optvol_optvol?region=us-east-2"
+//! cfd_index = 0
+//! rays = "s3://cfd.archive/gs_onaxis_params_1031.u8.npz?region=us-east-2"
+//!
+//! # CFD csv columns, the columns that are not given are the default ones
+//! [schema]
+//! pressure = "Absolute Pressure (Pa)"
+//! # or with the unit given separately:
+//! # pressure = { header = "Absolute Pressure", unit = "Pa" }
+//!
+//! [ray_tracing]
+//! refractive_index = { model = "gladstone-dale", pressure = 75e3 }
+//! interpolation = { method = "shepard", radius = 0.5 }
+//! # or one of:
+//! # interpolation = { method = "k-nearest", k = 8, power = 2 }
+//! # interpolation = { method = "adaptive", k = 8, scale = 1.5, max_radius = 1 }
+//! # interpolation = { method = "wendland", radius = 0.5 }
+//! # interpolation = { method = "gaussian", radius = 0.5, sigma = 0.2 }
+//! # or the CFD data resampled on a voxel grid:
+//! # interpolation = { method = "grid", resolution = 0.25, sampling = "trilinear" }
+//! # or the barycentric interpolation within the Delaunay tetrahedralization of the CFD samples:
+//! # interpolation = { method = "delaunay" }
+//! step = 0.25
+//! quadrature = "simpson"
+//!
+//! [output]
+//! url = "s3://gmto.im.grim/CASES/zen30az000_OS7/optvol?region=us-west-2"
+//! name = "{n_px}/{stem}.bin"
+//! ```
+//! The output file name template accepts the placeholders `{stem}`, the CFD file name
+//! without the `.csv.gz` extension, `{index}`, the CFD file index, and `{n_px}`,
+//! the exit pupil sampling.
+
+use super::{
+    storage, AdaptiveInterpolation, Ciddor, CsvSchema, DelaunayInterpolation, Edlen, Error,
+    FromCompressedCsv, GladstoneDale, GridOptions, GridSampling, KNearestInterpolation,
+    LoadOptions, NearestNeighbor, OpdFile, OutOfDomain, Quadrature, RayTracer, RbfInterpolation,
+    Result, RigidTransform, ShepardInterpolation, StepConvergence, Storage,
+    TemperatureVelocityField, VoxelGrid,
+};
+use rstar::RTree;
+use serde::{Deserialize, Serialize};
+
+/// Run configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunConfig {
+    pub input: InputConfig,
+    /// CFD csv columns mapping
+    #[serde(default)]
+    pub schema: CsvSchema,
+    #[serde(default)]
+    pub ray_tracing: RayTracingConfig,
+    #[serde(default)]
+    pub output: OutputConfig,
+}
+
+/// Run inputs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InputConfig {
+    /// CFD csv.gz file URL or, with `cfd_index`, the URL prefix of the CFD csv.gz files
+    pub cfd: String,
+    /// index of the CFD csv.gz file among the files starting with the `cfd` prefix
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cfd_index: Option<usize>,
+    /// ray tracing parameters npz file URL
+    pub rays: String,
+    /// telescope pointing, the CFD samples are given in the GMT OSS frame if it is not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pointing: Option<Pointing>,
+}
+
+/// Telescope pointing in degrees, see [RigidTransform::pointing]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Pointing {
+    pub zenith: f64,
+    pub azimuth: f64,
+}
+
+/// Ray tracing parameters
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RayTracingConfig {
+    pub refractive_index: RefractiveIndexConfig,
+    pub interpolation: InterpolationConfig,
+    /// ray tracing step [m]
+    pub step: f64,
+    pub quadrature: Quadrature,
+    /// wavelength [micron]
+    pub wavelength: f64,
+    pub out_of_domain: OutOfDomain,
+    /// number of threads, defaults to the number of CPUs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n_thread: Option<usize>,
+}
+impl Default for RayTracingConfig {
+    fn default() -> Self {
+        Self {
+            refractive_index: Default::default(),
+            interpolation: Default::default(),
+            step: 0.25,
+            quadrature: Default::default(),
+            wavelength: 0.5,
+            out_of_domain: Default::default(),
+            n_thread: None,
+        }
+    }
+}
+
+/// Refractive index models
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(tag = "model", rename_all = "kebab-case")]
+pub enum RefractiveIndexConfig {
+    GladstoneDale(GladstoneDale),
+    Edlen(Edlen),
+    Ciddor(Ciddor),
+}
+impl Default for RefractiveIndexConfig {
+    fn default() -> Self {
+        Self::GladstoneDale(Default::default())
+    }
+}
+
+/// CFD data interpolation methods
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(tag = "method", rename_all = "kebab-case")]
+pub enum InterpolationConfig {
+    Nearest,
+    Shepard {
+        /// interpolation radius [m]
+        radius: f64,
+        /// inverse distance weights power
+        #[serde(default = "default_power")]
+        power: f64,
+    },
+    /// Inverse distance weighting of the k nearest neighbors
+    KNearest {
+        k: usize,
+        /// inverse distance weights power
+        #[serde(default = "default_power")]
+        power: f64,
+    },
+    /// Shepard interpolation within a radius scaled from the distance to the k-th nearest neighbor
+    Adaptive {
+        k: usize,
+        /// scale factor of the distance to the k-th nearest neighbor
+        #[serde(default = "default_scale")]
+        scale: f64,
+        /// inverse distance weights power
+        #[serde(default = "default_power")]
+        power: f64,
+        /// minimum radius [m]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        min_radius: Option<f64>,
+        /// maximum radius [m]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_radius: Option<f64>,
+    },
+    /// Wendland radial basis function
+    Wendland {
+        /// support radius [m]
+        radius: f64,
+    },
+    /// Gaussian radial basis function
+    Gaussian {
+        /// support radius [m]
+        radius: f64,
+        /// standard deviation [m]
+        sigma: f64,
+    },
+    /// CFD data resampled on a [VoxelGrid]
+    Grid {
+        /// grid resolution [m]
+        resolution: f64,
+        #[serde(default)]
+        sampling: GridSampling,
+    },
+    /// Barycentric interpolation within the [DelaunayInterpolation] tetrahedralization
+    Delaunay,
+}
+impl Default for InterpolationConfig {
+    fn default() -> Self {
+        Self::Shepard {
+            radius: 0.5,
+            power: default_power(),
+        }
+    }
+}
+fn default_power() -> f64 {
+    2.
+}
+fn default_scale() -> f64 {
+    1.
+}
+
+/// Run output
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OutputConfig {
+    /// URL of the OPD directory, defaults to the CFD file directory
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// OPD file name template
+    pub name: String,
+}
+impl Default for OutputConfig {
+    fn default() -> Self {
+        Self {
+            url: None,
+            name: "{stem}.bin".to_string(),
+        }
+    }
+}
+
+/// Joins the object `name` to the directory `dir`
+fn join(dir: &str, name: &str) -> String {
+    match dir.trim_end_matches('/') {
+        "" => name.to_string(),
+        dir => format!("{dir}/{name}"),
+    }
+}
+
+impl RunConfig {
+    /// Parses a TOML run configuration
+    pub fn from_toml(config: &str) -> Result<Self> {
+        toml::from_str(config).map_err(|e| Error::Config(e.to_string()))
+    }
+    /// Parses a YAML run configuration
+    pub fn from_yaml(config: &str) -> Result<Self> {
+        serde_yaml::from_str(config).map_err(|e| Error::Config(e.to_string()))
+    }
+    /// Loads a run configuration file from a storage `url`
+    ///
+    /// The file is parsed as YAML if its extension is `.yaml` or `.yml`, as TOML otherwise
+    pub fn from_url<U: AsRef<str>>(url: U) -> Result<Self> {
+        let (storage, key) = storage::from_url(url.as_ref())?;
+        let data = storage.get(&key)?;
+        let config = std::str::from_utf8(&data)?;
+        if key.ends_with(".yaml") || key.ends_with(".yml") {
+            Self::from_yaml(config)
+        } else {
+            Self::from_toml(config)
+        }
+    }
+    /// Returns the run configuration as TOML
+    pub fn to_toml(&self) -> Result<String> {
+        toml::to_string(self).map_err(|e| Error::Config(e.to_string()))
+    }
+    /// Returns the run configuration as YAML
+    pub fn to_yaml(&self) -> Result<String> {
+        serde_yaml::to_string(self).map_err(|e| Error::Config(e.to_string()))
+    }
+    /// Loads the ray tracing parameters and sets the ray tracer
+    pub fn ray_tracer(&self) -> Result<RayTracer> {
+        let config = &self.ray_tracing;
+        let mut ray_tracer = RayTracer::from_npz(&self.input.rays)?
+            .ray_tracing_step(config.step)
+            .quadrature(config.quadrature)
+            .wavelength(config.wavelength)
+            .out_of_domain(config.out_of_domain);
+        ray_tracer = match config.refractive_index {
+            RefractiveIndexConfig::GladstoneDale(model) => ray_tracer.refractive_index_model(model),
+            RefractiveIndexConfig::Edlen(model) => ray_tracer.refractive_index_model(model),
+            RefractiveIndexConfig::Ciddor(model) => ray_tracer.refractive_index_model(model),
+        };
+        ray_tracer = match config.interpolation {
+            InterpolationConfig::Nearest => ray_tracer.interpolator(NearestNeighbor),
+            InterpolationConfig::Shepard { radius, power } => {
+                ray_tracer.interpolator(ShepardInterpolation::new(radius).power(power))
+            }
+            InterpolationConfig::KNearest { k, power } => {
+                ray_tracer.interpolator(KNearestInterpolation::new(k).power(power))
+            }
+            InterpolationConfig::Adaptive {
+                k,
+                scale,
+                power,
+                min_radius,
+                max_radius,
+            } => {
+                let mut interpolator = AdaptiveInterpolation::new(k).scale(scale).power(power);
+                if let Some(min_radius) = min_radius {
+                    interpolator = interpolator.min_radius(min_radius);
+                }
+                if let Some(max_radius) = max_radius {
+                    interpolator = interpolator.max_radius(max_radius);
+                }
+                ray_tracer.interpolator(interpolator)
+            }
+            InterpolationConfig::Wendland { radius } => {
+                ray_tracer.interpolator(RbfInterpolation::wendland(radius))
+            }
+            InterpolationConfig::Gaussian { radius, sigma } => {
+                ray_tracer.interpolator(RbfInterpolation::gaussian(radius, sigma))
+            }
+            // the voxel grid and the tetrahedralization are set once the CFD data are loaded, see [RunConfig::with_cfd_data]
+            InterpolationConfig::Grid { .. } | InterpolationConfig::Delaunay => ray_tracer,
+        };
+        if let Some(n_thread) = config.n_thread {
+            ray_tracer = ray_tracer.n_thread(n_thread);
+        }
+        Ok(ray_tracer)
+    }
+    /// Sets the interpolator of the ray tracer that depends on the CFD data
+    ///
+    /// The CFD data are resampled on a [VoxelGrid] if the interpolation method is `grid`
+    /// and tetrahedralized if it is `delaunay`
+    pub fn with_cfd_data(
+        &self,
+        ray_tracer: RayTracer,
+        cfd_data: &RTree<TemperatureVelocityField>,
+    ) -> Result<RayTracer> {
+        match self.ray_tracing.interpolation {
+            InterpolationConfig::Grid {
+                resolution,
+                sampling,
+            } => {
+                let options = GridOptions::default()
+                    .resolution(resolution)
+                    .sampling(sampling);
+                Ok(ray_tracer.interpolator(VoxelGrid::from_rtree(cfd_data, options)?))
+            }
+            InterpolationConfig::Delaunay => {
+                Ok(ray_tracer.interpolator(DelaunayInterpolation::new(cfd_data)?))
+            }
+            _ => Ok(ray_tracer),
+        }
+    }
+    /// Returns the CFD data loading options
+    pub fn load_options(&self) -> LoadOptions {
+        let transform = match self.input.pointing {
+            Some(Pointing { zenith, azimuth }) => {
+                RigidTransform::pointing(zenith.to_radians(), azimuth.to_radians())
+            }
+            None => RigidTransform::oss(),
+        };
+        LoadOptions::default()
+            .schema(self.schema.clone())
+            .transform(transform)
+    }
+    /// Returns the storage and the key of the CFD csv.gz file
+    pub fn cfd(&self) -> Result<(Box<dyn Storage>, String)> {
+        let (storage, key) = storage::from_url(&self.input.cfd)?;
+        match self.input.cfd_index {
+            Some(index) => {
+                let key = storage
+                    .list(&key)?
+                    .into_iter()
+                    .filter(|key| key.ends_with(".csv.gz"))
+                    .nth(index)
+                    .ok_or_else(|| {
+                        Error::Storage(format!("no CFD file #{index} in {:?}", self.input.cfd))
+                    })?;
+                Ok((storage, key))
+            }
+            None => Ok((storage, key)),
+        }
+    }
+    /// Returns the storage and the key of the OPD file for the CFD file `cfd_key`
+    pub fn output(
+        &self,
+        cfd_key: &str,
+        ray_tracer: &RayTracer,
+    ) -> Result<(Box<dyn Storage>, String)> {
+        let cfd_name = cfd_key.rsplit_once('/').map_or(cfd_key, |(_, name)| name);
+        let stem = cfd_name.strip_suffix(".csv.gz").unwrap_or(cfd_name);
+        let n_px = ray_tracer.metadata().n_px;
+        let name = self
+            .output
+            .name
+            .replace("{stem}", stem)
+            .replace(
+                "{index}",
+                &self.input.cfd_index.unwrap_or_default().to_string(),
+            )
+            .replace("{n_px}", &n_px.to_string());
+        if name.contains(['{', '}']) {
+            return Err(Error::Config(format!(
+                "unknown placeholder in output name {:?}",
+                self.output.name
+            )));
+        }
+        match &self.output.url {
+            Some(url) => {
+                let (storage, dir) = storage::from_url(url)?;
+                Ok((storage, join(&dir, &name)))
+            }
+            None => {
+                let (storage, _) = storage::from_url(&self.input.cfd)?;
+                let dir = cfd_key.rsplit_once('/').map_or("", |(dir, _)| dir);
+                Ok((storage, join(dir, &name)))
+            }
+        }
+    }
+    /// Runs the ray tracing pipeline
+    ///
+    /// Loads the CFD data and the ray tracing parameters, ray traces
+    /// and saves the OPD file, returning it with the OPD file key
+    pub fn run(&self) -> Result<(OpdFile, String)> {
+        let ray_tracer = self.ray_tracer()?;
+        let (storage, cfd_key) = self.cfd()?;
+        let tree: RTree<TemperatureVelocityField> =
+            RTree::from_gz_in(storage.as_ref(), &cfd_key, self.load_options())?;
+        let ray_tracer = self.with_cfd_data(ray_tracer, &tree)?;
+        let opd = ray_tracer.ray_trace(&tree)?;
+        let opd_file = OpdFile::new(opd, ray_tracer.metadata().cfd_file(cfd_key.as_str()));
+        let (storage, key) = self.output(&cfd_key, &ray_tracer)?;
+        opd_file.save_in(storage.as_ref(), &key)?;
+        Ok((opd_file, key))
+    }
+    /// Loads the CFD data and the ray tracing parameters and ray traces
+    /// with the ray tracing step halved `n_step`-1 times, see [RayTracer::step_convergence]
+    pub fn step_convergence(&self, n_step: usize) -> Result<Vec<StepConvergence>> {
+        let ray_tracer = self.ray_tracer()?;
+        let (storage, cfd_key) = self.cfd()?;
+        let tree: RTree<TemperatureVelocityField> =
+            RTree::from_gz_in(storage.as_ref(), &cfd_key, self.load_options())?;
+        let ray_tracer = self.with_cfd_data(ray_tracer, &tree)?;
+        ray_tracer.step_convergence(&tree, n_step)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{gmt::Source, storage::Location, Column, Unit};
+
+    fn config() -> RunConfig {
+        RunConfig::from_toml(
+            r#"
+            [input]
+            cfd = "s3://gmto.cfd.2022/CASES/zen30az000_OS7/optvol/optvol_optvol?region=us-east-2"
+            cfd_index = 3
+            rays = "rays.npz"
+            pointing = { zenith = 30, azimuth = 90 }
+
+            [schema]
+            pressure = "Absolute Pressure (Pa)"
+            x = { header = "X", unit = "mm" }
+
+            [ray_tracing]
+            refractive_index = { model = "edlen", pressure = 74e3, humidity = 0.2 }
+            interpolation = { method = "adaptive", k = 12, scale = 1.5, max_radius = 1 }
+            step = 0.1
+            quadrature = "simpson"
+            wavelength = 0.7
+            out_of_domain = { ambient = 2e-4 }
+            n_thread = 2
+
+            [output]
+            url = "opd"
+            name = "{n_px}/{stem}_{index}.bin"
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        let config = config();
+        assert_eq!(config.input.cfd_index, Some(3));
+        assert!(matches!(
+            config.input.pointing,
+            Some(Pointing {
+                zenith: 30.,
+                azimuth: 90.
+            })
+        ));
+        assert_eq!(config.schema.x, Column::new("X", Unit::Millimeter));
+        let ray_tracing = &config.ray_tracing;
+        assert!(matches!(
+            ray_tracing.refractive_index,
+            RefractiveIndexConfig::Edlen(Edlen {
+                pressure: 74e3,
+                humidity: 0.2
+            })
+        ));
+        assert!(matches!(
+            ray_tracing.interpolation,
+            InterpolationConfig::Adaptive {
+                k: 12,
+                scale: 1.5,
+                power: 2.,
+                min_radius: None,
+                max_radius: Some(1.)
+            }
+        ));
+        assert_eq!(ray_tracing.step, 0.1);
+        assert_eq!(ray_tracing.quadrature, Quadrature::Simpson);
+        assert_eq!(ray_tracing.wavelength, 0.7);
+        assert_eq!(ray_tracing.out_of_domain, OutOfDomain::Ambient(2e-4));
+        assert_eq!(ray_tracing.n_thread, Some(2));
+        // defaults
+        let config = RunConfig::from_yaml("input: { cfd: optvol.csv.gz, rays: rays.npz }").unwrap();
+        assert_eq!(config.schema, CsvSchema::default());
+        assert!(matches!(
+            config.ray_tracing.interpolation,
+            InterpolationConfig::Shepard {
+                radius: 0.5,
+                power: 2.
+            }
+        ));
+        assert_eq!(config.ray_tracing.step, 0.25);
+        assert_eq!(config.output.name, "{stem}.bin");
+        assert!(RunConfig::from_toml("[input]\ncfd = \"optvol.csv.gz\"").is_err());
+    }
+
+    #[test]
+    fn round_trip() {
+        let config = config();
+        let toml = config.to_toml().unwrap();
+        assert_eq!(
+            RunConfig::from_toml(&toml).unwrap().to_toml().unwrap(),
+            toml
+        );
+        let yaml = config.to_yaml().unwrap();
+        assert_eq!(
+            RunConfig::from_yaml(&yaml).unwrap().to_yaml().unwrap(),
+            yaml
+        );
+        assert_eq!(
+            RunConfig::from_yaml(&yaml).unwrap().to_toml().unwrap(),
+            toml
+        );
+    }
+
+    #[test]
+    fn schema() {
+        let config = RunConfig::from_toml(
+            r#"
+            [input]
+            cfd = "optvol.csv.gz"
+            rays = "rays.npz"
+
+            [schema]
+            pressure = "Absolute Pressure (Pa)"
+            "#,
+        )
+        .unwrap();
+        let schema =
+            CsvSchema::default().with_pressure(Column::new("Absolute Pressure (Pa)", Unit::Pascal));
+        assert_eq!(config.schema, schema);
+        assert_eq!(config.load_options().schema, schema);
+    }
+
+    #[test]
+    fn output_name() {
+        let ray_tracer = RayTracer::gmt(12, Source::on_axis()).unwrap();
+        let mut config = config();
+        config.input.cfd = "optvol/optvol_".to_string();
+        let (storage, key) = config
+            .output("optvol/optvol_0250.csv.gz", &ray_tracer)
+            .unwrap();
+        assert_eq!(key, "opd/12/optvol_0250_3.bin");
+        assert_eq!(
+            storage.local_path(&key),
+            Some(std::path::PathBuf::from("opd/12/optvol_0250_3.bin"))
+        );
+        // next to the CFD file
+        config.output = OutputConfig::default();
+        let (_, key) = config
+            .output("optvol/optvol_0250.csv.gz", &ray_tracer)
+            .unwrap();
+        assert_eq!(key, "optvol/optvol_0250.bin");
+        config.output.name = "{time}.bin".to_string();
+        assert!(matches!(
+            config.output("optvol/optvol_0250.csv.gz", &ray_tracer),
+            Err(Error::Config(_))
+        ));
+    }
+
+    #[test]
+    fn cfd_index() {
+        let dir = std::env::temp_dir().join(format!("cfd_raytrace-index-{}", std::process::id()));
+        let storage = crate::LocalStorage::new(&dir);
+        for key in [
+            "optvol/optvol_0100.csv.gz",
+            "optvol/optvol_0000.cfd",
+            "optvol/optvol_0000.csv.gz",
+            "optvol/optvol_0050.csv.gz",
+            "optvol/pressure_0000.csv.gz",
+        ] {
+            storage.put(key, &[]).unwrap();
+        }
+        let mut config =
+            RunConfig::from_yaml("input: { cfd: optvol.csv.gz, rays: rays.npz }").unwrap();
+        config.input.cfd = format!("file://{}/optvol/optvol_", dir.display());
+        let key = |index: usize| {
+            let mut config = config.clone();
+            config.input.cfd_index = Some(index);
+            config.cfd().map(|(_, key)| key)
+        };
+        for (index, name) in ["optvol_0000", "optvol_0050", "optvol_0100"]
+            .into_iter()
+            .enumerate()
+        {
+            assert_eq!(
+                key(index).unwrap(),
+                format!("{}/optvol/{name}.csv.gz", dir.display())
+            );
+        }
+        assert!(matches!(key(3), Err(Error::Storage(_))));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn documented_example() {
+        let doc: String = include_str!("config.rs")
+            .lines()
+            .filter_map(|line| line.strip_prefix("//!"))
+            .map(|line| format!("{}\n", line.trim_start()))
+            .collect();
+        let example = doc
+            .split_once("```toml\n")
+            .and_then(|(_, doc)| doc.split_once("```"))
+            .map(|(example, _)| example)
+            .unwrap();
+        let config = RunConfig::from_toml(example).unwrap();
+        assert_eq!(config.input.cfd_index, Some(0));
+        assert_eq!(config.ray_tracing.quadrature, Quadrature::Simpson);
+        assert_eq!(config.output.name, "{n_px}/{stem}.bin");
+    }
+
+    #[test]
+    fn stack() {
+        // the run configuration of the AWS Batch job of the bundled CloudFormation stack
+        let stack: serde_yaml::Value =
+            serde_yaml::from_str(include_str!("../cfd_raytrace.yaml")).unwrap();
+        let container =
+            &stack["Resources"]["CFDJobDefinition"]["Properties"]["ContainerProperties"];
+        assert_eq!(container["Command"][1], "trace");
+        let env = |name: &str| {
+            container["Environment"]
+                .as_sequence()
+                .unwrap()
+                .iter()
+                .find(|variable| variable["Name"] == name)
+                .and_then(|variable| variable["Value"].as_str())
+                .unwrap()
+                .to_string()
+        };
+        let config = RunConfig {
+            input: InputConfig {
+                cfd: env("CFD_URL"),
+                cfd_index: Some(0),
+                rays: env("RAY_TRACER_URL"),
+                pointing: None,
+            },
+            schema: Default::default(),
+            ray_tracing: Default::default(),
+            output: OutputConfig {
+                url: Some(env("OPD_URL")),
+                ..Default::default()
+            },
+        };
+        let config = RunConfig::from_yaml(&config.to_yaml().unwrap()).unwrap();
+        for (url, bucket, region) in [
+            (&config.input.cfd, "gmto.cfd.2022", "us-east-2"),
+            (&config.input.rays, "cfd.archive", "us-east-2"),
+            (
+                config.output.url.as_ref().unwrap(),
+                "gmto.im.grim",
+                "us-west-2",
+            ),
+        ] {
+            match Location::parse(url).unwrap() {
+                Location::S3 {
+                    bucket: b,
+                    key,
+                    region: r,
+                } => {
+                    assert_eq!((b, r), (bucket, Some(region)));
+                    assert!(!key.is_empty());
+                }
+                location => panic!("expected a S3 location, found {location:?}"),
+            }
+        }
+    }
+}
@@ -0,0 +1,810 @@
+//! Delaunay tetrahedralization of the CFD samples
+//!
+//! The tetrahedralization is built with the Bowyer-Watson incremental algorithm,
+//! the samples being inserted in Morton order within a bounding tetrahedron.
+//! The sample coordinates are slightly perturbed to break the degeneracies of structured meshes,
+//! the interpolation weights being computed with the unperturbed coordinates.
+
+use super::{Error, Interpolator, Result, TemperatureVelocityField};
+use rstar::RTree;
+use std::{borrow::Cow, collections::HashMap};
+
+/// Missing neighbor or tetrahedron
+const NONE: u32 = u32::MAX;
+/// Average number of samples per cell of the point location grid
+const SAMPLES_PER_CELL: usize = 8;
+/// Relative amplitude of the sample coordinates perturbation
+const PERTURBATION: f64 = 1e-9;
+/// Largest number of tetrahedra visited by the search of a tetrahedron near a query point
+const N_NEARBY: usize = 256;
+
+/// Barycentric interpolation within the Delaunay tetrahedralization of the CFD samples
+///
+/// The CFD data are interpolated linearly inside the tetrahedron that contains the query point;
+/// the query points outside the convex hull of the CFD samples are outside the CFD domain
+/// and the query points on the convex hull are inside.
+/// The search for the tetrahedron that contains a query point starts from the tetrahedron
+/// of the grid cell the query point falls in, so the query point is located in a few moves
+/// and the tetrahedron, hence the interpolation weights, depends only on the query point.
+///
+/// The tetrahedralization keeps a copy of the CFD samples at each vertex,
+/// the samples sharing the same coordinates being a single vertex with the weight split evenly;
+/// as an [Interpolator], it ignores the CFD data it is given
+/// and returns the samples at the tetrahedron vertices and their weights instead.
+#[derive(Debug)]
+pub struct DelaunayInterpolation {
+    /// CFD samples sorted by coordinates
+    samples: Vec<TemperatureVelocityField>,
+    /// index of the first sample of each vertex, followed by the number of samples
+    vertices: Vec<u32>,
+    /// perturbed sample coordinates followed by the 4 vertices of the bounding tetrahedron,
+    /// used for the geometric predicates
+    points: Vec<[f64; 3]>,
+    tetrahedra: Vec<[u32; 4]>,
+    /// neighbors of each tetrahedron, the neighbor #i is opposite to the vertex #i
+    neighbors: Vec<[u32; 4]>,
+    /// largest distance between a query point and the tetrahedron it is located in,
+    /// bounding the displacement of the tetrahedra faces by the perturbation
+    tolerance: f64,
+    /// starting tetrahedra of the point location
+    grid: LocationGrid,
+}
+
+/// Regular grid over the bounding box of the CFD samples
+/// with the tetrahedron containing the center of each cell
+#[derive(Debug, Default)]
+struct LocationGrid {
+    origin: [f64; 3],
+    spacing: [f64; 3],
+    shape: [usize; 3],
+    tetrahedra: Vec<u32>,
+}
+impl LocationGrid {
+    /// Returns the index of the cell containing `point`, the points outside the grid
+    /// being moved to the nearest cell
+    fn cell(&self, point: &[f64; 3]) -> usize {
+        let [i, j, k] = [0, 1, 2].map(|i| {
+            (((point[i] - self.origin[i]) / self.spacing[i]) as usize).min(self.shape[i] - 1)
+        });
+        i + self.shape[0] * (j + self.shape[1] * k)
+    }
+    /// Returns the tetrahedron the location of `point` starts from
+    fn start(&self, point: &[f64; 3]) -> usize {
+        self.tetrahedra
+            .get(self.cell(point))
+            .map_or(0, |&t| t as usize)
+    }
+}
+
+impl DelaunayInterpolation {
+    /// Tetrahedralizes the CFD samples
+    pub fn new(cfd_data: &RTree<TemperatureVelocityField>) -> Result<Self> {
+        let mut samples: Vec<TemperatureVelocityField> = cfd_data.iter().cloned().collect();
+        if samples.len() >= NONE as usize / 8 {
+            return Err(Error::Delaunay(format!(
+                "too many CFD samples ({})",
+                samples.len()
+            )));
+        }
+        // the samples with the same coordinates are contiguous, -0 being turned into 0
+        samples.sort_by(|a, b| {
+            let [a, b] = [a, b].map(|s| s.coordinates().map(|x| x + 0.));
+            (0..3)
+                .map(|i| a[i].total_cmp(&b[i]))
+                .find(|o| o.is_ne())
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        let mut vertices: Vec<u32> = (0..samples.len())
+            .filter(|&i| i == 0 || samples[i].coordinates() != samples[i - 1].coordinates())
+            .map(|i| i as u32)
+            .collect();
+        vertices.push(samples.len() as u32);
+        let n = vertices.len() - 1;
+        if n < 4 {
+            return Err(Error::Delaunay(format!(
+                "expected at least 4 CFD samples at distinct coordinates, found {n}"
+            )));
+        }
+        let envelope = cfd_data.root().envelope();
+        let (lower, upper) = (envelope.lower(), envelope.upper());
+        let center: Vec<f64> = lower
+            .iter()
+            .zip(&upper)
+            .map(|(l, u)| 0.5 * (l + u))
+            .collect();
+        let size = lower
+            .iter()
+            .zip(&upper)
+            .map(|(l, u)| (u - l).powi(2))
+            .sum::<f64>()
+            .sqrt();
+        let mut points: Vec<[f64; 3]> = vertices[..n]
+            .iter()
+            .enumerate()
+            .map(|(i, &v)| {
+                let mut state = i as u64;
+                samples[v as usize]
+                    .coordinates()
+                    .map(|x| x + PERTURBATION * size * (2. * uniform(&mut state) - 1.))
+            })
+            .collect();
+        // bounding tetrahedron
+        let r = 100. * size;
+        for v in [[1., 1., 1.], [1., -1., -1.], [-1., 1., -1.], [-1., -1., 1.]] {
+            points.push([
+                center[0] + r * v[0],
+                center[1] + r * v[1],
+                center[2] + r * v[2],
+            ]);
+        }
+        let mut delaunay = Self {
+            samples,
+            vertices,
+            points,
+            tetrahedra: vec![[n as u32, n as u32 + 1, n as u32 + 2, n as u32 + 3]],
+            neighbors: vec![[NONE; 4]],
+            tolerance: 2. * PERTURBATION * size,
+            grid: Default::default(),
+        };
+        if delaunay.volume(0) < 0. {
+            delaunay.tetrahedra[0].swap(0, 1);
+        }
+        delaunay.triangulate(morton_order(&delaunay.points[..n], lower, upper))?;
+        // the perturbation gives a flat tetrahedralization of coplanar samples
+        let volume: f64 = (0..delaunay.tetrahedra.len())
+            .filter(|&t| !delaunay.is_exterior(t))
+            .map(|t| delaunay.exact_volume(t))
+            .sum();
+        if volume.is_nan() || volume <= PERTURBATION * size.powi(3) {
+            return Err(Error::Delaunay("the CFD samples are coplanar".to_string()));
+        }
+        delaunay.grid = delaunay.location_grid(lower, upper);
+        Ok(delaunay)
+    }
+    /// Builds the point location grid from `lower` to `upper`
+    /// with about [SAMPLES_PER_CELL] samples per cell
+    fn location_grid(&self, lower: [f64; 3], upper: [f64; 3]) -> LocationGrid {
+        let n_cell = (self.points.len() - 4).div_ceil(SAMPLES_PER_CELL);
+        let size = [0, 1, 2].map(|i| (upper[i] - lower[i]).max(f64::MIN_POSITIVE));
+        let cell_size = (size.iter().product::<f64>() / n_cell as f64).cbrt();
+        let shape = size.map(|x| ((x / cell_size).ceil() as usize).clamp(1, n_cell));
+        let spacing = [0, 1, 2].map(|i| size[i] / shape[i] as f64);
+        let mut tetrahedra = Vec::with_capacity(shape.iter().product());
+        let mut t = 0;
+        for k in 0..shape[2] {
+            for j in 0..shape[1] {
+                for i in 0..shape[0] {
+                    let center = [i, j, k].map(|i| i as f64 + 0.5);
+                    let center = [0, 1, 2].map(|i| lower[i] + center[i] * spacing[i]);
+                    t = self.walk(t, &center, |_| true).unwrap_or(t);
+                    tetrahedra.push(t as u32);
+                }
+            }
+        }
+        LocationGrid {
+            origin: lower,
+            spacing,
+            shape,
+            tetrahedra,
+        }
+    }
+    /// Returns the number of tetrahedra within the convex hull of the CFD samples
+    pub fn n_tetrahedra(&self) -> usize {
+        (0..self.tetrahedra.len())
+            .filter(|&t| !self.is_exterior(t))
+            .count()
+    }
+    /// Inserts the samples in the given order
+    fn triangulate(&mut self, order: Vec<u32>) -> Result<()> {
+        let mut alive = vec![true];
+        let mut free = vec![];
+        let mut last = 0;
+        let mut in_cavity = vec![false];
+        let mut faces: HashMap<(u32, u32), (usize, usize)> = HashMap::new();
+        for p in order {
+            let point = self.points[p as usize];
+            let start = self
+                .walk(last, &point, |t| alive[t])
+                .ok_or_else(|| Error::Delaunay(format!("failed to locate sample #{p}")))?;
+            // cavity of the tetrahedra whose circumsphere contains the point
+            let mut cavity = vec![start];
+            in_cavity[start] = true;
+            let mut k = 0;
+            while k < cavity.len() {
+                let t = cavity[k];
+                k += 1;
+                for &n in &self.neighbors[t] {
+                    if n != NONE && !in_cavity[n as usize] && self.insphere(n as usize, &point) > 0.
+                    {
+                        in_cavity[n as usize] = true;
+                        cavity.push(n as usize);
+                    }
+                }
+            }
+            // the cavity is expanded until it is star-shaped from the point
+            let boundary = loop {
+                let mut boundary = vec![];
+                let mut expansion = vec![];
+                for &t in &cavity {
+                    for (i, &n) in self.neighbors[t].iter().enumerate() {
+                        if n != NONE && in_cavity[n as usize] {
+                            continue;
+                        }
+                        let mut face = self.tetrahedra[t];
+                        face[i] = p;
+                        if self.volume_of(&face) > 0. {
+                            boundary.push((t, i, n));
+                        } else if n != NONE {
+                            expansion.push(n as usize);
+                        } else {
+                            return Err(Error::Delaunay(format!("failed to insert sample #{p}")));
+                        }
+                    }
+                }
+                if expansion.is_empty() {
+                    break boundary;
+                }
+                for n in expansion {
+                    if !in_cavity[n] {
+                        in_cavity[n] = true;
+                        cavity.push(n);
+                    }
+                }
+            };
+            // new tetrahedra joining the cavity boundary to the point
+            faces.clear();
+            let mut created = Vec::with_capacity(boundary.len());
+            for &(t, i, n) in &boundary {
+                let mut tetrahedron = self.tetrahedra[t];
+                tetrahedron[i] = p;
+                let mut neighbors = [NONE; 4];
+                neighbors[i] = n;
+                let new = match free.pop() {
+                    Some(new) => {
+                        self.tetrahedra[new] = tetrahedron;
+                        self.neighbors[new] = neighbors;
+                        new
+                    }
+                    None => {
+                        self.tetrahedra.push(tetrahedron);
+                        self.neighbors.push(neighbors);
+                        alive.push(false);
+                        in_cavity.push(false);
+                        self.tetrahedra.len() - 1
+                    }
+                };
+                if n != NONE {
+                    let j = self.neighbors[n as usize]
+                        .iter()
+                        .position(|&m| m as usize == t)
+                        .expect("inconsistent tetrahedra neighbors");
+                    self.neighbors[n as usize][j] = new as u32;
+                }
+                // the faces including the point are matched by their 2 other vertices
+                for j in (0..4).filter(|&j| j != i) {
+                    let edge: Vec<u32> = (0..4)
+                        .filter(|&k| k != i && k != j)
+                        .map(|k| tetrahedron[k])
+                        .collect();
+                    let key = (edge[0].min(edge[1]), edge[0].max(edge[1]));
+                    match faces.remove(&key) {
+                        Some((other, k)) => {
+                            self.neighbors[new][j] = other as u32;
+                            self.neighbors[other][k] = new as u32;
+                        }
+                        None => {
+                            faces.insert(key, (new, j));
+                        }
+                    }
+                }
+                created.push(new);
+            }
+            for &t in &cavity {
+                in_cavity[t] = false;
+                alive[t] = false;
+                free.push(t);
+            }
+            for &t in &created {
+                alive[t] = true;
+            }
+            last = created[0];
+        }
+        self.compact(&alive);
+        Ok(())
+    }
+    /// Removes the deleted tetrahedra
+    fn compact(&mut self, alive: &[bool]) {
+        let mut index = vec![NONE; alive.len()];
+        let kept: Vec<usize> = (0..alive.len()).filter(|&t| alive[t]).collect();
+        for (k, &t) in kept.iter().enumerate() {
+            index[t] = k as u32;
+        }
+        for (k, &t) in kept.iter().enumerate() {
+            self.tetrahedra[k] = self.tetrahedra[t];
+            self.neighbors[k] =
+                self.neighbors[t].map(|n| if n == NONE { NONE } else { index[n as usize] });
+        }
+        self.tetrahedra.truncate(kept.len());
+        self.neighbors.truncate(kept.len());
+    }
+    /// Walks from the tetrahedron `start` to the tetrahedron containing `point`
+    fn walk<F: Fn(usize) -> bool>(
+        &self,
+        start: usize,
+        point: &[f64; 3],
+        alive: F,
+    ) -> Option<usize> {
+        let mut t = start;
+        if !alive(t) {
+            t = (0..self.tetrahedra.len()).rev().find(|&t| alive(t))?;
+        }
+        let mut state = t as u64;
+        'walk: for _ in 0..self.tetrahedra.len() {
+            // the faces are visited from a random one to avoid cycles
+            let offset = (uniform(&mut state) * 4.) as usize;
+            for i in (0..4).map(|i| (i + offset) % 4) {
+                let mut face = self.tetrahedra[t];
+                face[i] = NONE;
+                if self.orient(&face, point) < 0. {
+                    let n = self.neighbors[t][i];
+                    if n == NONE {
+                        return None;
+                    }
+                    t = n as usize;
+                    continue 'walk;
+                }
+            }
+            return Some(t);
+        }
+        None
+    }
+    /// Returns `true` if the tetrahedron `t` has a vertex of the bounding tetrahedron
+    fn is_exterior(&self, t: usize) -> bool {
+        let n = self.points.len() - 4;
+        self.tetrahedra[t].iter().any(|&v| v as usize >= n)
+    }
+    /// Returns the CFD samples at the vertex `v`
+    fn vertex_samples(&self, v: u32) -> &[TemperatureVelocityField] {
+        let v = v as usize;
+        &self.samples[self.vertices[v] as usize..self.vertices[v + 1] as usize]
+    }
+    /// Returns the unperturbed coordinates of the tetrahedron `t` vertices
+    fn coordinates(&self, t: usize) -> [[f64; 3]; 4] {
+        self.tetrahedra[t].map(|v| self.vertex_samples(v)[0].coordinates())
+    }
+    /// Returns `true` if the tetrahedron `t` is flat without the perturbation
+    fn is_flat(&self, t: usize) -> bool {
+        self.exact_volume(t) <= 0.5 * self.volume(t)
+    }
+    /// Returns 6 times the signed volume of the tetrahedron `t` with the unperturbed coordinates
+    fn exact_volume(&self, t: usize) -> f64 {
+        let [a, b, c, d] = self.coordinates(t);
+        orient3d(&a, &b, &c, &d)
+    }
+    fn point(&self, v: u32, point: &[f64; 3]) -> [f64; 3] {
+        if v == NONE {
+            *point
+        } else {
+            self.points[v as usize]
+        }
+    }
+    /// Returns 6 times the signed volume of the tetrahedron with the vertex `NONE` replaced by `point`
+    ///
+    /// The vertices are sorted before computing the volume, so that the faces shared
+    /// by 2 tetrahedra give consistent orientations despite the rounding errors
+    fn orient(&self, tetrahedron: &[u32; 4], point: &[f64; 3]) -> f64 {
+        let (sorted, odd) = sort(tetrahedron);
+        let [a, b, c, d] = sorted.map(|v| self.point(v, point));
+        let volume = orient3d(&a, &b, &c, &d);
+        if odd {
+            -volume
+        } else {
+            volume
+        }
+    }
+    /// Returns 6 times the signed volume of the tetrahedron
+    fn volume_of(&self, tetrahedron: &[u32; 4]) -> f64 {
+        self.orient(tetrahedron, &[0.; 3])
+    }
+    fn volume(&self, t: usize) -> f64 {
+        self.volume_of(&self.tetrahedra[t])
+    }
+    /// Returns the signed distance from `point` to the face #i of the tetrahedron `t`,
+    /// positive on the side of the vertex #i
+    fn distance(&self, t: usize, i: usize, point: &[f64; 3]) -> f64 {
+        let mut face = self.tetrahedra[t];
+        face[i] = NONE;
+        let [a, b, c] = [1, 2, 3].map(|k| self.points[self.tetrahedra[t][(i + k) % 4] as usize]);
+        let u = [b[0] - a[0], b[1] - a[1], b[2] - a[2]];
+        let v = [c[0] - a[0], c[1] - a[1], c[2] - a[2]];
+        let normal = [
+            u[1] * v[2] - u[2] * v[1],
+            u[2] * v[0] - u[0] * v[2],
+            u[0] * v[1] - u[1] * v[0],
+        ];
+        let area = normal.iter().map(|x| x * x).sum::<f64>().sqrt();
+        self.orient(&face, point) / area
+    }
+    /// Returns a positive value if `point` is inside the circumsphere of the tetrahedron `t`
+    fn insphere(&self, t: usize, point: &[f64; 3]) -> f64 {
+        let (sorted, odd) = sort(&self.tetrahedra[t]);
+        let [a, b, c, d] = sorted.map(|v| self.points[v as usize]);
+        let value = insphere(&a, &b, &c, &d, point);
+        if odd {
+            -value
+        } else {
+            value
+        }
+    }
+    /// Returns the tetrahedron containing `query_point`,
+    /// starting the search from the tetrahedron of the location grid cell
+    fn locate(&self, query_point: &[f64; 3]) -> Option<usize> {
+        let t = self.walk(self.grid.start(query_point), query_point, |_| true)?;
+        if self.is_exterior(t) || self.is_flat(t) {
+            self.nearby(t, query_point)
+                .or((!self.is_exterior(t)).then_some(t))
+        } else {
+            Some(t)
+        }
+    }
+    /// Returns the tetrahedron within the convex hull, not flat, that contains `query_point` the best
+    ///
+    /// The search goes from the exterior or flat tetrahedron `start` through the faces
+    /// within the tolerance of `query_point`, the best tetrahedron being the one
+    /// with the largest smallest barycentric coordinate.
+    /// `None` is returned if `query_point` is not within the tolerance of such a tetrahedron.
+    fn nearby(&self, start: usize, query_point: &[f64; 3]) -> Option<usize> {
+        let mut visited = vec![start];
+        let mut best: Option<(f64, usize)> = None;
+        let mut k = 0;
+        while k < visited.len() && visited.len() < N_NEARBY {
+            let t = visited[k];
+            k += 1;
+            for i in 0..4 {
+                let n = self.neighbors[t][i];
+                if n == NONE
+                    || visited.contains(&(n as usize))
+                    || self.distance(t, i, query_point).abs() > self.tolerance
+                {
+                    continue;
+                }
+                let n = n as usize;
+                visited.push(n);
+                if self.is_exterior(n)
+                    || self.is_flat(n)
+                    || (0..4).any(|i| self.distance(n, i, query_point) < -self.tolerance)
+                {
+                    continue;
+                }
+                let volume = self.volume(n);
+                let smallest = (0..4)
+                    .map(|i| {
+                        let mut sub = self.tetrahedra[n];
+                        sub[i] = NONE;
+                        self.orient(&sub, query_point) / volume
+                    })
+                    .fold(f64::INFINITY, f64::min);
+                if best.filter(|&(b, _)| b >= smallest).is_none() {
+                    best = Some((smallest, n));
+                }
+            }
+        }
+        best.map(|(_, t)| t)
+    }
+}
+
+impl Interpolator for DelaunayInterpolation {
+    fn description(&self) -> String {
+        format!("Delaunay ({} tetrahedra)", self.n_tetrahedra())
+    }
+    fn weights<'a>(
+        &'a self,
+        _cfd_data: &'a RTree<TemperatureVelocityField>,
+        query_point: &[f64; 3],
+    ) -> Option<Vec<(Cow<'a, TemperatureVelocityField>, f64)>> {
+        let t = self.locate(query_point)?;
+        let tetrahedron = self.tetrahedra[t];
+        let volume = self.volume(t);
+        // the weights are computed with the unperturbed coordinates
+        // unless the tetrahedron is flat without the perturbation
+        let flat = self.is_flat(t);
+        let exact_volume = self.exact_volume(t);
+        let coordinates = self.coordinates(t);
+        let mut weights = Vec::with_capacity(4);
+        for i in 0..4 {
+            let w = if !flat {
+                let mut sub = coordinates;
+                sub[i] = *query_point;
+                orient3d(&sub[0], &sub[1], &sub[2], &sub[3]) / exact_volume
+            } else {
+                let mut sub = tetrahedron;
+                sub[i] = NONE;
+                self.orient(&sub, query_point) / volume
+            };
+            if w != 0. {
+                let samples = self.vertex_samples(tetrahedron[i]);
+                let w = w / samples.len() as f64;
+                weights.extend(samples.iter().map(|sample| (Cow::Borrowed(sample), w)));
+            }
+        }
+        Some(weights)
+    }
+}
+
+/// Sorts the tetrahedron vertices, returning `true` if the permutation is odd
+fn sort(tetrahedron: &[u32; 4]) -> ([u32; 4], bool) {
+    let mut sorted = *tetrahedron;
+    let mut odd = false;
+    for i in 1..4 {
+        let mut j = i;
+        while j > 0 && sorted[j - 1] > sorted[j] {
+            sorted.swap(j - 1, j);
+            odd = !odd;
+            j -= 1;
+        }
+    }
+    (sorted, odd)
+}
+
+/// Returns 6 times the signed volume of the tetrahedron (a,b,c,d)
+fn orient3d(a: &[f64; 3], b: &[f64; 3], c: &[f64; 3], d: &[f64; 3]) -> f64 {
+    let u = [b[0] - a[0], b[1] - a[1], b[2] - a[2]];
+    let v = [c[0] - a[0], c[1] - a[1], c[2] - a[2]];
+    let w = [d[0] - a[0], d[1] - a[1], d[2] - a[2]];
+    u[0] * (v[1] * w[2] - v[2] * w[1]) - u[1] * (v[0] * w[2] - v[2] * w[0])
+        + u[2] * (v[0] * w[1] - v[1] * w[0])
+}
+
+/// Returns a positive value if `e` is inside the circumsphere
+/// of the positively oriented tetrahedron (a,b,c,d)
+fn insphere(a: &[f64; 3], b: &[f64; 3], c: &[f64; 3], d: &[f64; 3], e: &[f64; 3]) -> f64 {
+    let lift = |p: &[f64; 3]| {
+        let r = [p[0] - e[0], p[1] - e[1], p[2] - e[2]];
+        (r, r[0] * r[0] + r[1] * r[1] + r[2] * r[2])
+    };
+    let (a, aw) = lift(a);
+    let (b, bw) = lift(b);
+    let (c, cw) = lift(c);
+    let (d, dw) = lift(d);
+    let det3 = |u: &[f64; 3], v: &[f64; 3], w: &[f64; 3]| {
+        u[0] * (v[1] * w[2] - v[2] * w[1]) - u[1] * (v[0] * w[2] - v[2] * w[0])
+            + u[2] * (v[0] * w[1] - v[1] * w[0])
+    };
+    aw * det3(&b, &c, &d) - bw * det3(&a, &c, &d) + cw * det3(&a, &b, &d) - dw * det3(&a, &b, &c)
+}
+
+/// Returns the indices of the `points` sorted along a Morton curve
+fn morton_order(points: &[[f64; 3]], lower: [f64; 3], upper: [f64; 3]) -> Vec<u32> {
+    let code = |p: &[f64; 3]| {
+        let mut code = 0u64;
+        for i in 0..3 {
+            let x =
+                ((p[i] - lower[i]) / (upper[i] - lower[i]).max(f64::MIN_POSITIVE)).clamp(0., 1.);
+            let x = (x * ((1 << 21) - 1) as f64) as u64;
+            for bit in 0..21 {
+                code |= ((x >> bit) & 1) << (3 * bit + i);
+            }
+        }
+        code
+    };
+    let mut order: Vec<(u64, u32)> = points
+        .iter()
+        .enumerate()
+        .map(|(i, p)| (code(p), i as u32))
+        .collect();
+    order.sort_unstable();
+    order.into_iter().map(|(_, i)| i).collect()
+}
+
+/// Returns a pseudo-random number in [0,1) and updates the generator `state`
+fn uniform(state: &mut u64) -> f64 {
+    // splitmix64
+    *state = state.wrapping_add(0x9E3779B97F4A7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^= z >> 31;
+    (z >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{dome, lattice};
+
+    fn linear([x, y, z]: [f64; 3]) -> f64 {
+        280. + 0.5 * x - 0.25 * y + 0.1 * z
+    }
+
+    /// Cubic lattice, every 8 cube corners are cospherical
+    fn cube() -> RTree<TemperatureVelocityField> {
+        RTree::bulk_load(lattice([0.; 3], [3.; 3], 1., linear))
+    }
+
+    fn assert_linear(delaunay: &DelaunayInterpolation, cfd_data: &RTree<TemperatureVelocityField>) {
+        for i in 0..=6 {
+            for j in 0..=6 {
+                for k in 0..=6 {
+                    let xyz = [i as f64 * 0.5, j as f64 * 0.5, k as f64 * 0.5];
+                    let weights = delaunay
+                        .weights(cfd_data, &xyz)
+                        .unwrap_or_else(|| panic!("{xyz:?} is outside"));
+                    assert!((weights.iter().map(|(_, w)| w).sum::<f64>() - 1.).abs() < 1e-9);
+                    let value = delaunay
+                        .interpolate(cfd_data, &xyz, &|sample| sample.temperature())
+                        .unwrap();
+                    assert!((value - linear(xyz)).abs() < 1e-9, "{xyz:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn cubic_lattice() {
+        let cfd_data = cube();
+        let delaunay = DelaunayInterpolation::new(&cfd_data).unwrap();
+        let volume: f64 = (0..delaunay.tetrahedra.len())
+            .filter(|&t| !delaunay.is_exterior(t))
+            .map(|t| delaunay.volume(t))
+            .sum();
+        assert!((volume - 6. * 27.).abs() < 1e-6);
+        assert_linear(&delaunay, &cfd_data);
+    }
+
+    #[test]
+    fn convex_hull() {
+        let cfd_data = cube();
+        let delaunay = DelaunayInterpolation::new(&cfd_data).unwrap();
+        for xyz in [
+            [0.; 3],
+            [3.; 3],
+            [3., 0., 0.],
+            [1.5, 0., 3.],
+            [0., 1.25, 2.5],
+        ] {
+            let weights = delaunay.weights(&cfd_data, &xyz).unwrap();
+            let value: f64 = weights.iter().map(|(s, w)| w * s.temperature()).sum();
+            assert!((value - linear(xyz)).abs() < 1e-9, "{xyz:?}");
+        }
+        for xyz in [
+            [-1e-3, 1., 1.],
+            [1., 3. + 1e-3, 1.],
+            [4., 4., 4.],
+            [-1e3, 0., 0.],
+        ] {
+            assert!(delaunay.weights(&cfd_data, &xyz).is_none(), "{xyz:?}");
+        }
+    }
+
+    #[test]
+    fn duplicate_samples() {
+        let mut samples = lattice([0.; 3], [3.; 3], 1., linear);
+        let n = samples.len();
+        for i in [0, 5, 21, n - 1] {
+            let sample = &samples[i];
+            let duplicate = TemperatureVelocityField::new(
+                sample.temperature(),
+                sample.velocity(),
+                sample.coordinates(),
+                samples.len(),
+            );
+            samples.push(duplicate);
+        }
+        let cfd_data = RTree::bulk_load(samples);
+        let delaunay = DelaunayInterpolation::new(&cfd_data).unwrap();
+        assert_linear(&delaunay, &cfd_data);
+        let weights = delaunay.weights(&cfd_data, &[1., 1., 1.]).unwrap();
+        assert!(weights.iter().all(|(s, _)| s.coordinates() == [1., 1., 1.]));
+    }
+
+    #[test]
+    fn smooth_field() {
+        let cfd_data = dome();
+        let delaunay = DelaunayInterpolation::new(&cfd_data).unwrap();
+        let xyz = [0.3, -1.7, 5.1];
+        let value = delaunay
+            .interpolate(&cfd_data, &xyz, &|sample| sample.temperature())
+            .unwrap();
+        let expected = 285. + (0.1f64).sin() + 0.5 * (-0.34f64).cos() + 0.05 * 5.1;
+        assert!((value - expected).abs() < 0.05);
+    }
+
+    #[test]
+    fn deterministic_location() {
+        // query points on the lattice planes and on the convex hull, located in any order
+        let cfd_data = cube();
+        let delaunay = DelaunayInterpolation::new(&cfd_data).unwrap();
+        let xyz: Vec<[f64; 3]> = (0..=12)
+            .flat_map(|i| (0..=12).flat_map(move |j| (0..=12).map(move |k| [i, j, k])))
+            .map(|ijk| ijk.map(|i| i as f64 * 0.25))
+            .collect();
+        let weights = |xyz: &[f64; 3]| -> Vec<(usize, u64)> {
+            delaunay
+                .weights(&cfd_data, xyz)
+                .unwrap()
+                .into_iter()
+                .map(|(sample, w)| (sample.index(), w.to_bits()))
+                .collect()
+        };
+        let forward: Vec<_> = xyz.iter().map(weights).collect();
+        let backward: Vec<_> = xyz.iter().rev().map(weights).collect();
+        assert!(forward.into_iter().eq(backward.into_iter().rev()));
+        // the location starts from a tetrahedron containing the cell center
+        let grid = &delaunay.grid;
+        assert_eq!(grid.tetrahedra.len(), grid.shape.iter().product::<usize>());
+        assert!(grid.tetrahedra.len() >= 64 / SAMPLES_PER_CELL);
+        for (i, &t) in grid.tetrahedra.iter().enumerate() {
+            assert!(!delaunay.is_exterior(t as usize));
+            let center = [
+                i % grid.shape[0],
+                (i / grid.shape[0]) % grid.shape[1],
+                i / (grid.shape[0] * grid.shape[1]),
+            ]
+            .map(|i| i as f64 + 0.5);
+            let center = [0, 1, 2].map(|i| grid.origin[i] + center[i] * grid.spacing[i]);
+            assert_eq!(grid.cell(&center), i);
+            assert_eq!(
+                delaunay.walk(t as usize, &center, |_| true),
+                Some(t as usize)
+            );
+        }
+    }
+
+    #[test]
+    fn degenerate_samples() {
+        let few = RTree::bulk_load(lattice([0.; 3], [2., 0., 0.], 1., linear));
+        assert!(matches!(
+            DelaunayInterpolation::new(&few),
+            Err(Error::Delaunay(_))
+        ));
+        let coplanar = RTree::bulk_load(lattice([0.; 3], [3., 3., 0.], 1., linear));
+        assert!(matches!(
+            DelaunayInterpolation::new(&coplanar),
+            Err(Error::Delaunay(_))
+        ));
+    }
+
+    #[test]
+    fn predicates() {
+        let [a, b, c, d] = [[0., 0., 0.], [1., 0., 0.], [0., 1., 0.], [0., 0., 1.]];
+        assert_eq!(orient3d(&a, &b, &c, &d), 1.);
+        assert_eq!(orient3d(&b, &a, &c, &d), -1.);
+        assert_eq!(orient3d(&a, &b, &c, &[0.5, 0.5, 0.]), 0.);
+        assert!(insphere(&a, &b, &c, &d, &[0.25; 3]) > 0.);
+        assert!(insphere(&a, &b, &c, &d, &[2.; 3]) < 0.);
+        // the cube corners are cospherical
+        assert_eq!(insphere(&a, &b, &c, &d, &[1., 1., 1.]), 0.);
+        assert_eq!(sort(&[3, 1, 2, 0]), ([0, 1, 2, 3], true));
+        assert_eq!(sort(&[1, 0, 3, 2]), ([0, 1, 2, 3], false));
+    }
+
+    #[test]
+    fn perturbation() {
+        let cfd_data = cube();
+        let delaunay = DelaunayInterpolation::new(&cfd_data).unwrap();
+        let size = 27f64.sqrt();
+        for (v, point) in delaunay.points[..64].iter().enumerate() {
+            let xyz = delaunay.vertex_samples(v as u32)[0].coordinates();
+            assert_ne!(&xyz, point);
+            for i in 0..3 {
+                assert!((xyz[i] - point[i]).abs() <= PERTURBATION * size);
+            }
+        }
+        // the perturbation breaks the cospherical degeneracy of the cube corners
+        let corner = |xyz: [f64; 3]| {
+            let v = (0..64)
+                .find(|&v| delaunay.vertex_samples(v)[0].coordinates() == xyz)
+                .unwrap();
+            delaunay.points[v as usize]
+        };
+        let [a, b, c, d, e] = [
+            [0., 0., 0.],
+            [1., 0., 0.],
+            [0., 1., 0.],
+            [0., 0., 1.],
+            [1., 1., 1.],
+        ]
+        .map(corner);
+        assert_ne!(insphere(&a, &b, &c, &d, &e), 0.);
+    }
+}
@@ -0,0 +1,424 @@
+//! OPD export to Python pickle, Numpy NPY/NPZ and FITS files
+//!
+//! The OPD is exported as a `n_px`x`n_px` row major array of the exit pupil
+//! with NaN outside the exit pupil mask.
+
+use super::{Error, OpdFile, Result};
+use npyz::{
+    npz::NpzWriter,
+    zip::{write::FileOptions, CompressionMethod},
+    DType, TypeStr, WriteOptions, WriterBuilder,
+};
+use std::io::{Cursor, Write};
+
+/// OPD export file formats
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// Python pickle of the flattened exit pupil
+    Pickle,
+    /// Numpy NPY file of the exit pupil
+    Npy,
+    /// Numpy NPZ file of the exit pupil, the mask and the metadata
+    Npz,
+    /// FITS file of the exit pupil with the metadata as header keywords
+    Fits,
+}
+impl ExportFormat {
+    /// Returns the export format matching the extension of the object `key`
+    pub fn from_key(key: &str) -> Option<Self> {
+        match key.rsplit_once('.')?.1 {
+            "pkl" | "pickle" => Some(Self::Pickle),
+            "npy" => Some(Self::Npy),
+            "npz" => Some(Self::Npz),
+            "fits" | "fit" | "fts" => Some(Self::Fits),
+            _ => None,
+        }
+    }
+    /// Returns the file extension
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Pickle => "pkl",
+            Self::Npy => "npy",
+            Self::Npz => "npz",
+            Self::Fits => "fits",
+        }
+    }
+}
+
+impl OpdFile {
+    /// Returns the OPD in the exit pupil with NaN outside the exit pupil mask
+    pub fn pupil(&self) -> Result<Vec<f64>> {
+        let n_px = self.n_px();
+        if n_px * n_px != self.opd.mask.len() {
+            return Err(Error::OpdFile(format!(
+                "the exit pupil mask size ({}) does not match the exit pupil sampling ({n_px})",
+                self.opd.mask.len()
+            )));
+        }
+        let mut values = self.opd.values.iter();
+        Ok(self
+            .opd
+            .mask
+            .iter()
+            .map(|&m| {
+                if m {
+                    values.next().copied().unwrap_or(f64::NAN)
+                } else {
+                    f64::NAN
+                }
+            })
+            .collect())
+    }
+    /// Exports the OPD into the given file `format`
+    pub fn export(&self, format: ExportFormat) -> Result<Vec<u8>> {
+        match format {
+            ExportFormat::Pickle => {
+                let mut data = vec![];
+                serde_pickle::to_writer(&mut data, &self.pupil()?, Default::default())
+                    .map_err(|e| Error::OpdFile(e.to_string()))?;
+                Ok(data)
+            }
+            ExportFormat::Npy => self.to_npy(),
+            ExportFormat::Npz => self.to_npz(),
+            ExportFormat::Fits => self.to_fits(),
+        }
+    }
+    /// Exports the OPD to a Numpy NPY file
+    pub fn to_npy(&self) -> Result<Vec<u8>> {
+        let n_px = self.n_px() as u64;
+        let mut data = vec![];
+        let mut writer = WriteOptions::new()
+            .default_dtype()
+            .shape(&[n_px, n_px])
+            .writer(&mut data)
+            .begin_nd()?;
+        writer.extend(self.pupil()?)?;
+        writer.finish()?;
+        Ok(data)
+    }
+    /// Exports the OPD to a Numpy NPZ file
+    ///
+    /// The archive holds the arrays:
+    ///  - `opd`: the OPD in the exit pupil [m],
+    ///  - `mask`: the exit pupil mask,
+    ///  - `mean`: the mean of the OPD [m],
+    ///  - `version`: the OPD file format version,
+    ///
+    /// and, if the OPD file has metadata, the arrays `n_px`, `wavelength` [micron],
+    /// `step` [m], `time` [s] (NaN if unknown), `cfd_case`, `cfd_file`, `interpolation`,
+    /// `refractive_index`, `quadrature`, `out_of_domain`, `crate_version` and, if the source is known,
+    /// `source`: the source zenith and azimuth angles [rd] and range [m] (infinity if at infinity)
+    pub fn to_npz(&self) -> Result<Vec<u8>> {
+        let n_px = self.n_px() as u64;
+        let mut data = vec![];
+        let mut npz = NpzWriter::new(Cursor::new(&mut data));
+        write_npz_array(&mut npz, "opd", &[n_px, n_px], self.pupil()?)?;
+        let mask: Vec<u8> = self.opd.mask.iter().map(|&m| m as u8).collect();
+        write_npz_array(&mut npz, "mask", &[n_px, n_px], mask)?;
+        write_npz_array(&mut npz, "mean", &[], [self.opd.mean])?;
+        write_npz_array(&mut npz, "version", &[], [self.version()])?;
+        if let Some(metadata) = &self.metadata {
+            write_npz_array(&mut npz, "n_px", &[], [metadata.n_px as u64])?;
+            write_npz_array(&mut npz, "wavelength", &[], [metadata.wavelength])?;
+            write_npz_array(&mut npz, "step", &[], [metadata.step])?;
+            write_npz_array(&mut npz, "time", &[], [metadata.time.unwrap_or(f64::NAN)])?;
+            if let Some(source) = &metadata.source {
+                let range = source.range.unwrap_or(f64::INFINITY);
+                write_npz_array(
+                    &mut npz,
+                    "source",
+                    &[3],
+                    [source.zenith, source.azimuth, range],
+                )?;
+            }
+            let strings = [
+                ("cfd_case", metadata.cfd_case.clone().unwrap_or_default()),
+                ("cfd_file", metadata.cfd_file.clone().unwrap_or_default()),
+                ("interpolation", metadata.interpolation.clone()),
+                ("refractive_index", metadata.refractive_index.clone()),
+                ("quadrature", format!("{:?}", metadata.quadrature)),
+                ("out_of_domain", format!("{:?}", metadata.out_of_domain)),
+                ("crate_version", metadata.version.clone()),
+            ];
+            for (name, value) in strings {
+                write_npz_string(&mut npz, name, &value)?;
+            }
+        }
+        npz.zip_writer().finish().map_err(std::io::Error::from)?;
+        drop(npz);
+        Ok(data)
+    }
+    /// Exports the OPD to a FITS file
+    ///
+    /// The primary HDU is the OPD in the exit pupil [m] as 64 bits floating point numbers
+    /// and the metadata are written as header keywords
+    pub fn to_fits(&self) -> Result<Vec<u8>> {
+        let n_px = self.n_px();
+        let mut header = FitsHeader::default();
+        header.card("SIMPLE", "T", "conforms to FITS standard");
+        header.card("BITPIX", "-64", "64 bits floating point");
+        header.card("NAXIS", "2", "");
+        header.card("NAXIS1", &n_px.to_string(), "exit pupil sampling");
+        header.card("NAXIS2", &n_px.to_string(), "exit pupil sampling");
+        header.string("BUNIT", "m", "OPD unit");
+        header.string("ORIGIN", "cfd_raytrace", "");
+        header.card(
+            "OPDVERS",
+            &self.version().to_string(),
+            "OPD file format version",
+        );
+        header.card("OPDMEAN", &fits_float(self.opd.mean), "[m] OPD mean");
+        if let Some(metadata) = &self.metadata {
+            header.card(
+                "WAVELEN",
+                &fits_float(metadata.wavelength),
+                "[micron] wavelength",
+            );
+            header.card("RTSTEP", &fits_float(metadata.step), "[m] ray tracing step");
+            header.string(
+                "QUADRAT",
+                &format!("{:?}", metadata.quadrature),
+                "ray leg quadrature",
+            );
+            if let Some(time) = metadata.time {
+                header.card("CFDTIME", &fits_float(time), "[s] CFD snapshot time");
+            }
+            if let Some(cfd_case) = &metadata.cfd_case {
+                header.string("CFDCASE", cfd_case, "CFD case");
+            }
+            if let Some(cfd_file) = &metadata.cfd_file {
+                header.string("CFDFILE", cfd_file, "");
+            }
+            header.string("INTERP", &metadata.interpolation, "");
+            header.string("REFRIDX", &metadata.refractive_index, "");
+            header.string(
+                "OUTOFDOM",
+                &format!("{:?}", metadata.out_of_domain),
+                "out of CFD domain policy",
+            );
+            header.string("CRATEVER", &metadata.version, "cfd_raytrace version");
+            if let Some(source) = &metadata.source {
+                header.card(
+                    "SRCZEN",
+                    &fits_float(source.zenith),
+                    "[rd] source zenith angle",
+                );
+                header.card("SRCAZ", &fits_float(source.azimuth), "[rd] source azimuth");
+                if let Some(range) = source.range {
+                    header.card("SRCRANGE", &fits_float(range), "[m] source range");
+                }
+            }
+        }
+        let mut data = header.finish();
+        for value in self.pupil()? {
+            data.write_all(&value.to_be_bytes())?;
+        }
+        data.resize(data.len().next_multiple_of(FITS_BLOCK), 0);
+        Ok(data)
+    }
+}
+
+/// Writes the array `name` of the given `shape` into a NPZ archive
+pub(crate) fn write_npz_array<W, T, I>(
+    npz: &mut NpzWriter<W>,
+    name: &str,
+    shape: &[u64],
+    values: I,
+) -> std::io::Result<()>
+where
+    W: Write + std::io::Seek,
+    T: npyz::AutoSerialize,
+    I: IntoIterator<Item = T>,
+{
+    let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    let mut writer = npz
+        .array::<T>(name, options)?
+        .default_dtype()
+        .shape(shape)
+        .begin_nd()?;
+    writer.extend(values)?;
+    writer.finish()
+}
+
+/// Writes the byte string array `name` into a NPZ archive
+fn write_npz_string<W: Write + std::io::Seek>(
+    npz: &mut NpzWriter<W>,
+    name: &str,
+    value: &str,
+) -> Result<()> {
+    let type_str: TypeStr = format!("|S{}", value.len().max(1))
+        .parse()
+        .map_err(|e| Error::OpdFile(format!("{e:?}")))?;
+    let mut writer = npz
+        .array::<[u8]>(name, FileOptions::default())?
+        .dtype(DType::Plain(type_str))
+        .shape(&[])
+        .begin_nd()?;
+    writer.push(value.as_bytes())?;
+    writer.finish()?;
+    Ok(())
+}
+
+/// FITS block size in bytes
+const FITS_BLOCK: usize = 2880;
+/// FITS header card size in bytes
+const FITS_CARD: usize = 80;
+
+/// FITS header
+#[derive(Default)]
+struct FitsHeader(Vec<u8>);
+impl FitsHeader {
+    /// Adds the `keyword` card with the given `value` and `comment`
+    fn card(&mut self, keyword: &str, value: &str, comment: &str) {
+        let mut card = format!("{keyword:<8}= {value:>20}");
+        if !comment.is_empty() {
+            card.push_str(" / ");
+            card.push_str(comment);
+        }
+        self.push(card);
+    }
+    /// Adds the `keyword` card with the string `value` and `comment`
+    ///
+    /// The string is truncated to fit into the card
+    fn string(&mut self, keyword: &str, value: &str, comment: &str) {
+        let mut escaped = String::new();
+        for c in value
+            .chars()
+            .filter(|c| c.is_ascii() && !c.is_ascii_control())
+        {
+            let n = if c == '\'' { 2 } else { 1 };
+            if escaped.len() + n > FITS_CARD - 12 {
+                break;
+            }
+            escaped.push(c);
+            if c == '\'' {
+                escaped.push(c);
+            }
+        }
+        let value = escaped;
+        let mut card = format!("{keyword:<8}= '{value:<8}'");
+        if !comment.is_empty() {
+            card.push_str(" / ");
+            card.push_str(comment);
+        }
+        self.push(card);
+    }
+    fn push(&mut self, mut card: String) {
+        card.truncate(FITS_CARD);
+        self.0
+            .extend_from_slice(format!("{card:<width$}", width = FITS_CARD).as_bytes());
+    }
+    /// Ends the header, padding it to a FITS block
+    fn finish(mut self) -> Vec<u8> {
+        self.push("END".to_string());
+        let n = self.0.len().next_multiple_of(FITS_BLOCK);
+        self.0.resize(n, b' ');
+        self.0
+    }
+}
+
+/// Formats a FITS floating point value
+fn fits_float(value: f64) -> String {
+    format!("{value:.15E}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opd::tests::opd_file;
+    use npyz::npz::NpzArchive;
+
+    #[test]
+    fn fits_layout() {
+        let opd_file = opd_file();
+        let data = opd_file.to_fits().unwrap();
+        assert_eq!(data.len() % FITS_BLOCK, 0);
+        let cards: Vec<&str> = data[..FITS_BLOCK]
+            .chunks(FITS_CARD)
+            .map(|card| std::str::from_utf8(card).unwrap())
+            .collect();
+        assert_eq!(
+            cards[0],
+            format!(
+                "{:<80}",
+                "SIMPLE  =                    T / conforms to FITS standard"
+            )
+        );
+        assert!(cards[1].starts_with("BITPIX  =                  -64"));
+        assert!(cards[3].starts_with("NAXIS1  =                    3"));
+        assert!(cards
+            .iter()
+            .any(|card| card.starts_with("QUADRAT = 'Riemann '")));
+        assert!(cards
+            .iter()
+            .any(|card| card.starts_with("CFDCASE = 'zen30az000_OS7'")));
+        let end = cards
+            .iter()
+            .position(|card| card.trim_end() == "END")
+            .unwrap();
+        assert!(cards[end + 1..].iter().all(|card| card.trim().is_empty()));
+        // one header block followed by one data block
+        assert_eq!(data.len(), 2 * FITS_BLOCK);
+        let values: Vec<f64> = data[FITS_BLOCK..FITS_BLOCK + 9 * 8]
+            .chunks(8)
+            .map(|x| f64::from_be_bytes(x.try_into().unwrap()))
+            .collect();
+        assert!(values[0].is_nan());
+        assert_eq!(values[1], 1e-9);
+        assert!(values[8].is_nan());
+        assert!(data[FITS_BLOCK + 9 * 8..].iter().all(|&x| x == 0));
+    }
+
+    #[test]
+    fn fits_string() {
+        let mut header = FitsHeader::default();
+        header.string("KEY", "it's", "");
+        header.string("LONG", &"x".repeat(100), "");
+        let data = header.finish();
+        assert_eq!(&data[..20], b"KEY     = 'it''s   '");
+        assert_eq!(data[FITS_CARD + 79], b'\'');
+    }
+
+    #[test]
+    fn npz_arrays() {
+        let data = opd_file().to_npz().unwrap();
+        let mut npz = NpzArchive::new(Cursor::new(data)).unwrap();
+        let mut names: Vec<_> = npz.array_names().map(|x| x.to_string()).collect();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "cfd_case",
+                "cfd_file",
+                "crate_version",
+                "interpolation",
+                "mask",
+                "mean",
+                "n_px",
+                "opd",
+                "out_of_domain",
+                "quadrature",
+                "refractive_index",
+                "source",
+                "step",
+                "time",
+                "version",
+                "wavelength"
+            ]
+        );
+        let dtype = |npz: &mut NpzArchive<_>, name: &str| {
+            let array = npz.by_name(name).unwrap().unwrap();
+            (array.dtype().descr(), array.shape().to_vec())
+        };
+        assert_eq!(dtype(&mut npz, "opd"), ("'<f8'".to_string(), vec![3, 3]));
+        assert_eq!(dtype(&mut npz, "mask"), ("'|u1'".to_string(), vec![3, 3]));
+        assert_eq!(dtype(&mut npz, "mean"), ("'<f8'".to_string(), vec![]));
+        assert_eq!(dtype(&mut npz, "version"), ("'<u4'".to_string(), vec![]));
+        assert_eq!(dtype(&mut npz, "n_px"), ("'<u8'".to_string(), vec![]));
+        assert_eq!(dtype(&mut npz, "source"), ("'<f8'".to_string(), vec![3]));
+        assert_eq!(dtype(&mut npz, "cfd_case"), ("'|S14'".to_string(), vec![]));
+        let opd: Vec<f64> = npz.by_name("opd").unwrap().unwrap().into_vec().unwrap();
+        assert!(opd[0].is_nan());
+        assert_eq!(opd[1..5], [1e-9, -2e-9, 3e-9, -4e-9]);
+    }
+}
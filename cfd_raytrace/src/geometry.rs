@@ -0,0 +1,305 @@
+use super::{export::write_npz_array, storage, Error, Result, Storage};
+use nalgebra::DMatrix;
+use npyz::npz::{NpzArchive, NpzWriter};
+use std::io::{Cursor, Read, Seek};
+
+/// Ray geometry through the telescope
+///
+/// For each exit pupil sample, the ray intersection points `xyz` with the successive surfaces
+/// and the ray direction cosines `klm` from each surface, as `n_px`<sup>2</sup>x3 matrices,
+/// and the exit pupil `mask`.
+/// The rays are ordered row-wise with the x coordinate varying the fastest
+#[derive(Debug, Clone, Default)]
+pub struct RayGeometry {
+    pub mask: Vec<bool>,
+    pub xyz: Vec<DMatrix<f64>>,
+    pub klm: Vec<DMatrix<f64>>,
+}
+
+/// Largest deviations between two [RayGeometry]
+#[derive(Debug, Clone, Default)]
+pub struct GeometryDeviation {
+    /// number of rays within only one of the exit pupil masks
+    pub mask_mismatch: usize,
+    /// largest distance between the ray intersection points for each surface [m]
+    pub xyz: Vec<f64>,
+    /// largest difference between the ray direction cosines for each surface
+    pub klm: Vec<f64>,
+}
+
+impl RayGeometry {
+    /// Loads the ray geometry from a Numpy npz data file at a storage `url`
+    pub fn from_npz<U: AsRef<str>>(url: U) -> Result<Self> {
+        let (storage, key) = storage::from_url(url.as_ref())?;
+        Self::from_npz_in(storage.as_ref(), &key)
+    }
+    /// Loads the ray geometry from the Numpy npz data file `key` in `storage`
+    pub fn from_npz_in(storage: &dyn Storage, key: &str) -> Result<Self> {
+        Self::from_npz_archive(NpzArchive::new(Cursor::new(storage.get(key)?))?)
+    }
+    /// Reads the ray geometry from a npz archive
+    ///
+    /// The number of surfaces is given by the `xyz#` arrays, numbered from 0,
+    /// the direction cosines `klm#` of the last surface are optional
+    pub(crate) fn from_npz_archive<R: Read + Seek>(mut archive: NpzArchive<R>) -> Result<Self> {
+        let names: Vec<String> = archive.array_names().map(|x| x.to_owned()).collect();
+        let indices = |prefix: &str| -> Result<usize> {
+            let mut indices: Vec<usize> = names
+                .iter()
+                .filter_map(|name| name.strip_prefix(prefix)?.parse().ok())
+                .collect();
+            indices.sort_unstable();
+            match indices.iter().enumerate().find(|(k, i)| k != *i) {
+                Some((k, _)) => Err(Error::Geometry(format!("missing array {prefix}{k}"))),
+                None => Ok(indices.len()),
+            }
+        };
+        let (n_xyz, n_klm) = (indices("xyz")?, indices("klm")?);
+        let mut geometry = Self::default();
+        let mask: Vec<u8> = archive
+            .by_name("m")?
+            .ok_or_else(|| Error::Geometry("missing exit pupil mask array m".to_string()))?
+            .into_vec()?;
+        geometry.mask = mask.into_iter().map(|x| x != 0).collect();
+        for (prefix, n, matrices) in [
+            ("xyz", n_xyz, &mut geometry.xyz),
+            ("klm", n_klm, &mut geometry.klm),
+        ] {
+            for k in 0..n {
+                let name = format!("{prefix}{k}");
+                let data = archive.by_name(&name)?.ok_or(Error::NPZ)?;
+                let [n_row, 3] = data.shape()[..] else {
+                    return Err(Error::Geometry(format!(
+                        "expected a Nx3 array {name}, found the shape {:?}",
+                        data.shape()
+                    )));
+                };
+                matrices.push(DMatrix::from_row_slice(
+                    n_row as usize,
+                    3,
+                    data.into_vec()?.as_slice(),
+                ));
+            }
+        }
+        geometry.validate()?;
+        Ok(geometry)
+    }
+    /// Returns the number of surfaces
+    pub fn n_surface(&self) -> usize {
+        self.xyz.len()
+    }
+    /// Checks the consistency of the ray geometry
+    ///
+    /// There must be at least 2 surfaces, the direction cosines of all the surfaces
+    /// but the last one and as many rays as exit pupil samples for each surface
+    pub fn validate(&self) -> Result<()> {
+        if self.mask.is_empty() {
+            return Err(Error::Geometry("empty exit pupil mask".to_string()));
+        }
+        if self.xyz.len() < 2 {
+            return Err(Error::Geometry(format!(
+                "expected at least 2 surfaces, found {}",
+                self.xyz.len()
+            )));
+        }
+        if self.klm.len() + 1 < self.xyz.len() || self.klm.len() > self.xyz.len() {
+            return Err(Error::Geometry(format!(
+                "expected the direction cosines of {} or {} surfaces, found {}",
+                self.xyz.len() - 1,
+                self.xyz.len(),
+                self.klm.len()
+            )));
+        }
+        let n = self.mask.len();
+        for (name, matrices) in [("xyz", &self.xyz), ("klm", &self.klm)] {
+            if let Some((k, matrix)) = matrices
+                .iter()
+                .enumerate()
+                .find(|(_, matrix)| matrix.nrows() != n)
+            {
+                return Err(Error::Geometry(format!(
+                    "expected {n} rays in {name}{k}, found {}",
+                    matrix.nrows()
+                )));
+            }
+        }
+        Ok(())
+    }
+    /// Returns the exit pupil sampling
+    pub fn n_px(&self) -> usize {
+        (self.mask.len() as f64).sqrt().round() as usize
+    }
+    /// Encodes the ray geometry into a Numpy npz data file
+    ///
+    /// The arrays are named as in the ray tracing parameters npz files: `xyz#`, `klm#` and `m`
+    pub fn to_npz(&self) -> Result<Vec<u8>> {
+        let mut data = vec![];
+        let mut npz = NpzWriter::new(Cursor::new(&mut data));
+        for (name, matrices) in [("xyz", &self.xyz), ("klm", &self.klm)] {
+            for (k, matrix) in matrices.iter().enumerate() {
+                let values: Vec<f64> = matrix.transpose().iter().cloned().collect();
+                write_npz_array(
+                    &mut npz,
+                    &format!("{name}{k}"),
+                    &[matrix.nrows() as u64, 3],
+                    values,
+                )?;
+            }
+        }
+        let mask: Vec<u8> = self.mask.iter().map(|&m| m as u8).collect();
+        write_npz_array(&mut npz, "m", &[mask.len() as u64], mask)?;
+        npz.zip_writer().finish().map_err(std::io::Error::from)?;
+        drop(npz);
+        Ok(data)
+    }
+    /// Saves the ray geometry to a Numpy npz data file at a storage `url`
+    pub fn to_npz_url<U: AsRef<str>>(&self, url: U) -> Result<()> {
+        let (storage, key) = storage::from_url(url.as_ref())?;
+        storage.put(&key, &self.to_npz()?)
+    }
+    /// Returns the largest deviations with `other` for the rays within both exit pupil masks
+    pub fn deviation(&self, other: &Self) -> Result<GeometryDeviation> {
+        if self.mask.len() != other.mask.len()
+            || self.xyz.len() != other.xyz.len()
+            || self.klm.len() != other.klm.len()
+        {
+            return Err(Error::Geometry(format!(
+                "cannot compare the geometry of {} rays through {} surfaces with the geometry of {} rays through {} surfaces",
+                self.mask.len(),
+                self.xyz.len(),
+                other.mask.len(),
+                other.xyz.len()
+            )));
+        }
+        let mask_mismatch = self
+            .mask
+            .iter()
+            .zip(&other.mask)
+            .filter(|(a, b)| a != b)
+            .count();
+        let max_deviation = |a: &DMatrix<f64>, b: &DMatrix<f64>| {
+            a.row_iter()
+                .zip(b.row_iter())
+                .zip(self.mask.iter().zip(&other.mask))
+                .filter(|(_, (&a, &b))| a && b)
+                .map(|((a, b), _)| (a - b).norm())
+                .fold(0f64, f64::max)
+        };
+        Ok(GeometryDeviation {
+            mask_mismatch,
+            xyz: self
+                .xyz
+                .iter()
+                .zip(&other.xyz)
+                .map(|(a, b)| max_deviation(a, b))
+                .collect(),
+            klm: self
+                .klm
+                .iter()
+                .zip(&other.klm)
+                .map(|(a, b)| max_deviation(a, b))
+                .collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RayTracer;
+
+    const N: usize = 4;
+
+    /// Returns a npz archive with the given `arrays` of zeros and their number of rows,
+    /// and a `N` samples exit pupil mask
+    fn npz(arrays: &[(&str, usize)]) -> NpzArchive<Cursor<Vec<u8>>> {
+        let mut data = vec![];
+        let mut npz = NpzWriter::new(Cursor::new(&mut data));
+        for &(name, n_row) in arrays {
+            write_npz_array(&mut npz, name, &[n_row as u64, 3], vec![0f64; n_row * 3]).unwrap();
+        }
+        write_npz_array(&mut npz, "m", &[N as u64], vec![1u8; N]).unwrap();
+        npz.zip_writer().finish().unwrap();
+        drop(npz);
+        NpzArchive::new(Cursor::new(data)).unwrap()
+    }
+
+    fn geometry_error(arrays: &[(&str, usize)]) -> String {
+        match RayGeometry::from_npz_archive(npz(arrays)) {
+            Err(Error::Geometry(message)) => message,
+            result => panic!("expected a geometry error, found {result:?}"),
+        }
+    }
+
+    #[test]
+    fn m1_only() {
+        let geometry =
+            RayGeometry::from_npz_archive(npz(&[("xyz0", N), ("xyz1", N), ("klm0", N)])).unwrap();
+        assert_eq!(geometry.n_surface(), 2);
+        assert_eq!(geometry.klm.len(), 1);
+        assert_eq!(geometry.n_px(), 2);
+        let ray_tracer = RayTracer::try_from(geometry).unwrap();
+        assert_eq!(ray_tracer.n_leg(), 1);
+        assert_eq!(ray_tracer.n_sample(), N);
+        // with the direction cosines of the last surface
+        let geometry = RayGeometry::from_npz_archive(npz(&[
+            ("xyz0", N),
+            ("xyz1", N),
+            ("klm0", N),
+            ("klm1", N),
+        ]))
+        .unwrap();
+        assert_eq!(geometry.n_surface(), 2);
+        assert_eq!(geometry.klm.len(), 2);
+    }
+
+    #[test]
+    fn missing_array() {
+        assert_eq!(
+            geometry_error(&[("xyz0", N), ("xyz2", N), ("klm0", N), ("klm1", N)]),
+            "missing array xyz1"
+        );
+        assert_eq!(
+            geometry_error(&[("xyz0", N), ("xyz1", N), ("xyz2", N), ("klm1", N)]),
+            "missing array klm0"
+        );
+        assert!(matches!(
+            RayGeometry::from_npz_archive(npz(&[])),
+            Err(Error::Geometry(_))
+        ));
+    }
+
+    #[test]
+    fn klm_count() {
+        assert_eq!(
+            geometry_error(&[("xyz0", N), ("xyz1", N), ("xyz2", N), ("klm0", N)]),
+            "expected the direction cosines of 2 or 3 surfaces, found 1"
+        );
+        assert_eq!(
+            geometry_error(&[
+                ("xyz0", N),
+                ("xyz1", N),
+                ("klm0", N),
+                ("klm1", N),
+                ("klm2", N)
+            ]),
+            "expected the direction cosines of 1 or 2 surfaces, found 3"
+        );
+        assert_eq!(
+            geometry_error(&[("xyz0", N), ("klm0", N)]),
+            "expected at least 2 surfaces, found 1"
+        );
+    }
+
+    #[test]
+    fn row_count() {
+        assert_eq!(
+            geometry_error(&[("xyz0", N), ("xyz1", N - 1), ("klm0", N)]),
+            format!("expected {N} rays in xyz1, found {}", N - 1)
+        );
+        assert_eq!(
+            geometry_error(&[("xyz0", N), ("xyz1", N), ("klm0", N + 1)]),
+            format!("expected {N} rays in klm0, found {}", N + 1)
+        );
+    }
+}
@@ -0,0 +1,485 @@
+//! GMT pupil geometry
+//!
+//! Generates the [RayGeometry] of the GMT from the M1 and M2 conic prescriptions,
+//! the 7 segments pupil mask, the pupil sampling and the source direction,
+//! replacing the ray tracing parameters npz files.
+//!
+//! The rays are traced in the ray tracing frame, with the origin at M1 vertex and the z axis
+//! along the optical axis toward M2, from an entrance plane above M2 to M1, then to M2
+//! and then to the exit surface.
+//! The exit pupil is sampled on a square grid in the plane of M1 vertex
+//! and the exit pupil mask is given by the location of the ray intersection points with M1.
+//!
+//! ```no_run
+//! use cfd_raytrace::{gmt::{Gmt, Source}, RayGeometry, RayTracer};
+//!
+//! let geometry = Gmt::default().rays(1031, Source::on_axis())?;
+//! let deviation = geometry.deviation(&RayGeometry::from_npz("gs_onaxis_params_1031.u8.npz")?)?;
+//! println!("{deviation:?}");
+//! let ray_tracer = RayTracer::try_from(geometry)?;
+//! # Ok::<(), cfd_raytrace::Error>(())
+//! ```
+
+use super::{Error, RayGeometry, RayTracer, Result};
+use nalgebra::{DMatrix, Vector3};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Conic mirror
+///
+/// The surface is given by r<sup>2</sup> = 2R(z-z<sub>0</sub>) - (1+κ)(z-z<sub>0</sub>)<sup>2</sup>
+/// with `R` the radius of curvature, `κ` the conic constant and z<sub>0</sub> the vertex height
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Conic {
+    /// vertex height [m]
+    pub vertex: f64,
+    /// radius of curvature [m], positive if the center of curvature is above the vertex
+    pub radius: f64,
+    /// conic constant
+    pub conic_constant: f64,
+}
+impl Conic {
+    /// Creates a new conic mirror
+    pub fn new(vertex: f64, radius: f64, conic_constant: f64) -> Self {
+        Self {
+            vertex,
+            radius,
+            conic_constant,
+        }
+    }
+    /// GMT M1 parent conic
+    pub fn gmt_m1() -> Self {
+        Self::new(0., 36., -0.9982857)
+    }
+    /// GMT M2 parent conic
+    pub fn gmt_m2() -> Self {
+        Self::new(20.26247614, -4.1639009, -0.71692784)
+    }
+    /// Returns the surface height at the radial distance `r` from the optical axis
+    pub fn sag(&self, r: f64) -> f64 {
+        let c = self.radius.recip();
+        let r2 = r * r;
+        self.vertex + c * r2 / (1. + (1. - (1. + self.conic_constant) * c * c * r2).sqrt())
+    }
+    /// Returns the unit normal to the surface at `point`
+    fn normal(&self, point: &Vector3<f64>) -> Vector3<f64> {
+        let z = point.z - self.vertex;
+        Vector3::new(
+            point.x,
+            point.y,
+            (1. + self.conic_constant) * z - self.radius,
+        )
+        .normalize()
+    }
+    /// Returns the length along the ray starting at `point` with the `direction` cosines
+    /// to the surface, if any
+    ///
+    /// Of the two intersections with the conic, the one closest to the vertex is selected
+    fn intersect(&self, point: &Vector3<f64>, direction: &Vector3<f64>) -> Option<f64> {
+        let q = point - Vector3::new(0., 0., self.vertex);
+        let d = direction;
+        let kappa = 1. + self.conic_constant;
+        let a = d.x * d.x + d.y * d.y + kappa * d.z * d.z;
+        let b = 2. * (q.x * d.x + q.y * d.y + (kappa * q.z - self.radius) * d.z);
+        let c = q.x * q.x + q.y * q.y + (kappa * q.z - 2. * self.radius) * q.z;
+        let roots = if a.abs() < f64::EPSILON * b.abs() {
+            [-c / b, f64::NAN]
+        } else {
+            let delta = b * b - 4. * a * c;
+            if delta < 0. {
+                return None;
+            }
+            let p = -0.5 * (b + b.signum() * delta.sqrt());
+            [p / a, c / p]
+        };
+        roots
+            .into_iter()
+            .filter(|s| s.is_finite() && *s > 0.)
+            .min_by(|s1, s2| (q.z + s1 * d.z).abs().total_cmp(&(q.z + s2 * d.z).abs()))
+    }
+}
+
+/// GMT M1 segments
+///
+/// The pupil is made of a central segment with a central hole and of 6 outer segments
+/// evenly distributed around it. The outer segments are tilted with respect to the optical axis
+/// and their projection along the optical axis is an ellipse
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Segments {
+    /// segment clear aperture diameter [m]
+    pub diameter: f64,
+    /// diameter of the central segment hole [m]
+    pub central_hole: f64,
+    /// distance of the outer segments center from the optical axis [m]
+    pub distance: f64,
+    /// outer segments tilt [rd]
+    pub tilt: f64,
+    /// azimuth of the first outer segment center from the x axis [rd]
+    pub orientation: f64,
+}
+impl Default for Segments {
+    fn default() -> Self {
+        Self {
+            diameter: 8.365,
+            central_hole: 3.2,
+            distance: 8.71,
+            tilt: 13.522f64.to_radians(),
+            orientation: 0.,
+        }
+    }
+}
+impl Segments {
+    /// Checks if the point (`x`,`y`) projected along the optical axis lies within a segment
+    pub fn contains(&self, x: f64, y: f64) -> bool {
+        let radius = 0.5 * self.diameter;
+        let r = x.hypot(y);
+        if r <= radius {
+            return r >= 0.5 * self.central_hole;
+        }
+        let radial_radius = radius * self.tilt.cos();
+        (0..6).any(|k| {
+            let (sin, cos) = (self.orientation + k as f64 * std::f64::consts::FRAC_PI_3).sin_cos();
+            let u = (x - self.distance * cos) * cos + (y - self.distance * sin) * sin;
+            let v = (y - self.distance * sin) * cos - (x - self.distance * cos) * sin;
+            (u / radial_radius).powi(2) + (v / radius).powi(2) <= 1.
+        })
+    }
+}
+
+/// Point source
+///
+/// The source is either at infinity or, like a laser guide star, at a finite range
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Source {
+    /// angle from the optical axis [rd]
+    pub zenith: f64,
+    /// azimuth angle from the x axis [rd]
+    pub azimuth: f64,
+    /// distance from the ray tracing frame origin [m], `None` for a source at infinity
+    #[serde(default)]
+    pub range: Option<f64>,
+}
+impl Source {
+    /// Creates a source at infinity in the direction given by the `zenith` and `azimuth` angles in radians
+    pub fn new(zenith: f64, azimuth: f64) -> Self {
+        Self {
+            zenith,
+            azimuth,
+            range: None,
+        }
+    }
+    /// On-axis source at infinity
+    pub fn on_axis() -> Self {
+        Default::default()
+    }
+    /// Sets the source at the given `range` in meters
+    pub fn range(mut self, range: f64) -> Self {
+        self.range = Some(range);
+        self
+    }
+    /// Returns the direction cosines of the ray coming from the source toward `point`
+    fn direction(&self, point: &Vector3<f64>) -> Vector3<f64> {
+        let (sin_z, cos_z) = self.zenith.sin_cos();
+        let (sin_a, cos_a) = self.azimuth.sin_cos();
+        let u = Vector3::new(sin_z * cos_a, sin_z * sin_a, cos_z);
+        match self.range {
+            Some(range) => (point - u * range).normalize(),
+            None => -u,
+        }
+    }
+}
+
+/// Surface the rays are traced to after M2
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "surface", rename_all = "kebab-case")]
+pub enum ExitSurface {
+    /// Plane perpendicular to the optical axis at height `z` [m]
+    Plane { z: f64 },
+    /// Sphere centered on the source image in the focal plane at height `focal_plane` [m]
+    /// and going through the paraxial exit pupil on the optical axis
+    ReferenceSphere { focal_plane: f64 },
+}
+impl Default for ExitSurface {
+    fn default() -> Self {
+        Self::ReferenceSphere { focal_plane: -5.83 }
+    }
+}
+
+/// GMT prescription
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Gmt {
+    pub m1: Conic,
+    pub m2: Conic,
+    pub segments: Segments,
+    /// size of the square exit pupil grid [m]
+    pub pupil_size: f64,
+    /// height of the rays entrance plane [m]
+    pub entrance: f64,
+    pub exit: ExitSurface,
+}
+impl Default for Gmt {
+    fn default() -> Self {
+        Self {
+            m1: Conic::gmt_m1(),
+            m2: Conic::gmt_m2(),
+            segments: Default::default(),
+            pupil_size: 25.5,
+            entrance: 25.,
+            exit: Default::default(),
+        }
+    }
+}
+
+/// Ray intersection points and direction cosines after each surface
+type RayPath = [(Vector3<f64>, Vector3<f64>); 4];
+
+/// Returns the direction cosines of the ray after reflection on a surface with the given `normal`
+fn reflect(direction: &Vector3<f64>, normal: &Vector3<f64>) -> Vector3<f64> {
+    direction - 2. * direction.dot(normal) * normal
+}
+
+impl Gmt {
+    /// Returns the height of the paraxial exit pupil, the image of M1 by M2
+    pub fn exit_pupil(&self) -> f64 {
+        let focal_length = -0.5 * self.m2.radius;
+        let distance = self.m2.vertex - self.m1.vertex;
+        self.m2.vertex - (focal_length.recip() - distance.recip()).recip()
+    }
+    /// Traces the ray from the `source` going through (`x`,`y`) in the plane of M1 vertex
+    ///
+    /// The exit surface is not set for the last intersection point
+    fn trace_mirrors(&self, source: &Source, x: f64, y: f64) -> Option<RayPath> {
+        let p = Vector3::new(x, y, self.m1.vertex);
+        let d0 = source.direction(&p);
+        let p0 = p + d0 * ((self.entrance - self.m1.vertex) / d0.z);
+        let p1 = p0 + d0 * self.m1.intersect(&p0, &d0)?;
+        let d1 = reflect(&d0, &self.m1.normal(&p1));
+        let p2 = p1 + d1 * self.m2.intersect(&p1, &d1)?;
+        let d2 = reflect(&d1, &self.m2.normal(&p2));
+        Some([(p0, d0), (p1, d1), (p2, d2), (p2, d2)])
+    }
+    /// Traces the rays from the `source` through the GMT for a `n_px`x`n_px` exit pupil sampling
+    pub fn rays(&self, n_px: usize, source: Source) -> Result<RayGeometry> {
+        if n_px < 2 {
+            return Err(Error::Geometry(format!(
+                "the exit pupil sampling must be at least 2, found {n_px}"
+            )));
+        }
+        // Exit surface: sphere center and radius or plane height
+        let exit = match self.exit {
+            ExitSurface::Plane { z } => Err(z),
+            ExitSurface::ReferenceSphere { focal_plane } => {
+                let [.., (p, d)] = self.trace_mirrors(&source, 0., 0.).ok_or_else(|| {
+                    Error::Geometry("the chief ray does not hit the mirrors".to_string())
+                })?;
+                let center = p + d * ((focal_plane - p.z) / d.z);
+                Ok((center, self.exit_pupil() - focal_plane))
+            }
+        };
+        let to_exit = |p: &Vector3<f64>, d: &Vector3<f64>| -> Option<f64> {
+            match exit {
+                Err(z) => Some((z - p.z) / d.z).filter(|s| *s > 0.),
+                Ok((center, radius)) => {
+                    let q = p - center;
+                    let b = d.dot(&q);
+                    let delta = b * b - q.norm_squared() + radius * radius;
+                    (delta >= 0.)
+                        .then(|| [-b - delta.sqrt(), -b + delta.sqrt()])
+                        .and_then(|roots| roots.into_iter().find(|s| *s > 0.))
+                }
+            }
+        };
+        let paths: Vec<_> = (0..n_px * n_px)
+            .into_par_iter()
+            .map(|i| {
+                let (row, col) = (i / n_px, i % n_px);
+                let x = self.pupil_size * (col as f64 / (n_px - 1) as f64 - 0.5);
+                let y = self.pupil_size * (row as f64 / (n_px - 1) as f64 - 0.5);
+                self.trace_mirrors(&source, x, y).and_then(|mut path| {
+                    let (p, d) = path[2];
+                    path[3].0 = p + d * to_exit(&p, &d)?;
+                    Some(path)
+                })
+            })
+            .collect();
+        let n = paths.len();
+        let mut geometry = RayGeometry {
+            mask: Vec::with_capacity(n),
+            xyz: vec![DMatrix::from_element(n, 3, f64::NAN); 4],
+            klm: vec![DMatrix::from_element(n, 3, f64::NAN); 4],
+        };
+        for (i, path) in paths.into_iter().enumerate() {
+            geometry.mask.push(
+                path.as_ref()
+                    .is_some_and(|path| self.segments.contains(path[1].0.x, path[1].0.y)),
+            );
+            for (k, (p, d)) in path.into_iter().flatten().enumerate() {
+                geometry.xyz[k].row_mut(i).copy_from(&p.transpose());
+                geometry.klm[k].row_mut(i).copy_from(&d.transpose());
+            }
+        }
+        Ok(geometry)
+    }
+}
+
+impl RayTracer {
+    /// Creates a ray tracer for the GMT with a `n_px`x`n_px` exit pupil sampling and the given `source`
+    ///
+    /// See [Gmt::rays]
+    pub fn gmt(n_px: usize, source: Source) -> Result<Self> {
+        Gmt::default().rays(n_px, source)?.try_into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemoryStorage;
+
+    const FOCAL_PLANE: f64 = -5.83;
+
+    fn row(matrix: &DMatrix<f64>, i: usize) -> Vector3<f64> {
+        Vector3::new(matrix[(i, 0)], matrix[(i, 1)], matrix[(i, 2)])
+    }
+
+    #[test]
+    fn conic() {
+        let m1 = Conic::gmt_m1();
+        // vertical ray hitting M1 at r = 4m
+        let p = Vector3::new(4., 0., 25.);
+        let d = Vector3::new(0., 0., -1.);
+        let p1 = p + d * m1.intersect(&p, &d).unwrap();
+        assert!((p1.z - m1.sag(4.)).abs() < 1e-12);
+        // the normal is perpendicular to the surface
+        let h = 1e-6;
+        let tangent = Vector3::new(2. * h, 0., m1.sag(4. + h) - m1.sag(4. - h)).normalize();
+        let normal = m1.normal(&p1);
+        assert!(tangent.dot(&normal).abs() < 1e-9);
+        assert!((normal.norm() - 1.).abs() < 1e-12);
+        assert_eq!(
+            m1.normal(&Vector3::new(0., 0., 0.)),
+            Vector3::new(0., 0., -1.)
+        );
+        // M1 is almost a paraboloid: the reflected ray goes through its focus at R/2
+        let d1 = reflect(&d, &normal);
+        assert!((p1.z - p1.x / d1.x * d1.z - 0.5 * m1.radius).abs() < 1e-3);
+        // M2 is hit from below
+        let m2 = Conic::gmt_m2();
+        let d = Vector3::new(0., 0., 1.);
+        let p2 = p1 + d * m2.intersect(&p1, &d).unwrap();
+        assert!((p2.z - m2.sag(4.)).abs() < 1e-12);
+        // a ray below M1 vertex parallel to its surface misses it
+        assert_eq!(
+            m1.intersect(&Vector3::new(-50., 0., -1.), &Vector3::new(1., 0., 0.)),
+            None
+        );
+    }
+
+    #[test]
+    fn segments() {
+        let segments = Segments::default();
+        // central segment and its hole
+        assert!(!segments.contains(0., 0.));
+        assert!(!segments.contains(1.5, 0.));
+        assert!(segments.contains(2., 0.));
+        assert!(segments.contains(0., 4.1));
+        // outer segment on the x axis, foreshortened radially
+        assert!(segments.contains(8.71, 0.));
+        assert!(segments.contains(8.71 + 4., 0.));
+        assert!(!segments.contains(8.71 + 4.1, 0.));
+        assert!(segments.contains(8.71, 4.1));
+        assert!(!segments.contains(8.71, 4.25));
+        // between the segments
+        assert!(!segments.contains(0., 4.5));
+        assert!(!segments.contains(0., 13.));
+        // the outer segments are evenly distributed
+        let (sin, cos) = std::f64::consts::FRAC_PI_3.sin_cos();
+        assert!(segments.contains(12.7 * cos, 12.7 * sin));
+        assert!(!segments.contains(12.8 * cos, 12.8 * sin));
+    }
+
+    #[test]
+    fn mirrors() {
+        let gmt = Gmt::default();
+        let geometry = gmt.rays(32, Source::on_axis()).unwrap();
+        for i in (0..geometry.mask.len()).filter(|&i| geometry.mask[i]) {
+            for (k, mirror) in [(1, &gmt.m1), (2, &gmt.m2)] {
+                let p = row(&geometry.xyz[k], i);
+                assert!((p.z - mirror.sag(p.x.hypot(p.y))).abs() < 1e-9);
+            }
+            // the direction cosines are unit vectors
+            for klm in &geometry.klm {
+                assert!((row(klm, i).norm() - 1.).abs() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn focus() {
+        let gmt = Gmt::default();
+        let geometry = gmt.rays(32, Source::on_axis()).unwrap();
+        // the on-axis chief ray
+        let chief = gmt.trace_mirrors(&Source::on_axis(), 0., 0.).unwrap();
+        let (p, d) = chief[2];
+        assert_eq!((p.x, p.y), (0., 0.));
+        assert_eq!(d, Vector3::new(0., 0., -1.));
+        // all the rays go through the focal point
+        for i in (0..geometry.mask.len()).filter(|&i| geometry.mask[i]) {
+            let (p, d) = (row(&geometry.xyz[2], i), row(&geometry.klm[2], i));
+            let q = p + d * ((FOCAL_PLANE - p.z) / d.z);
+            assert!(q.x.hypot(q.y) < 1e-6);
+        }
+    }
+
+    #[test]
+    fn reference_sphere() {
+        let gmt = Gmt::default();
+        let radius = gmt.exit_pupil() - FOCAL_PLANE;
+        let geometry = gmt.rays(33, Source::on_axis()).unwrap();
+        for i in (0..geometry.mask.len()).filter(|&i| geometry.mask[i]) {
+            let p = row(&geometry.xyz[3], i);
+            assert!(((p - Vector3::new(0., 0., FOCAL_PLANE)).norm() - radius).abs() < 1e-9);
+            // the exit surface is past M2 toward the focal plane
+            assert!(p.z < row(&geometry.xyz[2], i).z);
+        }
+        // the sphere goes through the paraxial exit pupil on the optical axis
+        let p = row(&geometry.xyz[3], geometry.mask.len() / 2);
+        assert!(p.x.hypot(p.y) < 1e-12);
+        assert!((p.z - gmt.exit_pupil()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn collecting_area() {
+        let gmt = Gmt::default();
+        let n_px = 101;
+        let geometry = gmt.rays(n_px, Source::on_axis()).unwrap();
+        let pixel = gmt.pupil_size / (n_px - 1) as f64;
+        let area = geometry.mask.iter().filter(|m| **m).count() as f64 * pixel * pixel;
+        // GMT collecting area: 368m^2
+        assert!((area / 368. - 1.).abs() < 1e-2, "{area}");
+    }
+
+    #[test]
+    fn deviation() {
+        // geometry of a source 5 arcmin off-axis at 30deg azimuth saved by a previous version
+        let storage = MemoryStorage::new().insert(
+            "gmt_12_off_axis.npz",
+            include_bytes!("../tests/data/gmt_12_off_axis.npz").to_vec(),
+        );
+        let reference = RayGeometry::from_npz_in(&storage, "gmt_12_off_axis.npz").unwrap();
+        let source = Source::new(5f64.to_radians() / 60., 30f64.to_radians());
+        let geometry = Gmt::default().rays(12, source).unwrap();
+        let deviation = geometry.deviation(&reference).unwrap();
+        assert_eq!(deviation.mask_mismatch, 0);
+        assert!(deviation.xyz.iter().all(|x| *x < 1e-9), "{deviation:?}");
+        assert!(deviation.klm.iter().all(|x| *x < 1e-12), "{deviation:?}");
+        // the deviation with the on-axis geometry
+        let on_axis = Gmt::default().rays(12, Source::on_axis()).unwrap();
+        let deviation = on_axis.deviation(&reference).unwrap();
+        assert!(
+            deviation.xyz[0] > 1e-2 && deviation.klm[0] > 1e-3,
+            "{deviation:?}"
+        );
+    }
+}
@@ -0,0 +1,372 @@
+//! CFD data resampled on a regular voxel grid
+//!
+//! The CFD samples are interpolated once onto the nodes of a regular 3D grid
+//! and the ray samples are interpolated from the grid nodes,
+//! similarly to the `RegularGridInterpolator` of the former Python implementation.
+
+use super::{
+    Error, Interpolator, NearestNeighbor, Result, ShepardInterpolation, TemperatureVelocityField,
+};
+use rayon::prelude::*;
+use rstar::RTree;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+/// Voxel grid interpolation methods
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GridSampling {
+    /// Trilinear interpolation from the 8 surrounding nodes
+    #[default]
+    Trilinear,
+    /// Tricubic (Catmull-Rom) interpolation from the 64 surrounding nodes
+    Tricubic,
+}
+
+/// Voxel grid options
+#[derive(Debug, Clone, Copy)]
+pub struct GridOptions {
+    pub(crate) resolution: f64,
+    pub(crate) extent: Option<[[f64; 3]; 2]>,
+    pub(crate) sampling: GridSampling,
+}
+impl Default for GridOptions {
+    fn default() -> Self {
+        Self {
+            resolution: 0.25,
+            extent: None,
+            sampling: Default::default(),
+        }
+    }
+}
+impl GridOptions {
+    /// Sets the largest distance between 2 nodes along each axis [m]
+    pub fn resolution(mut self, resolution: f64) -> Self {
+        self.resolution = resolution;
+        self
+    }
+    /// Sets the grid extent from the `lower` to the `upper` corner,
+    /// defaults to the bounding box of the CFD samples
+    pub fn extent(mut self, lower: [f64; 3], upper: [f64; 3]) -> Self {
+        self.extent = Some([lower, upper]);
+        self
+    }
+    /// Sets the interpolation method
+    pub fn sampling(mut self, sampling: GridSampling) -> Self {
+        self.sampling = sampling;
+        self
+    }
+}
+
+/// CFD data on a regular voxel grid
+///
+/// The grid nodes are indexed in row major order with the x coordinate varying the fastest.
+/// Only the temperature and, if the CFD samples have one, the pressure are resampled
+/// as the refractive index models do not use the other fields.
+/// As an [Interpolator], the grid ignores the CFD data it is given
+/// and returns the grid nodes and weights instead;
+/// the query points outside the grid are outside the CFD domain
+/// and the [OplOperator](crate::OplOperator) applies to the refraction index of the grid nodes.
+pub struct VoxelGrid {
+    origin: [f64; 3],
+    spacing: [f64; 3],
+    shape: [usize; 3],
+    temperature: Vec<f64>,
+    /// NaN where the pressure cannot be interpolated, `None` if no CFD sample has a pressure
+    pressure: Option<Vec<f64>>,
+    sampling: GridSampling,
+}
+impl VoxelGrid {
+    /// Resamples the CFD data on a voxel grid with the Shepard interpolation
+    ///
+    /// The Shepard interpolation radius is twice the grid resolution
+    pub fn from_rtree(
+        cfd_data: &RTree<TemperatureVelocityField>,
+        options: GridOptions,
+    ) -> Result<Self> {
+        let fill = ShepardInterpolation::new(2. * options.resolution);
+        Self::from_rtree_with(cfd_data, options, &fill)
+    }
+    /// Resamples the CFD data on a voxel grid with the given `interpolator`
+    ///
+    /// The nodes where the CFD data cannot be interpolated take the value of the nearest CFD sample
+    pub fn from_rtree_with(
+        cfd_data: &RTree<TemperatureVelocityField>,
+        options: GridOptions,
+        interpolator: &dyn Interpolator,
+    ) -> Result<Self> {
+        if cfd_data.size() == 0 {
+            return Err(Error::Grid("no CFD sample".to_string()));
+        }
+        if !options.resolution.is_finite() || options.resolution <= 0. {
+            return Err(Error::Grid(format!(
+                "expected a positive resolution, found {}",
+                options.resolution
+            )));
+        }
+        let [lower, upper] = options.extent.unwrap_or_else(|| {
+            let envelope = cfd_data.root().envelope();
+            [envelope.lower(), envelope.upper()]
+        });
+        let mut shape = [0; 3];
+        let mut spacing = [0.; 3];
+        for i in 0..3 {
+            let size = upper[i] - lower[i];
+            if !size.is_finite() || size <= 0. {
+                return Err(Error::Grid(format!(
+                    "empty extent from {lower:?} to {upper:?}"
+                )));
+            }
+            shape[i] = (size / options.resolution).ceil() as usize + 1;
+            spacing[i] = size / (shape[i] - 1) as f64;
+        }
+        let [nx, ny, _] = shape;
+        let nodes: Vec<_> = (0..shape.iter().product::<usize>())
+            .into_par_iter()
+            .map(|index| {
+                let ijk = [index % nx, (index / nx) % ny, index / (nx * ny)];
+                let mut xyz = [0.; 3];
+                for i in 0..3 {
+                    xyz[i] = lower[i] + ijk[i] as f64 * spacing[i];
+                }
+                let weights = interpolator
+                    .weights(cfd_data, &xyz)
+                    .or_else(|| NearestNeighbor.weights(cfd_data, &xyz))
+                    .unwrap_or_default();
+                let temperature = weights
+                    .iter()
+                    .map(|(sample, w)| w * sample.temperature())
+                    .sum::<f64>();
+                let pressure = weights
+                    .iter()
+                    .map(|(sample, w)| sample.pressure().map(|p| w * p))
+                    .sum::<Option<f64>>();
+                (temperature, pressure.unwrap_or(f64::NAN))
+            })
+            .collect();
+        let has_pressure = cfd_data.iter().any(|sample| sample.pressure().is_some());
+        let (temperature, pressure): (Vec<_>, Vec<_>) = nodes.into_iter().unzip();
+        Ok(Self {
+            origin: lower,
+            spacing,
+            shape,
+            temperature,
+            pressure: has_pressure.then_some(pressure),
+            sampling: options.sampling,
+        })
+    }
+    /// Sets the interpolation method
+    pub fn sampling(mut self, sampling: GridSampling) -> Self {
+        self.sampling = sampling;
+        self
+    }
+    /// Returns the coordinates of the first node
+    pub fn origin(&self) -> [f64; 3] {
+        self.origin
+    }
+    /// Returns the distance between 2 nodes along each axis
+    pub fn spacing(&self) -> [f64; 3] {
+        self.spacing
+    }
+    /// Returns the number of nodes along each axis
+    pub fn shape(&self) -> [usize; 3] {
+        self.shape
+    }
+    /// Returns the temperature at the grid nodes
+    pub fn temperature(&self) -> &[f64] {
+        &self.temperature
+    }
+    /// Returns the grid node `index` as a CFD sample
+    pub fn node(&self, index: usize) -> Option<TemperatureVelocityField> {
+        if index >= self.temperature.len() {
+            return None;
+        }
+        let [nx, ny, _] = self.shape;
+        Some(self.node_at(index, [index % nx, (index / nx) % ny, index / (nx * ny)]))
+    }
+    /// Returns the grid node `index` at the grid indices `ijk` as a CFD sample
+    fn node_at(&self, index: usize, ijk: [usize; 3]) -> TemperatureVelocityField {
+        let xyz = [0, 1, 2].map(|i| self.origin[i] + ijk[i] as f64 * self.spacing[i]);
+        let pressure = self
+            .pressure
+            .as_ref()
+            .map(|pressure| pressure[index])
+            .filter(|p| !p.is_nan());
+        TemperatureVelocityField::new(self.temperature[index], None, xyz, index)
+            .with_pressure(pressure)
+    }
+    /// Returns the node indices and the interpolation weights along each axis
+    ///
+    /// Each axis has at most 4 nodes, the unused nodes having a zero weight.
+    /// The tricubic interpolation extrapolates linearly the nodes beyond the grid edges
+    fn axis_weights(&self, query_point: &[f64; 3]) -> Option<[[(usize, f64); 4]; 3]> {
+        let mut weights = [[(0, 0.); 4]; 3];
+        for i in 0..3 {
+            let n = self.shape[i];
+            let u = (query_point[i] - self.origin[i]) / self.spacing[i];
+            if !(0f64..=(n - 1) as f64).contains(&u) {
+                return None;
+            }
+            let i0 = (u.floor() as usize).min(n - 2);
+            let t = u - i0 as f64;
+            match self.sampling {
+                GridSampling::Trilinear => {
+                    weights[i][0] = (i0, 1. - t);
+                    weights[i][1] = (i0 + 1, t);
+                }
+                GridSampling::Tricubic => {
+                    // the nodes from i0-1 to i0+2, clamped to the grid
+                    let lower = i0.saturating_sub(1);
+                    weights[i]
+                        .iter_mut()
+                        .zip(lower..)
+                        .for_each(|(node, index)| node.0 = index);
+                    let mut add = |index: usize, w: f64| weights[i][index - lower].1 += w;
+                    let (t2, t3) = (t * t, t * t * t);
+                    [
+                        (-t3 + 2. * t2 - t) / 2.,
+                        (3. * t3 - 5. * t2 + 2.) / 2.,
+                        (-3. * t3 + 4. * t2 + t) / 2.,
+                        (t3 - t2) / 2.,
+                    ]
+                    .into_iter()
+                    .enumerate()
+                    .for_each(|(j, w)| match i0 + j {
+                        0 => {
+                            add(0, 2. * w);
+                            add(1, -w);
+                        }
+                        j if j > n => {
+                            add(n - 1, 2. * w);
+                            add(n - 2, -w);
+                        }
+                        j => add(j - 1, w),
+                    });
+                }
+            }
+        }
+        Some(weights)
+    }
+}
+impl Interpolator for VoxelGrid {
+    fn description(&self) -> String {
+        let [dx, dy, dz] = self.spacing;
+        let sampling = match self.sampling {
+            GridSampling::Trilinear => "trilinear",
+            GridSampling::Tricubic => "tricubic",
+        };
+        format!("{sampling} voxel grid (spacing: {dx:.3}x{dy:.3}x{dz:.3}m)")
+    }
+    fn cfd_samples(&self) -> bool {
+        false
+    }
+    fn weights<'a>(
+        &'a self,
+        _cfd_data: &'a RTree<TemperatureVelocityField>,
+        query_point: &[f64; 3],
+    ) -> Option<Vec<(Cow<'a, TemperatureVelocityField>, f64)>> {
+        let [wx, wy, wz] = self
+            .axis_weights(query_point)?
+            .map(|axis| axis.into_iter().filter(|(_, w)| *w != 0.));
+        let [nx, ny, _] = self.shape;
+        let mut weights =
+            Vec::with_capacity(wx.clone().count() * wy.clone().count() * wz.clone().count());
+        for (k, w_z) in wz {
+            for (j, w_y) in wy.clone() {
+                for (i, w_x) in wx.clone() {
+                    let index = i + nx * (j + ny * k);
+                    weights.push((Cow::Owned(self.node_at(index, [i, j, k])), w_x * w_y * w_z));
+                }
+            }
+        }
+        Some(weights)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{heap_usage, lattice};
+
+    fn linear([x, y, z]: [f64; 3]) -> f64 {
+        280. + 0.5 * x - 0.25 * y + 0.1 * z
+    }
+
+    fn linear_grid(sampling: GridSampling) -> (RTree<TemperatureVelocityField>, VoxelGrid) {
+        let cfd_data = RTree::bulk_load(lattice([0.; 3], [2., 3., 4.], 0.5, linear));
+        let options = GridOptions::default().resolution(0.5).sampling(sampling);
+        let grid = VoxelGrid::from_rtree_with(&cfd_data, options, &NearestNeighbor).unwrap();
+        (cfd_data, grid)
+    }
+
+    fn assert_exact(sampling: GridSampling) {
+        let (cfd_data, grid) = linear_grid(sampling);
+        assert_eq!(grid.shape(), [5, 7, 9]);
+        for i in 0..=8 {
+            for j in 0..=12 {
+                for k in 0..=16 {
+                    let xyz = [
+                        i as f64 * 0.25,
+                        j as f64 * 0.25 - 1e-3,
+                        k as f64 * 0.25 + 1e-3,
+                    ];
+                    let xyz = xyz.map(|x| x.clamp(0., 4.));
+                    let value = grid
+                        .interpolate(&cfd_data, &xyz, &|sample| sample.temperature())
+                        .unwrap();
+                    assert!(
+                        (value - linear(xyz)).abs() < 1e-9,
+                        "{sampling:?} at {xyz:?}: {value} != {}",
+                        linear(xyz)
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn trilinear_linear_field() {
+        assert_exact(GridSampling::Trilinear);
+    }
+
+    #[test]
+    fn tricubic_linear_field() {
+        assert_exact(GridSampling::Tricubic);
+    }
+
+    #[test]
+    fn nodes() {
+        let (_, grid) = linear_grid(GridSampling::Trilinear);
+        assert_eq!(grid.temperature().len(), 5 * 7 * 9);
+        assert!(grid.pressure.is_none());
+        let node = grid.node(1 + 5 * (2 + 7 * 3)).unwrap();
+        assert_eq!(node.coordinates(), [0.5, 1., 1.5]);
+        assert_eq!(node.temperature(), linear([0.5, 1., 1.5]));
+        assert_eq!(node.pressure(), None);
+        assert!(grid.node(5 * 7 * 9).is_none());
+    }
+
+    #[test]
+    fn outside() {
+        let (cfd_data, grid) = linear_grid(GridSampling::Tricubic);
+        assert!(grid.weights(&cfd_data, &[-1e-3, 1., 1.]).is_none());
+        assert!(grid.weights(&cfd_data, &[1., 3. + 1e-3, 1.]).is_none());
+    }
+
+    #[test]
+    fn weights_allocation() {
+        // the nodes are built in place into the weights, the only heap allocation
+        for (sampling, n) in [(GridSampling::Trilinear, 8), (GridSampling::Tricubic, 64)] {
+            let (cfd_data, grid) = linear_grid(sampling);
+            let (weights, peak, _) =
+                heap_usage(|| grid.weights(&cfd_data, &[1.1, 1.3, 1.7]).unwrap());
+            assert_eq!(weights.len(), n);
+            assert_eq!(
+                peak,
+                n * std::mem::size_of::<(Cow<TemperatureVelocityField>, f64)>()
+            );
+            let sum: f64 = weights.iter().map(|(_, w)| w).sum();
+            assert!((sum - 1.).abs() < 1e-12);
+        }
+    }
+}
@@ -0,0 +1,646 @@
+use super::TemperatureVelocityField;
+use rstar::{PointDistance, RTree};
+use std::{borrow::Cow, fmt, sync::Mutex};
+
+/// Interface to the CFD data interpolation methods
+pub trait Interpolator: Send + Sync {
+    /// Returns the CFD samples and weights used to interpolate at `query_point`
+    ///
+    /// The weights sum to 1 and `None` is returned if no sample can be found.
+    /// The samples are either borrowed from `cfd_data` or built by the interpolator.
+    fn weights<'a>(
+        &'a self,
+        cfd_data: &'a RTree<TemperatureVelocityField>,
+        query_point: &[f64; 3],
+    ) -> Option<Vec<(Cow<'a, TemperatureVelocityField>, f64)>>;
+    /// Interpolates the CFD samples `value` at `query_point`
+    fn interpolate(
+        &self,
+        cfd_data: &RTree<TemperatureVelocityField>,
+        query_point: &[f64; 3],
+        value: &dyn Fn(&TemperatureVelocityField) -> f64,
+    ) -> Option<f64> {
+        self.weights(cfd_data, query_point).map(|weights| {
+            weights
+                .into_iter()
+                .map(|(sample, w)| w * value(&sample))
+                .sum()
+        })
+    }
+    /// Returns a description of the interpolation method
+    fn description(&self) -> String {
+        std::any::type_name::<Self>().to_string()
+    }
+    /// Interpolates the refraction index at `query_point`
+    fn refraction_index(
+        &self,
+        cfd_data: &RTree<TemperatureVelocityField>,
+        query_point: &[f64; 3],
+    ) -> Option<f64> {
+        self.interpolate(cfd_data, query_point, &|sample| sample.refraction_index())
+    }
+    /// Returns the statistics of the interpolation radius since the last reset
+    ///
+    /// `None` is returned if the interpolation radius does not adapt to the query points
+    fn radius_statistics(&self) -> Option<RadiusStatistics> {
+        None
+    }
+    /// Resets the statistics of the interpolation radius
+    fn reset_radius_statistics(&self) {}
+    /// Returns `true` if the samples returned by [Interpolator::weights] are CFD samples,
+    /// indexed as in the CFD csv file
+    fn cfd_samples(&self) -> bool {
+        true
+    }
+}
+
+/// Nearest neighbor interpolation
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NearestNeighbor;
+impl Interpolator for NearestNeighbor {
+    fn description(&self) -> String {
+        "nearest neighbor".to_string()
+    }
+    fn weights<'a>(
+        &'a self,
+        cfd_data: &'a RTree<TemperatureVelocityField>,
+        query_point: &[f64; 3],
+    ) -> Option<Vec<(Cow<'a, TemperatureVelocityField>, f64)>> {
+        cfd_data
+            .nearest_neighbor(query_point)
+            .map(|nn| vec![(Cow::Borrowed(nn), 1f64)])
+    }
+}
+
+/// Shepard radial basis function interpolation
+///
+/// Interpolates using all the samples within the squared radius `max_squared_radius`
+/// from the query point.
+/// The radial basis function is r<sup>-p</sup> with the power p defaulting to 2.
+/// If there is no sample within the radius, the nearest neighbor is used instead.
+#[derive(Debug, Clone, Copy)]
+pub struct ShepardInterpolation {
+    pub(crate) max_squared_radius: f64,
+    pub(crate) power: f64,
+}
+impl ShepardInterpolation {
+    /// Creates a new Shepard interpolation within a sphere of the given `radius`
+    pub fn new(radius: f64) -> Self {
+        Self {
+            max_squared_radius: radius * radius,
+            power: 2.,
+        }
+    }
+    /// Sets the power of the inverse distance weights
+    pub fn power(mut self, power: f64) -> Self {
+        self.power = power;
+        self
+    }
+    /// Returns the radius of the interpolation sphere
+    pub fn radius(&self) -> f64 {
+        self.max_squared_radius.sqrt()
+    }
+}
+impl Default for ShepardInterpolation {
+    fn default() -> Self {
+        Self::new(0.5)
+    }
+}
+impl Interpolator for ShepardInterpolation {
+    fn description(&self) -> String {
+        if self.power == 2. {
+            format!("Shepard (radius: {}m)", self.radius())
+        } else {
+            format!(
+                "Shepard (radius: {}m, power: {})",
+                self.radius(),
+                self.power
+            )
+        }
+    }
+    fn weights<'a>(
+        &'a self,
+        cfd_data: &'a RTree<TemperatureVelocityField>,
+        query_point: &[f64; 3],
+    ) -> Option<Vec<(Cow<'a, TemperatureVelocityField>, f64)>> {
+        let samples = cfd_data.locate_within_distance(*query_point, self.max_squared_radius);
+        normalized_weights(samples, query_point, |d2| inverse_distance(d2, self.power))
+            .or_else(|| NearestNeighbor.weights(cfd_data, query_point))
+    }
+}
+
+/// Inverse distance weighting interpolation of the k nearest neighbors
+///
+/// The radial basis function is r<sup>-p</sup> with the power p defaulting to 2.
+#[derive(Debug, Clone, Copy)]
+pub struct KNearestInterpolation {
+    pub(crate) k: usize,
+    pub(crate) power: f64,
+}
+impl KNearestInterpolation {
+    /// Creates a new inverse distance weighting interpolation of the `k` nearest neighbors
+    pub fn new(k: usize) -> Self {
+        Self {
+            k: k.max(1),
+            power: 2.,
+        }
+    }
+    /// Sets the power of the inverse distance weights
+    pub fn power(mut self, power: f64) -> Self {
+        self.power = power;
+        self
+    }
+    /// Returns the number of nearest neighbors
+    pub fn k(&self) -> usize {
+        self.k
+    }
+}
+impl Default for KNearestInterpolation {
+    fn default() -> Self {
+        Self::new(8)
+    }
+}
+impl Interpolator for KNearestInterpolation {
+    fn description(&self) -> String {
+        format!("{}-nearest neighbors (power: {})", self.k, self.power)
+    }
+    fn weights<'a>(
+        &'a self,
+        cfd_data: &'a RTree<TemperatureVelocityField>,
+        query_point: &[f64; 3],
+    ) -> Option<Vec<(Cow<'a, TemperatureVelocityField>, f64)>> {
+        let samples = cfd_data.nearest_neighbor_iter(query_point).take(self.k);
+        normalized_weights(samples, query_point, |d2| inverse_distance(d2, self.power))
+    }
+}
+
+/// Statistics of the interpolation radius
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RadiusStatistics {
+    count: usize,
+    sum: f64,
+    sum_squared: f64,
+    min: f64,
+    max: f64,
+}
+impl Default for RadiusStatistics {
+    fn default() -> Self {
+        Self {
+            count: 0,
+            sum: 0.,
+            sum_squared: 0.,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+}
+impl RadiusStatistics {
+    /// Adds a `radius` to the statistics
+    pub fn push(&mut self, radius: f64) {
+        self.count += 1;
+        self.sum += radius;
+        self.sum_squared += radius * radius;
+        self.min = self.min.min(radius);
+        self.max = self.max.max(radius);
+    }
+    /// Merges the statistics with `other`
+    pub fn merge(&mut self, other: &Self) {
+        self.count += other.count;
+        self.sum += other.sum;
+        self.sum_squared += other.sum_squared;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+    /// Returns the number of interpolations
+    pub fn count(&self) -> usize {
+        self.count
+    }
+    /// Returns the smallest radius [m]
+    pub fn min(&self) -> f64 {
+        self.min
+    }
+    /// Returns the largest radius [m]
+    pub fn max(&self) -> f64 {
+        self.max
+    }
+    /// Returns the mean radius [m]
+    pub fn mean(&self) -> f64 {
+        self.sum / self.count as f64
+    }
+    /// Returns the standard deviation of the radius [m]
+    pub fn std(&self) -> f64 {
+        let mean = self.mean();
+        (self.sum_squared / self.count as f64 - mean * mean)
+            .max(0.)
+            .sqrt()
+    }
+}
+impl fmt::Display for RadiusStatistics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.count == 0 {
+            return write!(f, "no interpolation");
+        }
+        write!(
+            f,
+            "radius min: {:.3}m, mean: {:.3}m, std: {:.3}m, max: {:.3}m over {} interpolations",
+            self.min,
+            self.mean(),
+            self.std(),
+            self.max,
+            self.count
+        )
+    }
+}
+
+/// Number of shards of the adaptive interpolation radius statistics
+const N_SHARD: usize = 64;
+
+/// Density adaptive Shepard interpolation
+///
+/// Interpolates using all the samples within a radius that scales with the local sample spacing:
+/// the radius is the distance to the k-th nearest neighbor of the query point times a scale factor,
+/// clamped to the optional minimum and maximum radius.
+/// The radial basis function is r<sup>-p</sup> with the power p defaulting to 2.
+///
+/// The statistics of the radius are gathered for each interpolation,
+/// see [Interpolator::radius_statistics]; they are not part of the description
+/// so the description depends on the interpolation parameters only
+#[derive(Debug)]
+pub struct AdaptiveInterpolation {
+    pub(crate) k: usize,
+    pub(crate) scale: f64,
+    pub(crate) power: f64,
+    pub(crate) min_radius: f64,
+    pub(crate) max_radius: f64,
+    statistics: Vec<Mutex<RadiusStatistics>>,
+}
+impl AdaptiveInterpolation {
+    /// Creates a new adaptive interpolation within the distance to the `k`-th nearest neighbor
+    pub fn new(k: usize) -> Self {
+        Self {
+            k: k.max(1),
+            scale: 1.,
+            power: 2.,
+            min_radius: 0.,
+            max_radius: f64::INFINITY,
+            statistics: (0..N_SHARD).map(|_| Default::default()).collect(),
+        }
+    }
+    /// Sets the scale factor of the distance to the k-th nearest neighbor
+    pub fn scale(mut self, scale: f64) -> Self {
+        self.scale = scale;
+        self
+    }
+    /// Sets the power of the inverse distance weights
+    pub fn power(mut self, power: f64) -> Self {
+        self.power = power;
+        self
+    }
+    /// Sets the minimum radius [m]
+    pub fn min_radius(mut self, min_radius: f64) -> Self {
+        self.min_radius = min_radius;
+        self
+    }
+    /// Sets the maximum radius [m]
+    pub fn max_radius(mut self, max_radius: f64) -> Self {
+        self.max_radius = max_radius;
+        self
+    }
+    /// Returns the interpolation radius at `query_point`
+    pub fn radius(
+        &self,
+        cfd_data: &RTree<TemperatureVelocityField>,
+        query_point: &[f64; 3],
+    ) -> Option<f64> {
+        self.squared_radius(cfd_data, query_point).map(f64::sqrt)
+    }
+    /// Returns the squared interpolation radius at `query_point`
+    ///
+    /// The squared radius is computed from the squared distance to the k-th nearest neighbor
+    /// so that, for a unit scale factor, the k-th nearest neighbor lies within the radius
+    fn squared_radius(
+        &self,
+        cfd_data: &RTree<TemperatureVelocityField>,
+        query_point: &[f64; 3],
+    ) -> Option<f64> {
+        cfd_data
+            .nearest_neighbor_iter_with_distance_2(query_point)
+            .take(self.k)
+            .last()
+            .map(|(_, d2)| {
+                (self.scale * self.scale * d2)
+                    .max(self.min_radius * self.min_radius)
+                    .min(self.max_radius * self.max_radius)
+            })
+    }
+}
+impl Default for AdaptiveInterpolation {
+    fn default() -> Self {
+        Self::new(8)
+    }
+}
+impl Interpolator for AdaptiveInterpolation {
+    fn description(&self) -> String {
+        format!(
+            "adaptive Shepard (k: {}, scale: {}, power: {})",
+            self.k, self.scale, self.power
+        )
+    }
+    fn weights<'a>(
+        &'a self,
+        cfd_data: &'a RTree<TemperatureVelocityField>,
+        query_point: &[f64; 3],
+    ) -> Option<Vec<(Cow<'a, TemperatureVelocityField>, f64)>> {
+        let squared_radius = self.squared_radius(cfd_data, query_point)?;
+        let shard = rayon::current_thread_index().unwrap_or_default() % N_SHARD;
+        if let Ok(mut statistics) = self.statistics[shard].lock() {
+            statistics.push(squared_radius.sqrt());
+        }
+        let samples = cfd_data.locate_within_distance(*query_point, squared_radius);
+        normalized_weights(samples, query_point, |d2| inverse_distance(d2, self.power))
+            .or_else(|| NearestNeighbor.weights(cfd_data, query_point))
+    }
+    fn radius_statistics(&self) -> Option<RadiusStatistics> {
+        let mut statistics = RadiusStatistics::default();
+        for shard in &self.statistics {
+            if let Ok(shard) = shard.lock() {
+                statistics.merge(&shard);
+            }
+        }
+        Some(statistics)
+    }
+    fn reset_radius_statistics(&self) {
+        for shard in &self.statistics {
+            if let Ok(mut shard) = shard.lock() {
+                *shard = Default::default();
+            }
+        }
+    }
+}
+
+/// Compactly supported radial basis functions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RbfKernel {
+    /// Wendland C<sup>2</sup> function (1-r/R)<sup>4</sup>(1+4r/R) of the support radius R
+    Wendland,
+    /// Gaussian function exp(-r<sup>2</sup>/(2σ<sup>2</sup>)) truncated at the support radius
+    Gaussian {
+        /// standard deviation σ [m]
+        sigma: f64,
+    },
+}
+
+/// Normalized radial basis function interpolation
+///
+/// Interpolates using all the samples within the support radius of the [RbfKernel]
+/// from the query point, with weights normalized to sum to 1.
+/// If there is no sample within the radius, the nearest neighbor is used instead.
+#[derive(Debug, Clone, Copy)]
+pub struct RbfInterpolation {
+    pub(crate) kernel: RbfKernel,
+    pub(crate) radius: f64,
+}
+impl RbfInterpolation {
+    /// Creates a new radial basis function interpolation of the support `radius`
+    pub fn new(kernel: RbfKernel, radius: f64) -> Self {
+        Self { kernel, radius }
+    }
+    /// Creates a new Wendland radial basis function interpolation of the support `radius`
+    pub fn wendland(radius: f64) -> Self {
+        Self::new(RbfKernel::Wendland, radius)
+    }
+    /// Creates a new Gaussian radial basis function interpolation of standard deviation `sigma`
+    /// truncated at `radius`
+    pub fn gaussian(radius: f64, sigma: f64) -> Self {
+        Self::new(RbfKernel::Gaussian { sigma }, radius)
+    }
+    /// Returns the support radius
+    pub fn radius(&self) -> f64 {
+        self.radius
+    }
+    /// Returns the radial basis function value for the squared distance `d2`
+    fn rbf(&self, d2: f64) -> f64 {
+        match self.kernel {
+            RbfKernel::Wendland => {
+                let q = d2.sqrt() / self.radius;
+                (1. - q).max(0.).powi(4) * (1. + 4. * q)
+            }
+            RbfKernel::Gaussian { sigma } => (-0.5 * d2 / (sigma * sigma)).exp(),
+        }
+    }
+}
+impl Interpolator for RbfInterpolation {
+    fn description(&self) -> String {
+        match self.kernel {
+            RbfKernel::Wendland => format!("Wendland RBF (radius: {}m)", self.radius),
+            RbfKernel::Gaussian { sigma } => {
+                format!("Gaussian RBF (radius: {}m, sigma: {sigma}m)", self.radius)
+            }
+        }
+    }
+    fn weights<'a>(
+        &'a self,
+        cfd_data: &'a RTree<TemperatureVelocityField>,
+        query_point: &[f64; 3],
+    ) -> Option<Vec<(Cow<'a, TemperatureVelocityField>, f64)>> {
+        let samples = cfd_data.locate_within_distance(*query_point, self.radius * self.radius);
+        normalized_weights(samples, query_point, |d2| self.rbf(d2))
+            .or_else(|| NearestNeighbor.weights(cfd_data, query_point))
+    }
+}
+
+/// Returns the inverse distance weight r<sup>-p</sup> for the squared distance `d2` and the power `p`
+fn inverse_distance(d2: f64, p: f64) -> f64 {
+    if p == 2. {
+        d2.recip()
+    } else {
+        d2.powf(-p / 2.)
+    }
+}
+
+/// Returns the `samples` weights given by the radial basis function `rbf` of the squared distance
+/// to `query_point`, normalized to sum to 1
+///
+/// A sample at `query_point` gets all the weight and `None` is returned if the weights sum to 0
+fn normalized_weights<'a, I, F>(
+    samples: I,
+    query_point: &[f64; 3],
+    rbf: F,
+) -> Option<Vec<(Cow<'a, TemperatureVelocityField>, f64)>>
+where
+    I: Iterator<Item = &'a TemperatureVelocityField>,
+    F: Fn(f64) -> f64,
+{
+    let mut weights = vec![];
+    let mut denom = 0f64;
+    for sample in samples {
+        let d2 = sample.distance_2(query_point);
+        if d2 > 0f64 {
+            let rbf = rbf(d2);
+            weights.push((Cow::Borrowed(sample), rbf));
+            denom += rbf;
+        } else {
+            return Some(vec![(Cow::Borrowed(sample), 1f64)]);
+        }
+    }
+    if denom > 0f64 {
+        weights.iter_mut().for_each(|(_, w)| *w /= denom);
+        Some(weights)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::lattice;
+
+    fn cfd_data() -> RTree<TemperatureVelocityField> {
+        RTree::bulk_load(lattice([0.; 3], [4.; 3], 1., |_| 285.))
+    }
+
+    /// Query points inside, on the edge and outside of the lattice, none on a sample
+    const QUERY_POINTS: [[f64; 3]; 4] = [
+        [1.3, 2.7, 0.4],
+        [2.5, 2.5, 2.5],
+        [0., 3.2, 4.],
+        [-1.5, 5.2, 2.1],
+    ];
+
+    fn interpolators() -> Vec<Box<dyn Interpolator>> {
+        vec![
+            Box::new(ShepardInterpolation::new(1.5)),
+            Box::new(KNearestInterpolation::new(8)),
+            Box::new(KNearestInterpolation::new(5).power(3.)),
+            Box::new(AdaptiveInterpolation::new(8)),
+            Box::new(AdaptiveInterpolation::new(4).scale(1.5).power(1.)),
+            Box::new(RbfInterpolation::wendland(1.5)),
+            Box::new(RbfInterpolation::gaussian(2., 0.7)),
+        ]
+    }
+
+    #[test]
+    fn constant_field() {
+        let cfd_data = cfd_data();
+        for interpolator in interpolators() {
+            for xyz in QUERY_POINTS {
+                let weights = interpolator.weights(&cfd_data, &xyz).unwrap();
+                let sum: f64 = weights.iter().map(|(_, w)| w).sum();
+                assert!(
+                    (sum - 1.).abs() < 1e-12,
+                    "{}: {sum}",
+                    interpolator.description()
+                );
+                assert!(weights.iter().all(|(_, w)| *w > 0.));
+                let temperature = interpolator
+                    .interpolate(&cfd_data, &xyz, &|sample| sample.temperature())
+                    .unwrap();
+                assert!((temperature - 285.).abs() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn on_sample() {
+        let cfd_data = cfd_data();
+        for interpolator in interpolators() {
+            let weights = interpolator.weights(&cfd_data, &[1., 2., 3.]).unwrap();
+            assert_eq!(weights.len(), 1, "{}", interpolator.description());
+            assert_eq!(weights[0].0.coordinates(), [1., 2., 3.]);
+            assert_eq!(weights[0].1, 1.);
+        }
+    }
+
+    #[test]
+    fn k_nearest() {
+        let cfd_data = cfd_data();
+        let k_nearest = KNearestInterpolation::new(8);
+        let weights = k_nearest.weights(&cfd_data, &[1.5, 1.5, 1.5]).unwrap();
+        // the 8 corners of the voxel, at the same distance
+        assert_eq!(weights.len(), 8);
+        for (sample, w) in &weights {
+            assert!(sample.coordinates().iter().all(|x| *x == 1. || *x == 2.));
+            assert!((w - 0.125).abs() < 1e-12);
+        }
+        let k_nearest = KNearestInterpolation::new(2);
+        let weights = k_nearest.weights(&cfd_data, &[1.25, 1., 1.]).unwrap();
+        // inverse square distance weights at 0.25m and 0.75m
+        assert_eq!(weights.len(), 2);
+        assert!((weights[0].1 - 0.9).abs() < 1e-12);
+        assert!((weights[1].1 - 0.1).abs() < 1e-12);
+    }
+
+    #[test]
+    fn adaptive_radius() {
+        let cfd_data = cfd_data();
+        let xyz = [1.5, 1.5, 1.5];
+        // the 8 voxel corners are the nearest neighbors at sqrt(3)/2
+        let half_diagonal = 0.75f64.sqrt();
+        let adaptive = AdaptiveInterpolation::new(8);
+        assert!((adaptive.radius(&cfd_data, &xyz).unwrap() - half_diagonal).abs() < 1e-12);
+        assert_eq!(adaptive.weights(&cfd_data, &xyz).unwrap().len(), 8);
+        let adaptive = AdaptiveInterpolation::new(8).scale(2.);
+        assert!((adaptive.radius(&cfd_data, &xyz).unwrap() - 2. * half_diagonal).abs() < 1e-12);
+        assert!(adaptive.weights(&cfd_data, &xyz).unwrap().len() > 8);
+        let adaptive = AdaptiveInterpolation::new(8).max_radius(0.5);
+        assert_eq!(adaptive.radius(&cfd_data, &xyz), Some(0.5));
+        // no sample within the radius, falling back to the nearest neighbor
+        assert_eq!(adaptive.weights(&cfd_data, &xyz).unwrap().len(), 1);
+        let adaptive = AdaptiveInterpolation::new(1).min_radius(1.2);
+        assert_eq!(adaptive.radius(&cfd_data, &xyz), Some(1.2));
+        // the radius adapts to the sample spacing
+        let sparse = RTree::bulk_load(lattice([0.; 3], [8.; 3], 2., |_| 285.));
+        let adaptive = AdaptiveInterpolation::new(8);
+        let radius = adaptive.radius(&sparse, &[3., 3., 3.]).unwrap();
+        assert!((radius - 2. * half_diagonal).abs() < 1e-12);
+        assert!(AdaptiveInterpolation::new(8)
+            .radius(&RTree::new(), &xyz)
+            .is_none());
+    }
+
+    #[test]
+    fn radius_statistics() {
+        let cfd_data = cfd_data();
+        let adaptive = AdaptiveInterpolation::new(8).min_radius(1.);
+        let description = adaptive.description();
+        assert_eq!(adaptive.radius_statistics().unwrap().count(), 0);
+        for xyz in [[1.5, 1.5, 1.5], [1.5, 1.5, 1.], [10., 1.5, 1.5]] {
+            adaptive.weights(&cfd_data, &xyz).unwrap();
+        }
+        let statistics = adaptive.radius_statistics().unwrap();
+        assert_eq!(statistics.count(), 3);
+        assert_eq!(statistics.min(), 1.);
+        assert!(statistics.max() > 6.);
+        assert_eq!(adaptive.description(), description);
+        adaptive.reset_radius_statistics();
+        assert_eq!(adaptive.radius_statistics().unwrap().count(), 0);
+        assert!(KNearestInterpolation::default()
+            .radius_statistics()
+            .is_none());
+    }
+
+    #[test]
+    fn rbf() {
+        let cfd_data = cfd_data();
+        let wendland = RbfInterpolation::wendland(1.5);
+        assert_eq!(wendland.rbf(0.), 1.);
+        assert_eq!(wendland.rbf(1.5 * 1.5), 0.);
+        let gaussian = RbfInterpolation::gaussian(2., 0.5);
+        assert!((gaussian.rbf(0.25) - (-0.5f64).exp()).abs() < 1e-15);
+        // the weights decrease with the distance
+        for rbf in [wendland, gaussian] {
+            let mut weights = rbf.weights(&cfd_data, &[1.2, 1., 1.]).unwrap();
+            weights.sort_by(|(a, _), (b, _)| {
+                let d = |sample: &TemperatureVelocityField| sample.distance_2(&[1.2, 1., 1.]);
+                d(a).total_cmp(&d(b))
+            });
+            assert!(weights.windows(2).all(|w| w[0].1 >= w[1].1));
+            assert_eq!(weights[0].0.coordinates(), [1., 1., 1.]);
+        }
+        // no sample within the support radius, falling back to the nearest neighbor
+        let weights = wendland.weights(&cfd_data, &[10., 2., 2.]).unwrap();
+        assert_eq!(weights.len(), 1);
+        assert_eq!(weights[0].0.coordinates(), [4., 2., 2.]);
+    }
+}
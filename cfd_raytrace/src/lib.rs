@@ -1,9 +1,50 @@
 mod ray_tracing;
-pub use ray_tracing::{Opd, RayTracer};
+pub use ray_tracing::{
+    Opd, OpdBreakdown, OplOperator, OutOfDomain, OutOfDomainCount, Quadrature, RayTracer,
+    StepConvergence,
+};
+mod geometry;
+pub use geometry::{GeometryDeviation, RayGeometry};
+pub mod gmt;
+mod multi_source;
+pub use multi_source::{MultiSourceRayTracer, SourceOpd};
+pub mod opd;
+pub use opd::{OpdFile, OpdMetadata};
+mod export;
+pub use export::ExportFormat;
 mod cfd;
-pub use cfd::{FromCompressedCsv, Shepard, TemperatureVelocityField};
+pub use cfd::{
+    CompressedCsvReader, FromCompressedCsv, LoadMode, LoadOptions, Shepard,
+    TemperatureVelocityField,
+};
+mod schema;
+pub use schema::{Column, CsvSchema, Quantity, Unit};
+mod transform;
+pub use transform::RigidTransform;
+pub mod storage;
+#[cfg(feature = "s3")]
+pub use storage::S3Storage;
+pub use storage::{LocalStorage, MemoryStorage, Storage};
+pub mod binary;
+pub mod config;
+pub use binary::{FromBinary, Precision, ToBinary};
+pub use config::RunConfig;
+mod interpolation;
+pub use interpolation::{
+    AdaptiveInterpolation, Interpolator, KNearestInterpolation, NearestNeighbor, RadiusStatistics,
+    RbfInterpolation, RbfKernel, ShepardInterpolation,
+};
+mod grid;
+pub use grid::{GridOptions, GridSampling, VoxelGrid};
+mod delaunay;
+pub use delaunay::DelaunayInterpolation;
+mod refraction;
+pub use refraction::{Ciddor, Edlen, GladstoneDale, RefractiveIndexModel};
+#[cfg(test)]
+mod testing;
 
 #[derive(thiserror::Error, Debug)]
+#[non_exhaustive]
 pub enum Error {
     #[error("failed to open npz data file")]
     NPZ,
@@ -14,8 +55,47 @@ pub enum Error {
     #[cfg(feature = "s3")]
     #[error("failed to get S3 object")]
     S3(#[from] s3::error::S3Error),
+    #[error("storage error: {0}")]
+    Storage(String),
     #[error("failed to parse UTF8")]
     UTF8(#[from] std::str::Utf8Error),
+    #[error("sample {point:?} of ray #{ray} along leg #{leg} is outside the CFD domain")]
+    OutOfDomain {
+        ray: usize,
+        leg: usize,
+        point: [f64; 3],
+    },
+    #[error("missing column {column:?} in CFD csv file, available columns: {available:?}")]
+    MissingColumn {
+        column: String,
+        available: Vec<String>,
+    },
+    #[error("failed to parse value {value:?} of column {column:?}")]
+    Value { column: String, value: String },
+    #[error("invalid CFD csv schema: {0}")]
+    Schema(String),
+    #[error("invalid run configuration: {0}")]
+    Config(String),
+    #[error("failed to encode or decode OPD")]
+    Bincode(#[from] bincode::Error),
+    #[error("failed to build the ray tracing thread pool")]
+    ThreadPool(#[from] rayon::ThreadPoolBuildError),
+    #[error("no ray left within the exit pupil")]
+    EmptyPupil,
+    #[error("invalid ray geometry: {0}")]
+    Geometry(String),
+    #[error("invalid OPD file: {0}")]
+    OpdFile(String),
+    #[error("invalid voxel grid: {0}")]
+    Grid(String),
+    #[error("failed to tetrahedralize the CFD samples: {0}")]
+    Delaunay(String),
+    #[error("invalid CFD binary data: {0}")]
+    Binary(String),
+    #[error("expected at least {expected} CFD samples, found {found}")]
+    OperatorSize { expected: usize, found: usize },
+    #[error("the {0} interpolation does not interpolate from the CFD samples")]
+    OperatorInterpolator(String),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
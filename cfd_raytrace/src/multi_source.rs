@@ -0,0 +1,168 @@
+use super::{
+    gmt::Gmt, gmt::Source, Opd, OpdFile, OpdMetadata, RayTracer, Result, TemperatureVelocityField,
+};
+use rstar::RTree;
+use serde::{Deserialize, Serialize};
+
+/// OPD of a source
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SourceOpd {
+    pub source: Source,
+    pub opd: Opd,
+    /// ray tracing metadata with the source set
+    pub metadata: OpdMetadata,
+}
+impl From<SourceOpd> for OpdFile {
+    fn from(source_opd: SourceOpd) -> Self {
+        OpdFile::new(source_opd.opd, source_opd.metadata)
+    }
+}
+
+/// Ray tracer for several sources
+///
+/// Each source has its own [RayTracer] and all the sources are ray traced
+/// through the same CFD data
+pub struct MultiSourceRayTracer {
+    ray_tracers: Vec<(Source, RayTracer)>,
+}
+impl MultiSourceRayTracer {
+    /// Creates a multi-source ray tracer from the ray tracer of each source
+    pub fn new(ray_tracers: impl IntoIterator<Item = (Source, RayTracer)>) -> Self {
+        Self {
+            ray_tracers: ray_tracers.into_iter().collect(),
+        }
+    }
+    /// Creates a multi-source ray tracer for the GMT `sources` with a `n_px`x`n_px` exit pupil sampling
+    pub fn gmt(gmt: &Gmt, n_px: usize, sources: &[Source]) -> Result<Self> {
+        sources
+            .iter()
+            .map(|&source| Ok((source, gmt.rays(n_px, source)?.try_into()?)))
+            .collect::<Result<Vec<_>>>()
+            .map(Self::new)
+    }
+    /// Applies the ray tracer settings `f` to the ray tracer of each source
+    ///
+    /// ```no_run
+    /// # use cfd_raytrace::{gmt::{Gmt, Source}, MultiSourceRayTracer};
+    /// let sources = [Source::on_axis(), Source::new(0.1f64.to_radians(), 0.).range(90e3)];
+    /// let ray_tracer = MultiSourceRayTracer::gmt(&Gmt::default(), 512, &sources)?
+    ///     .configure(|ray_tracer| ray_tracer.wavelength(0.589).shepard_radius(0.25));
+    /// # Ok::<(), cfd_raytrace::Error>(())
+    /// ```
+    pub fn configure<F: Fn(RayTracer) -> RayTracer>(mut self, f: F) -> Self {
+        self.ray_tracers = self
+            .ray_tracers
+            .into_iter()
+            .map(|(source, ray_tracer)| (source, f(ray_tracer)))
+            .collect();
+        self
+    }
+    /// Returns the sources
+    pub fn sources(&self) -> impl Iterator<Item = &Source> {
+        self.ray_tracers.iter().map(|(source, _)| source)
+    }
+    /// Returns the sources and their ray tracers
+    pub fn ray_tracers(&self) -> impl Iterator<Item = &(Source, RayTracer)> {
+        self.ray_tracers.iter()
+    }
+    /// Ray traces each source through the GMT, returning the OPD of each source
+    ///
+    /// The sources are ray traced one after the other, the rays of each source being traced in parallel
+    pub fn ray_trace(&self, cfd_data: &RTree<TemperatureVelocityField>) -> Result<Vec<SourceOpd>> {
+        self.ray_tracers
+            .iter()
+            .map(|(source, ray_tracer)| {
+                Ok(SourceOpd {
+                    source: *source,
+                    opd: ray_tracer.ray_trace(cfd_data)?,
+                    metadata: ray_tracer.metadata().source(*source),
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use nalgebra::Vector3;
+
+    const N_PX: usize = 12;
+
+    fn sources() -> [Source; 3] {
+        [
+            Source::on_axis(),
+            Source::new(0.1f64.to_radians(), 30f64.to_radians()),
+            Source::new(0.1f64.to_radians(), 30f64.to_radians()).range(90e3),
+        ]
+    }
+
+    fn rays(
+        ray_tracer: &RayTracer,
+        k: usize,
+    ) -> impl Iterator<Item = (Vector3<f64>, Vector3<f64>)> + '_ {
+        (0..ray_tracer.n_sample()).map(move |i| {
+            let row = |matrix: &nalgebra::DMatrix<f64>| {
+                Vector3::new(matrix[(i, 0)], matrix[(i, 1)], matrix[(i, 2)])
+            };
+            (row(&ray_tracer.xyz[k]), row(&ray_tracer.klm[k]))
+        })
+    }
+
+    #[test]
+    fn geometries() {
+        let ray_tracer = MultiSourceRayTracer::gmt(&Gmt::default(), N_PX, &sources()).unwrap();
+        assert!(ray_tracer.sources().eq(sources().iter()));
+        let ray_tracers: Vec<_> = ray_tracer.ray_tracers().map(|(_, r)| r).collect();
+        for (source, ray_tracer) in sources().iter().zip(&ray_tracers) {
+            let (sin_z, cos_z) = source.zenith.sin_cos();
+            let (sin_a, cos_a) = source.azimuth.sin_cos();
+            let u = Vector3::new(sin_z * cos_a, sin_z * sin_a, cos_z);
+            for (p, d) in rays(ray_tracer, 0) {
+                match source.range {
+                    // parallel rays from the source direction
+                    None => assert!((d + u).norm() < 1e-12),
+                    // rays diverging from the source
+                    Some(range) => {
+                        let to_source = u * range - p;
+                        assert!(to_source.normalize().dot(&d) < -1. + 1e-12);
+                    }
+                }
+            }
+        }
+        // the geometries of the sources differ from each other
+        for (a, b) in [(0, 1), (1, 2), (0, 2)] {
+            let deviation = rays(ray_tracers[a], 0)
+                .zip(rays(ray_tracers[b], 0))
+                .map(|((_, d_a), (_, d_b))| (d_a - d_b).norm())
+                .fold(0., f64::max);
+            assert!(deviation > 1e-6, "sources #{a} and #{b}: {deviation}");
+        }
+    }
+
+    #[test]
+    fn ray_trace() {
+        let cfd_data = testing::dome();
+        let ray_tracer = MultiSourceRayTracer::gmt(&Gmt::default(), N_PX, &sources())
+            .unwrap()
+            .configure(|ray_tracer| ray_tracer.shepard_radius(3.).wavelength(0.7));
+        let opds = ray_tracer.ray_trace(&cfd_data).unwrap();
+        assert_eq!(opds.len(), sources().len());
+        for ((source_opd, source), (_, single)) in
+            opds.iter().zip(sources()).zip(ray_tracer.ray_tracers())
+        {
+            assert_eq!(source_opd.source, source);
+            assert_eq!(source_opd.metadata.source, Some(source));
+            assert_eq!(source_opd.metadata.n_px, N_PX);
+            assert_eq!(source_opd.metadata.wavelength, 0.7);
+            assert_eq!(
+                source_opd.opd.values,
+                single.ray_trace(&cfd_data).unwrap().values
+            );
+        }
+        assert_ne!(opds[0].opd.values, opds[1].opd.values);
+        let opd_file = OpdFile::from(opds.into_iter().nth(2).unwrap());
+        assert_eq!(opd_file.metadata.unwrap().source, Some(sources()[2]));
+    }
+}
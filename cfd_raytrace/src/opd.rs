@@ -0,0 +1,220 @@
+//! Self-describing OPD files
+//!
+//! An OPD file starts with the [MAGIC] bytes followed by the file format version
+//! as a little endian `u32` and by the bincode encoded [Opd] and [OpdMetadata].
+//! Files without the magic bytes are read as the bare bincode [Opd] files
+//! written by the previous versions of the crate.
+
+use super::{gmt::Source, storage, Error, Opd, OutOfDomain, Quadrature, Result, Storage};
+use serde::{Deserialize, Serialize};
+
+/// OPD file magic bytes
+pub const MAGIC: &[u8; 8] = b"CFDRTOPD";
+/// OPD file format version
+pub const VERSION: u32 = 1;
+
+/// Ray tracing metadata
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OpdMetadata {
+    /// exit pupil sampling, the mask is a `n_px`x`n_px` row major grid
+    pub n_px: usize,
+    /// wavelength [micron]
+    pub wavelength: f64,
+    /// CFD case, e.g. `zen30az000_OS7`
+    pub cfd_case: Option<String>,
+    /// CFD csv.gz file key
+    pub cfd_file: Option<String>,
+    /// CFD snapshot time [s]
+    pub time: Option<f64>,
+    /// CFD data interpolation method
+    pub interpolation: String,
+    /// air refractive index model
+    pub refractive_index: String,
+    /// ray tracing step [m]
+    pub step: f64,
+    /// quadrature along each ray tracing leg
+    pub quadrature: Quadrature,
+    /// policy for the ray samples outside the CFD domain
+    pub out_of_domain: OutOfDomain,
+    /// version of the crate that produced the OPD
+    pub version: String,
+    /// source the rays are coming from, `None` if unknown
+    pub source: Option<Source>,
+}
+
+impl OpdMetadata {
+    /// Sets the CFD csv.gz file `key`
+    ///
+    /// The CFD case is the path component following `CASES`
+    /// and the snapshot time is the number ending the file name,
+    /// e.g. `CASES/zen30az000_OS7/optvol/optvol_optvol_3.000000e+02.csv.gz`
+    pub fn cfd_file<S: Into<String>>(mut self, key: S) -> Self {
+        let key = key.into();
+        let mut components = key.split('/');
+        self.cfd_case = components
+            .by_ref()
+            .find(|c| *c == "CASES")
+            .and(components.next())
+            .map(|c| c.to_string());
+        let name = key.rsplit_once('/').map_or(key.as_str(), |(_, name)| name);
+        self.time = name
+            .strip_suffix(".csv.gz")
+            .and_then(|stem| stem.rsplit_once('_'))
+            .and_then(|(_, time)| time.parse().ok());
+        self.cfd_file = Some(key);
+        self
+    }
+    /// Sets the `source` the rays are coming from
+    pub fn source(mut self, source: Source) -> Self {
+        self.source = Some(source);
+        self
+    }
+}
+
+/// Versioned OPD file
+#[derive(Debug)]
+pub struct OpdFile {
+    version: u32,
+    pub opd: Opd,
+    /// ray tracing metadata, `None` for the bare bincode [Opd] files
+    pub metadata: Option<OpdMetadata>,
+}
+impl OpdFile {
+    /// Creates a new OPD file
+    pub fn new(opd: Opd, metadata: OpdMetadata) -> Self {
+        Self {
+            version: VERSION,
+            opd,
+            metadata: Some(metadata),
+        }
+    }
+    /// Returns the file format version, 0 for the bare bincode [Opd] files
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+    /// Returns the exit pupil sampling
+    ///
+    /// For the bare bincode [Opd] files, the exit pupil is assumed to be square
+    pub fn n_px(&self) -> usize {
+        self.metadata.as_ref().map_or_else(
+            || (self.opd.mask.len() as f64).sqrt().round() as usize,
+            |metadata| metadata.n_px,
+        )
+    }
+    /// Encodes the OPD file with the current file format version
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&VERSION.to_le_bytes());
+        bincode::serialize_into(&mut data, &(&self.opd, &self.metadata))?;
+        Ok(data)
+    }
+    /// Decodes an OPD file or a bare bincode [Opd] file
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        let Some(data) = data.strip_prefix(MAGIC) else {
+            return Ok(Self {
+                version: 0,
+                opd: bincode::deserialize(data)?,
+                metadata: None,
+            });
+        };
+        let (version, data) = data
+            .split_first_chunk::<4>()
+            .ok_or_else(|| Error::OpdFile("missing file format version".to_string()))?;
+        match u32::from_le_bytes(*version) {
+            VERSION => {
+                let (opd, metadata) = bincode::deserialize(data)?;
+                Ok(Self {
+                    version: VERSION,
+                    opd,
+                    metadata,
+                })
+            }
+            version => Err(Error::OpdFile(format!(
+                "unsupported file format version {version}, expected {VERSION}"
+            ))),
+        }
+    }
+    /// Loads an OPD file from a storage `url`
+    pub fn load<U: AsRef<str>>(url: U) -> Result<Self> {
+        let (storage, key) = storage::from_url(url.as_ref())?;
+        Self::load_in(storage.as_ref(), &key)
+    }
+    /// Loads the OPD file `key` from `storage`
+    pub fn load_in(storage: &dyn Storage, key: &str) -> Result<Self> {
+        Self::from_bytes(&storage.get(key)?)
+    }
+    /// Saves the OPD file to a storage `url`
+    pub fn save<U: AsRef<str>>(&self, url: U) -> Result<()> {
+        let (storage, key) = storage::from_url(url.as_ref())?;
+        self.save_in(storage.as_ref(), &key)
+    }
+    /// Saves the OPD file as `key` into `storage`
+    pub fn save_in(&self, storage: &dyn Storage, key: &str) -> Result<()> {
+        storage.put(key, &self.to_bytes()?)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::RayTracer;
+
+    pub(crate) fn opd_file() -> OpdFile {
+        let mask = vec![false, true, true, true, true, false, true, true, false];
+        let opd = Opd {
+            mean: 1e-3,
+            values: vec![1e-9, -2e-9, 3e-9, -4e-9, 5e-9, -3e-9],
+            mask,
+        };
+        let metadata = RayTracer::default()
+            .metadata()
+            .cfd_file("CASES/zen30az000_OS7/optvol/optvol_optvol_3.000000e+02.csv.gz")
+            .source(Source::new(1e-3, 0.5));
+        OpdFile::new(
+            opd,
+            OpdMetadata {
+                n_px: 3,
+                ..metadata
+            },
+        )
+    }
+
+    #[test]
+    fn round_trip() {
+        let opd_file = opd_file();
+        let data = opd_file.to_bytes().unwrap();
+        assert_eq!(&data[..8], MAGIC);
+        assert_eq!(data[8..12], VERSION.to_le_bytes());
+        let decoded = OpdFile::from_bytes(&data).unwrap();
+        assert_eq!(decoded.version(), VERSION);
+        assert_eq!(decoded.n_px(), 3);
+        assert_eq!(decoded.opd.values, opd_file.opd.values);
+        assert_eq!(decoded.opd.mask, opd_file.opd.mask);
+        assert_eq!(decoded.metadata, opd_file.metadata);
+    }
+
+    #[test]
+    fn bare_bincode() {
+        let opd = opd_file().opd;
+        let decoded = OpdFile::from_bytes(&bincode::serialize(&opd).unwrap()).unwrap();
+        assert_eq!(decoded.version(), 0);
+        assert_eq!(decoded.n_px(), 3);
+        assert_eq!(decoded.opd.mean, opd.mean);
+        assert_eq!(decoded.opd.values, opd.values);
+        assert!(decoded.metadata.is_none());
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let mut data = opd_file().to_bytes().unwrap();
+        data[8..12].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert!(matches!(OpdFile::from_bytes(&data), Err(Error::OpdFile(_))));
+    }
+
+    #[test]
+    fn cfd_file() {
+        let metadata = opd_file().metadata.unwrap();
+        assert_eq!(metadata.cfd_case.as_deref(), Some("zen30az000_OS7"));
+        assert_eq!(metadata.time, Some(300.));
+    }
+}
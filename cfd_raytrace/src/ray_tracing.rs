@@ -1,12 +1,21 @@
-#[cfg(feature = "shepard")]
-use super::cfd::Shepard;
-use super::{Result, TemperatureVelocityField};
-use nalgebra::DMatrix;
+use super::{
+    storage, Error, GladstoneDale, Interpolator, OpdMetadata, RadiusStatistics, RayGeometry,
+    RefractiveIndexModel, Result, ShepardInterpolation, Storage, TemperatureVelocityField,
+};
+use nalgebra::{DMatrix, DVector};
+use rayon::prelude::*;
 use rstar::RTree;
 use serde::{Deserialize, Serialize};
 #[cfg(feature = "linya")]
-use std::fmt::Write;
-use std::path::Path;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Mutex,
+};
+use std::{
+    borrow::Cow,
+    fmt,
+    io::{Cursor, Read, Seek},
+};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Opd {
@@ -14,14 +23,174 @@ pub struct Opd {
     pub values: Vec<f64>,
     pub mask: Vec<bool>,
 }
+impl Opd {
+    /// Creates the OPD from the optical path lengths `opl` of the rays within the exit pupil `mask`
+    ///
+    /// The OPD is the optical path length minus its mean.
+    /// Returns an error if there is no ray, e.g. if all the rays have been masked out
+    /// by the [OutOfDomain::Mask] policy
+    pub(crate) fn from_opl(opl: Vec<f64>, mask: Vec<bool>) -> Result<Self> {
+        if opl.is_empty() {
+            return Err(Error::EmptyPupil);
+        }
+        let mean_opl = opl.iter().cloned().sum::<f64>() / opl.len() as f64;
+        let zeroed_opl = opl.into_iter().map(|x| x - mean_opl);
+        Ok(Self {
+            mean: mean_opl,
+            values: zeroed_opl.collect(),
+            mask,
+        })
+    }
+}
+
+/// OPD and OPD of each ray tracing leg
+///
+/// The OPD of each leg is computed from the optical path length along the leg only,
+/// with the same exit pupil mask as the OPD and with its own mean removed,
+/// so the OPD is the sum of the OPDs of the legs
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OpdBreakdown {
+    pub total: Opd,
+    pub legs: Vec<Opd>,
+}
+
+/// Policy for the ray samples outside the CFD domain
+///
+/// A sample is outside the CFD domain if the interpolator finds no CFD sample to interpolate from,
+/// e.g. outside the convex hull of the [DelaunayInterpolation](crate::DelaunayInterpolation)
+/// or outside the [VoxelGrid](crate::VoxelGrid);
+/// the Shepard, adaptive and RBF interpolations fall back to the nearest CFD sample instead
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutOfDomain {
+    /// Uses the given ambient refraction index
+    Ambient(f64),
+    /// Removes the ray from the exit pupil mask
+    Mask,
+    /// Uses the refraction index of the nearest CFD sample
+    #[default]
+    Clamp,
+    /// Aborts the ray tracing
+    Error,
+}
+/// Quadrature of the refraction index along each ray tracing leg
+///
+/// The number of intervals along a leg is given by the ray tracing step
+/// and by the length of the longest ray of the leg
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Quadrature {
+    /// Sum of the samples at the end of each interval
+    ///
+    /// The leg is divided into one interval less than the number of samples,
+    /// so the last sample lies one interval past the end of the leg.
+    /// The ray is stepped incrementally from sample to sample and the optical path length
+    /// is accumulated over all the legs at once, as in the previous versions of the crate.
+    /// The interpolation weights are normalized before summing the refraction index
+    /// whereas the previous versions normalized the sum,
+    /// so the OPD matches the previous versions up to the rounding errors only
+    #[default]
+    Riemann,
+    /// Trapezoidal rule including both ends of the leg
+    Trapezoidal,
+    /// Simpson's rule including both ends of the leg, over an even number of intervals
+    Simpson,
+}
+impl Quadrature {
+    /// Returns the number of intervals along a leg of the given `length` for the ray tracing `step`
+    fn n_interval(&self, length: f64, step: f64) -> usize {
+        let n = ((length / step).ceil() as usize).max(1);
+        match self {
+            Self::Simpson => n + n % 2,
+            _ => n,
+        }
+    }
+    /// Returns the interval length for a leg of the given `length` divided into `n` intervals
+    fn interval(&self, length: f64, n: usize) -> f64 {
+        match self {
+            Self::Riemann => length / n.saturating_sub(1).max(1) as f64,
+            _ => length / n as f64,
+        }
+    }
+    /// Returns the abscissa, in units of the interval length, and the weight of each quadrature node
+    /// for a leg divided into `n` intervals
+    fn nodes(&self, n: usize) -> impl Iterator<Item = (f64, f64)> + '_ {
+        let range = match self {
+            Self::Riemann => 1..=n,
+            _ => 0..=n,
+        };
+        range.map(move |j| {
+            let w = match self {
+                Self::Riemann => 1.,
+                Self::Trapezoidal if j == 0 || j == n => 0.5,
+                Self::Trapezoidal => 1.,
+                Self::Simpson if j == 0 || j == n => 1. / 3.,
+                Self::Simpson if j % 2 == 1 => 4. / 3.,
+                Self::Simpson => 2. / 3.,
+            };
+            (j as f64, w)
+        })
+    }
+}
+
+/// OPD change as the ray tracing step is halved, see [RayTracer::step_convergence]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct StepConvergence {
+    /// ray tracing step [m]
+    pub step: f64,
+    /// OPD RMS [m]
+    pub rms: f64,
+    /// RMS of the difference with the OPD of twice the ray tracing step [m],
+    /// `None` for the first step
+    pub change: Option<f64>,
+}
+impl fmt::Display for StepConvergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "step: {:.4}m, OPD RMS: {:.3e}m", self.step, self.rms)?;
+        if let Some(change) = self.change {
+            write!(f, ", change: {:.3e}m", change)?;
+        }
+        Ok(())
+    }
+}
+
+/// Number of ray samples outside the CFD domain for each ray tracing leg
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct OutOfDomainCount(pub Vec<usize>);
+impl OutOfDomainCount {
+    /// Returns the total number of ray samples outside the CFD domain
+    pub fn total(&self) -> usize {
+        self.0.iter().sum()
+    }
+}
+impl fmt::Display for OutOfDomainCount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let counts: Vec<_> = self
+            .0
+            .iter()
+            .enumerate()
+            .map(|(k, n)| format!("leg #{}: {}", k + 1, n))
+            .collect();
+        write!(f, "{}", counts.join(", "))
+    }
+}
+
+/// Optical path lengths of a ray per wavelength, the total followed by each leg,
+/// and number of ray samples outside the CFD domain per leg
+type RayOpl = (Option<Vec<f64>>, Vec<usize>);
 
 /// Ray tracing parameters
 pub struct RayTracer {
     mask: Vec<bool>,
     pub xyz: Vec<DMatrix<f64>>,
     pub klm: Vec<DMatrix<f64>>,
-    shepard_radius2: f64,
+    interpolator: Box<dyn Interpolator>,
+    refraction: Box<dyn RefractiveIndexModel>,
+    wavelength: f64,
     step_length: f64,
+    quadrature: Quadrature,
+    n_thread: Option<usize>,
+    out_of_domain: OutOfDomain,
 }
 impl Default for RayTracer {
     fn default() -> Self {
@@ -29,188 +198,1055 @@ impl Default for RayTracer {
             mask: Default::default(),
             xyz: Default::default(),
             klm: Default::default(),
-            shepard_radius2: 0.25,
+            interpolator: Box::new(ShepardInterpolation::new(0.5)),
+            refraction: Box::new(GladstoneDale::default()),
+            wavelength: 0.5,
             step_length: 0.25,
+            quadrature: Default::default(),
+            n_thread: None,
+            out_of_domain: Default::default(),
         }
     }
 }
+impl TryFrom<RayGeometry> for RayTracer {
+    type Error = Error;
+    /// Creates a ray tracer with the rays within the exit pupil mask of the ray geometry
+    ///
+    /// The ray geometry is validated with [RayGeometry::validate]
+    fn try_from(geometry: RayGeometry) -> Result<Self> {
+        geometry.validate()?;
+        let RayGeometry { mask, xyz, klm } = geometry;
+        #[allow(clippy::filter_map_bool_then, clippy::unnecessary_lazy_evaluations)]
+        let masked = |matrices: Vec<DMatrix<f64>>| -> Vec<DMatrix<f64>> {
+            matrices
+                .into_iter()
+                .map(|mat| {
+                    let rows: Vec<_> = mat
+                        .row_iter()
+                        .zip(&mask)
+                        .filter_map(|(row, &mask)| mask.then(|| row))
+                        .collect();
+                    DMatrix::from_rows(&rows)
+                })
+                .collect()
+        };
+        Ok(Self {
+            xyz: masked(xyz),
+            klm: masked(klm),
+            mask,
+            ..Default::default()
+        })
+    }
+}
 impl RayTracer {
-    #[cfg(not(feature = "s3"))]
-    /// Loads the parameters from a Numpy npz data file
-    pub fn from_npz<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let mut archive = npyz::npz::NpzArchive::open(path)?;
-        let mut gs_onaxis_params: RayTracer = Default::default();
-        if let Ok(Some(data)) = archive.by_name("m") {
-            let val: Vec<u8> = data.into_vec()?;
-            gs_onaxis_params.mask = val.into_iter().map(|x| x != 0).collect();
-        }
-        for k in 0..4 {
-            if let Ok(Some(data)) = archive.by_name(&format!("xyz{k}")) {
-                let mat = DMatrix::from_row_slice(
-                    data.len() as usize / 3,
-                    3_,
-                    data.into_vec()?.as_slice(),
-                );
-                let rows: Vec<_> = mat
-                    .row_iter()
-                    .zip(&gs_onaxis_params.mask)
-                    .filter_map(|(row, &mask)| mask.then(|| row))
-                    .collect();
-                gs_onaxis_params.xyz.push(DMatrix::from_rows(&rows));
-            }
-            if let Ok(Some(data)) = archive.by_name(&format!("klm{k}")) {
-                let mat = DMatrix::from_row_slice(
-                    data.len() as usize / 3,
-                    3_,
-                    data.into_vec()?.as_slice(),
-                );
-                let rows: Vec<_> = mat
-                    .row_iter()
-                    .zip(&gs_onaxis_params.mask)
-                    .filter_map(|(row, &mask)| mask.then(|| row))
-                    .collect();
-                gs_onaxis_params.klm.push(DMatrix::from_rows(&rows));
-            }
-        }
-        Ok(gs_onaxis_params)
+    /// Loads the parameters from a Numpy npz data file at a storage `url`
+    pub fn from_npz<U: AsRef<str>>(url: U) -> Result<Self> {
+        let (storage, key) = storage::from_url(url.as_ref())?;
+        Self::from_npz_in(storage.as_ref(), &key)
     }
-    #[cfg(feature = "s3")]
-    /// Loads the parameters from a Numpy npz data file
-    pub async fn from_npz<P>(path: P) -> Result<Self>
-    where
-        P: AsRef<Path> + std::convert::AsRef<str>,
-    {
-        use s3::bucket::Bucket;
-        use s3::creds::Credentials;
-        let bucket_name = "cfd.archive";
-        let region = "us-east-2".parse()?;
-        let credentials = Credentials::default().map_err(|e| s3::error::S3Error::Credentials(e))?;
-        let bucket = Bucket::new(bucket_name, region, credentials)?;
-        let (data, _) = bucket.get_object(path).await?;
-        let stream = std::io::Cursor::new(data);
-
-        let mut archive = npyz::npz::NpzArchive::new(stream)?;
-        let mut gs_onaxis_params: RayTracer = Default::default();
-        if let Ok(Some(data)) = archive.by_name("m") {
-            let val: Vec<u8> = data.into_vec()?;
-            gs_onaxis_params.mask = val.into_iter().map(|x| x != 0).collect();
-        }
-        for k in 0..4 {
-            if let Ok(Some(data)) = archive.by_name(&format!("xyz{k}")) {
-                let mat = DMatrix::from_row_slice(
-                    data.len() as usize / 3,
-                    3_,
-                    data.into_vec()?.as_slice(),
-                );
-                let rows: Vec<_> = mat
-                    .row_iter()
-                    .zip(&gs_onaxis_params.mask)
-                    .filter_map(|(row, &mask)| mask.then(|| row))
-                    .collect();
-                gs_onaxis_params.xyz.push(DMatrix::from_rows(&rows));
-            }
-            if let Ok(Some(data)) = archive.by_name(&format!("klm{k}")) {
-                let mat = DMatrix::from_row_slice(
-                    data.len() as usize / 3,
-                    3_,
-                    data.into_vec()?.as_slice(),
-                );
-                let rows: Vec<_> = mat
-                    .row_iter()
-                    .zip(&gs_onaxis_params.mask)
-                    .filter_map(|(row, &mask)| mask.then(|| row))
-                    .collect();
-                gs_onaxis_params.klm.push(DMatrix::from_rows(&rows));
-            }
-        }
-        //gs_onaxis_params.xyz[0].iter_mut().for_each(|x| *x += 10.);
-        Ok(gs_onaxis_params)
+    /// Loads the parameters from the Numpy npz data file `key` in `storage`
+    pub fn from_npz_in(storage: &dyn Storage, key: &str) -> Result<Self> {
+        Self::from_npz_archive(npyz::npz::NpzArchive::new(Cursor::new(storage.get(key)?))?)
+    }
+    /// Loads the parameters from a Numpy npz data file at a storage `url`
+    pub async fn from_npz_async<U: AsRef<str>>(url: U) -> Result<Self> {
+        let (storage, key) = storage::from_url(url.as_ref())?;
+        Self::from_npz_in_async(storage.as_ref(), &key).await
+    }
+    /// Loads the parameters from the Numpy npz data file `key` in `storage`
+    pub async fn from_npz_in_async(storage: &dyn Storage, key: &str) -> Result<Self> {
+        let data = storage.get_async(key).await?;
+        Self::from_npz_archive(npyz::npz::NpzArchive::new(Cursor::new(data))?)
     }
-    pub fn shepard_radius(mut self, radius: f64) -> Self {
-        self.shepard_radius2 = radius * radius;
+    fn from_npz_archive<R: Read + Seek>(archive: npyz::npz::NpzArchive<R>) -> Result<Self> {
+        RayGeometry::from_npz_archive(archive)?.try_into()
+    }
+    /// Sets the CFD data interpolation method
+    pub fn interpolator<I: Interpolator + 'static>(mut self, interpolator: I) -> Self {
+        self.interpolator = Box::new(interpolator);
+        self
+    }
+    /// Returns the statistics of the interpolation radius of the last ray tracing,
+    /// see [Interpolator::radius_statistics]
+    pub fn radius_statistics(&self) -> Option<RadiusStatistics> {
+        self.interpolator.radius_statistics()
+    }
+    /// Sets the CFD data interpolation to Shepard interpolation within `radius`
+    pub fn shepard_radius(self, radius: f64) -> Self {
+        self.interpolator(ShepardInterpolation::new(radius))
+    }
+    /// Sets the model of the air index of refraction
+    pub fn refractive_index_model<M: RefractiveIndexModel + 'static>(mut self, model: M) -> Self {
+        self.refraction = Box::new(model);
+        self
+    }
+    /// Sets the wavelength in micron
+    pub fn wavelength(mut self, wavelength: f64) -> Self {
+        self.wavelength = wavelength;
         self
     }
     pub fn ray_tracing_step(mut self, step: f64) -> Self {
         self.step_length = step;
         self
     }
+    /// Sets the quadrature of the refraction index along each ray tracing leg
+    pub fn quadrature(mut self, quadrature: Quadrature) -> Self {
+        self.quadrature = quadrature;
+        self
+    }
+    /// Sets the number of ray tracing threads
+    ///
+    /// By default, all the available cores are used
+    pub fn n_thread(mut self, n_thread: usize) -> Self {
+        self.n_thread = Some(n_thread);
+        self
+    }
+    /// Sets the policy for the ray samples outside the CFD domain
+    pub fn out_of_domain(mut self, policy: OutOfDomain) -> Self {
+        self.out_of_domain = policy;
+        self
+    }
+    /// Returns the exit pupil mask
+    pub fn mask(&self) -> &[bool] {
+        &self.mask
+    }
+    /// Returns the ray tracing metadata
+    pub fn metadata(&self) -> OpdMetadata {
+        OpdMetadata {
+            n_px: (self.mask.len() as f64).sqrt().round() as usize,
+            wavelength: self.wavelength,
+            cfd_case: None,
+            cfd_file: None,
+            time: None,
+            interpolation: self.interpolator.description(),
+            refractive_index: self.refraction.description(),
+            step: self.step_length,
+            quadrature: self.quadrature,
+            out_of_domain: self.out_of_domain,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            source: None,
+        }
+    }
+    /// Returns the number of ray tracing legs, one less than the number of surfaces
+    pub fn n_leg(&self) -> usize {
+        self.xyz.len().saturating_sub(1)
+    }
     /// Returns the number of OPD sample within the exit pupil
     pub fn n_sample(&self) -> usize {
         self.mask.iter().filter(|x| **x).map(|_| 1).sum()
     }
     /// Ray traces through the GMT , returning the OPD
     ///
-    /// Ray tracing step is set to 0.125m.
-    /// CFD data is interpolated using Shepard interpolation within a 1m<sup><\sup> radius sphere
-    pub fn ray_trace(&self, cfd_data: &RTree<TemperatureVelocityField>) -> Opd {
+    /// CFD data is interpolated with the ray tracer interpolation method,
+    /// by default Shepard interpolation within a 0.5m radius sphere
+    pub fn ray_trace(&self, cfd_data: &RTree<TemperatureVelocityField>) -> Result<Opd> {
+        self.ray_trace_with(cfd_data, self.interpolator.as_ref())
+            .map(|(opd, _)| opd)
+    }
+    /// Ray traces through the GMT with the given interpolation method
+    ///
+    /// Returns the OPD and the number of ray samples outside the CFD domain.
+    /// The rays are traced in parallel, each ray being integrated independently of the others,
+    /// so the OPD does not depend on the number of threads
+    pub fn ray_trace_with(
+        &self,
+        cfd_data: &RTree<TemperatureVelocityField>,
+        interpolator: &dyn Interpolator,
+    ) -> Result<(Opd, OutOfDomainCount)> {
+        let (mut opds, out_of_domain) = self.polychromatic_ray_trace(
+            cfd_data,
+            interpolator,
+            &[self.wavelength],
+            self.step_length,
+            false,
+        )?;
+        Ok((opds.remove(0).total, out_of_domain))
+    }
+    /// Ray traces through the GMT, returning the OPD and the OPD of each ray tracing leg
+    ///
+    /// For the GMT, the legs are from the source to M1, from M1 to M2 and from M2 to the exit pupil
+    pub fn ray_trace_legs(
+        &self,
+        cfd_data: &RTree<TemperatureVelocityField>,
+    ) -> Result<OpdBreakdown> {
+        let (mut opds, _) = self.polychromatic_ray_trace(
+            cfd_data,
+            self.interpolator.as_ref(),
+            &[self.wavelength],
+            self.step_length,
+            true,
+        )?;
+        Ok(opds.remove(0))
+    }
+    /// Ray traces through the GMT at several wavelengths, returning one OPD per wavelength
+    ///
+    /// The wavelengths are given in micron.
+    /// The rays are traced once, the CFD data being interpolated
+    /// at the same locations and with the same weights for all the wavelengths
+    pub fn ray_trace_wavelengths(
+        &self,
+        cfd_data: &RTree<TemperatureVelocityField>,
+        wavelengths: &[f64],
+    ) -> Result<Vec<Opd>> {
+        self.polychromatic_ray_trace(
+            cfd_data,
+            self.interpolator.as_ref(),
+            wavelengths,
+            self.step_length,
+            false,
+        )
+        .map(|(opds, _)| opds.into_iter().map(|opd| opd.total).collect())
+    }
+    /// Ray traces through the GMT with the ray tracing step halved `n_step`-1 times,
+    /// returning the OPD RMS and the OPD change for each ray tracing step
+    ///
+    /// The OPD change is computed over the rays within the exit pupil masks of both OPDs
+    pub fn step_convergence(
+        &self,
+        cfd_data: &RTree<TemperatureVelocityField>,
+        n_step: usize,
+    ) -> Result<Vec<StepConvergence>> {
+        let mut convergence = vec![];
+        let mut previous: Option<Opd> = None;
+        for k in 0..n_step {
+            let step = self.step_length / 2f64.powi(k as i32);
+            let (mut opds, _) = self.polychromatic_ray_trace(
+                cfd_data,
+                self.interpolator.as_ref(),
+                &[self.wavelength],
+                step,
+                false,
+            )?;
+            let opd = opds.remove(0).total;
+            convergence.push(StepConvergence {
+                step,
+                rms: rms(opd.values.iter().copied()),
+                change: previous
+                    .as_ref()
+                    .map(|previous| rms(masked_pairs(previous, &opd).map(|(a, b)| a - b))),
+            });
+            previous = Some(opd);
+        }
+        Ok(convergence)
+    }
+    /// Ray traces through the GMT at several wavelengths, returning one OPD per wavelength
+    ///
+    /// The OPD of each leg is computed only if `per_leg` is true
+    fn polychromatic_ray_trace(
+        &self,
+        cfd_data: &RTree<TemperatureVelocityField>,
+        interpolator: &dyn Interpolator,
+        wavelengths: &[f64],
+        step_length: f64,
+        per_leg: bool,
+    ) -> Result<(Vec<OpdBreakdown>, OutOfDomainCount)> {
+        let n_sample = self.n_sample();
+        // Ray tracing step size and number of steps per ray for each leg
+        let legs = self.legs(step_length);
+        interpolator.reset_radius_statistics();
+
+        #[cfg(feature = "linya")]
+        let progress = Mutex::new(linya::Progress::new());
         #[cfg(feature = "linya")]
-        let mut progress = linya::Progress::new();
+        let bar: linya::Bar = progress.lock().unwrap().bar(1000, "Ray tracing");
         #[cfg(feature = "linya")]
-        let bar: linya::Bar = progress.bar(1000, "Ray tracing");
+        let n_traced = AtomicUsize::new(0);
+
         // Optical path length
-        let mut opl = vec![0f64; self.n_sample()];
+        let trace = || -> Result<Vec<RayOpl>> {
+            (0..n_sample)
+                .into_par_iter()
+                .map(|i| {
+                    let opl = self.ray_opl(i, &legs, cfd_data, interpolator, wavelengths);
+                    #[cfg(feature = "linya")]
+                    {
+                        let n = n_traced.fetch_add(1, Ordering::Relaxed) + 1;
+                        if n % (n_sample / 1000).max(1) == 0 {
+                            progress
+                                .lock()
+                                .unwrap()
+                                .set_and_draw(&bar, 1000 * n / n_sample);
+                        }
+                    }
+                    opl
+                })
+                .collect()
+        };
+        let rays = self.install(trace)?;
+
+        let mut out_of_domain = OutOfDomainCount(vec![0; legs.len()]);
+        let mut mask = self.mask.clone();
+        let n_leg = legs.len();
+        let mut opls = vec![Vec::with_capacity(n_sample); wavelengths.len() * (n_leg + 1)];
+        for ((ray_opl, count), m) in rays.into_iter().zip(mask.iter_mut().filter(|m| **m)) {
+            out_of_domain
+                .0
+                .iter_mut()
+                .zip(count)
+                .for_each(|(c, n)| *c += n);
+            match ray_opl {
+                // total and per leg optical path lengths for each wavelength
+                Some(ray_opl) => opls
+                    .chunks_mut(n_leg + 1)
+                    .zip(ray_opl.chunks(n_leg + 1))
+                    .for_each(|(opl, ray_opl)| {
+                        let n = if per_leg { n_leg + 1 } else { 1 };
+                        opl.iter_mut()
+                            .zip(ray_opl)
+                            .take(n)
+                            .for_each(|(opl, &ray_opl)| opl.push(ray_opl));
+                    }),
+                None => *m = false,
+            }
+        }
+
+        let mut opls = opls.into_iter();
+        let opds = (0..wavelengths.len())
+            .map(|_| {
+                let total = Opd::from_opl(opls.next().unwrap_or_default(), mask.clone())?;
+                let legs = opls
+                    .by_ref()
+                    .take(n_leg)
+                    .filter(|_| per_leg)
+                    .map(|opl| Opd::from_opl(opl, mask.clone()))
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(OpdBreakdown { total, legs })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok((opds, out_of_domain))
+    }
+    /// Runs `op` in a thread pool of `n_thread` threads if the number of threads is set,
+    /// or in the global thread pool otherwise
+    fn install<T, F>(&self, op: F) -> Result<T>
+    where
+        T: Send,
+        F: FnOnce() -> Result<T> + Send,
+    {
+        match self.n_thread {
+            Some(n_thread) => rayon::ThreadPoolBuilder::new()
+                .num_threads(n_thread)
+                .build()?
+                .install(op),
+            None => op(),
+        }
+    }
+    /// Returns the quadrature interval length of each ray and the number of intervals
+    /// along each leg for the ray tracing step `step_length`
+    fn legs(&self, step_length: f64) -> Vec<(DVector<f64>, usize)> {
+        (0..self.n_leg())
+            .map(|k| self.leg(k, step_length))
+            .collect()
+    }
+    /// Returns the quadrature interval length of each ray and the number of intervals along leg #`k`
+    fn leg(&self, k: usize, step_length: f64) -> (DVector<f64>, usize) {
+        // Getting the range to the next surface
+        let mut delta_s = self.xyz[k + 1].column(2) - self.xyz[k].column(2);
+        delta_s
+            .iter_mut()
+            .zip(self.klm[k].column(2).iter())
+            .for_each(|(ds, &mask)| *ds /= mask);
+        let max = delta_s.max();
+        let n = self.quadrature.n_interval(max, step_length);
+        // Upsampling the range
+        delta_s
+            .iter_mut()
+            .for_each(|ds| *ds = self.quadrature.interval(*ds, n));
+        (delta_s, n)
+    }
+    /// Returns the optical path lengths of ray #`i` at each wavelength, the total followed by
+    /// the optical path length along each leg, and the number of samples outside the CFD domain per leg
+    ///
+    /// The total is accumulated over all the legs rather than summed from the legs
+    ///
+    /// The optical path lengths are `None` if the ray is masked out by the [OutOfDomain::Mask] policy
+    fn ray_opl(
+        &self,
+        i: usize,
+        legs: &[(DVector<f64>, usize)],
+        cfd_data: &RTree<TemperatureVelocityField>,
+        interpolator: &dyn Interpolator,
+        wavelengths: &[f64],
+    ) -> Result<RayOpl> {
+        let n_leg = legs.len();
+        let mut opl = vec![0f64; wavelengths.len() * (n_leg + 1)];
+        let (valid, out_of_domain) =
+            self.walk_ray(i, legs, cfd_data, interpolator, |sample, ds, k| {
+                for (opl, &wavelength) in opl.chunks_mut(n_leg + 1).zip(wavelengths) {
+                    let x: f64 = match &sample {
+                        RaySample::Cfd(weights) => weights
+                            .iter()
+                            .map(|(sample, w)| {
+                                w * self.refraction.refraction_index(sample, wavelength)
+                            })
+                            .sum(),
+                        RaySample::Ambient(x) => *x,
+                    };
+                    opl[0] += x * ds;
+                    opl[k + 1] += x * ds;
+                }
+            })?;
+        Ok((valid.then_some(opl), out_of_domain))
+    }
+    /// Walks ray #`i` through the CFD data
+    ///
+    /// For each ray sample, `f` is called with either the CFD interpolation weights
+    /// or the ambient refraction index, the ray tracing step size and the leg index.
+    /// Returns `false` if the ray is masked out by the [OutOfDomain::Mask] policy
+    /// and the number of samples outside the CFD domain per leg
+    fn walk_ray<'a, F>(
+        &self,
+        i: usize,
+        legs: &[(DVector<f64>, usize)],
+        cfd_data: &'a RTree<TemperatureVelocityField>,
+        interpolator: &'a dyn Interpolator,
+        mut f: F,
+    ) -> Result<(bool, Vec<usize>)>
+    where
+        F: FnMut(RaySample<'a>, f64, usize),
+    {
+        let mut valid = true;
+        let mut out_of_domain = vec![0; legs.len()];
+        for (k, (delta_s, n)) in legs.iter().enumerate() {
+            let ds = delta_s[i];
+            let klm = self.klm[k].row(i);
+            let origin = self.xyz[k].row(i);
+            let mut xyz = [origin[0], origin[1], origin[2]];
+            for (s, w) in self.quadrature.nodes(*n) {
+                match self.quadrature {
+                    // Ray tracing to the next sample: u = u + k ds
+                    Quadrature::Riemann => xyz
+                        .iter_mut()
+                        .zip(klm.iter())
+                        .for_each(|(u, &k)| *u += k * ds),
+                    // Ray tracing to the quadrature node: v = u + s k
+                    _ => xyz
+                        .iter_mut()
+                        .zip(origin.iter().zip(klm.iter()))
+                        .for_each(|(v, (&u, &k))| *v = u + s * ds * k),
+                }
+                // interpolating through CFD temperature field
+                let sample = match interpolator.weights(cfd_data, &xyz) {
+                    Some(weights) => RaySample::Cfd(weights),
+                    None => {
+                        out_of_domain[k] += 1;
+                        match self.out_of_domain {
+                            OutOfDomain::Ambient(x) => RaySample::Ambient(x),
+                            OutOfDomain::Clamp => match cfd_data.nearest_neighbor(&xyz) {
+                                Some(nn) => RaySample::Cfd(vec![(Cow::Borrowed(nn), 1f64)]),
+                                None => {
+                                    return Err(Error::OutOfDomain {
+                                        ray: i,
+                                        leg: k,
+                                        point: xyz,
+                                    })
+                                }
+                            },
+                            OutOfDomain::Mask => {
+                                valid = false;
+                                continue;
+                            }
+                            OutOfDomain::Error => {
+                                return Err(Error::OutOfDomain {
+                                    ray: i,
+                                    leg: k,
+                                    point: xyz,
+                                })
+                            }
+                        }
+                    }
+                };
+                if valid {
+                    f(sample, w * ds, k);
+                }
+            }
+        }
+        Ok((valid, out_of_domain))
+    }
+    /// Computes the linear operator from the CFD samples refraction index to the rays optical path length
+    ///
+    /// The operator is computed with the ray tracer interpolation method
+    /// and out-of-domain policy; it can be reused for all the CFD data sharing the same mesh.
+    /// The interpolation must interpolate from the CFD samples, see [Interpolator::cfd_samples]
+    pub fn opl_operator(&self, cfd_data: &RTree<TemperatureVelocityField>) -> Result<OplOperator> {
+        let interpolator = self.interpolator.as_ref();
+        if !interpolator.cfd_samples() {
+            return Err(Error::OperatorInterpolator(interpolator.description()));
+        }
+        let n_sample = self.n_sample();
+        let legs = self.legs(self.step_length);
+        interpolator.reset_radius_statistics();
+
+        let build =
+            || -> Result<Vec<(Option<OperatorRow>, Vec<usize>)>> {
+                (0..n_sample)
+                    .into_par_iter()
+                    .map(|i| {
+                        let mut row = OperatorRow::default();
+                        let (valid, out_of_domain) =
+                            self.walk_ray(i, &legs, cfd_data, interpolator, |sample, ds, _| {
+                                match sample {
+                                    RaySample::Cfd(weights) => row.entries.extend(
+                                        weights
+                                            .into_iter()
+                                            .map(|(sample, w)| (sample.index(), w * ds)),
+                                    ),
+                                    RaySample::Ambient(x) => row.offset += x * ds,
+                                }
+                            })?;
+                        Ok((valid.then(|| row.compress()), out_of_domain))
+                    })
+                    .collect()
+            };
+        let rows = self.install(build)?;
+
+        let mut operator = OplOperator {
+            mask: self.mask.clone(),
+            out_of_domain: OutOfDomainCount(vec![0; legs.len()]),
+            ..Default::default()
+        };
+        operator.indptr.push(0);
+        for ((row, count), m) in rows
+            .into_iter()
+            .zip(operator.mask.iter_mut().filter(|m| **m))
+        {
+            operator
+                .out_of_domain
+                .0
+                .iter_mut()
+                .zip(count)
+                .for_each(|(c, n)| *c += n);
+            match row {
+                Some(row) => {
+                    for (index, value) in row.entries {
+                        operator.n_cfd_sample = operator.n_cfd_sample.max(index + 1);
+                        operator.indices.push(index);
+                        operator.values.push(value);
+                    }
+                    operator.indptr.push(operator.indices.len());
+                    operator.offset.push(row.offset);
+                }
+                None => *m = false,
+            }
+        }
+        Ok(operator)
+    }
+}
+
+/// Returns the root mean square of `values`
+fn rms<I: Iterator<Item = f64>>(values: I) -> f64 {
+    let (n, sum) = values.fold((0usize, 0f64), |(n, sum), x| (n + 1, sum + x * x));
+    (sum / n as f64).sqrt()
+}
+
+/// Returns the pairs of OPD values of the rays within the exit pupil masks of both OPDs,
+/// with the mean over these rays removed from each OPD
+fn masked_pairs<'a>(a: &'a Opd, b: &'a Opd) -> impl Iterator<Item = (f64, f64)> + 'a {
+    let pairs = move || {
+        let (mut a_values, mut b_values) = (a.values.iter(), b.values.iter());
+        a.mask.iter().zip(&b.mask).filter_map(move |(&ma, &mb)| {
+            let x = if ma { a_values.next().copied() } else { None };
+            let y = if mb { b_values.next().copied() } else { None };
+            x.zip(y)
+        })
+    };
+    let (n, a_sum, b_sum) = pairs().fold((0usize, 0f64, 0f64), |(n, sa, sb), (x, y)| {
+        (n + 1, sa + x, sb + y)
+    });
+    let (a_mean, b_mean) = (a_sum / n as f64, b_sum / n as f64);
+    pairs().map(move |(x, y)| (x - a_mean, y - b_mean))
+}
+
+/// Ray sample contribution to the optical path length
+enum RaySample<'a> {
+    /// CFD samples and interpolation weights
+    Cfd(Vec<(Cow<'a, TemperatureVelocityField>, f64)>),
+    /// ambient refraction index
+    Ambient(f64),
+}
+
+/// Sparse row of the optical path length operator
+#[derive(Default)]
+struct OperatorRow {
+    entries: Vec<(usize, f64)>,
+    offset: f64,
+}
+impl OperatorRow {
+    /// Sorts the entries by CFD sample index, summing the duplicates
+    fn compress(mut self) -> Self {
+        self.entries.sort_by_key(|(index, _)| *index);
+        let mut entries: Vec<(usize, f64)> = Vec::with_capacity(self.entries.len());
+        for (index, value) in self.entries {
+            match entries.last_mut() {
+                Some((last, sum)) if *last == index => *sum += value,
+                _ => entries.push((index, value)),
+            }
+        }
+        self.entries = entries;
+        self
+    }
+}
+
+/// Linear operator from the CFD samples refraction index to the rays optical path length
+///
+/// The operator is a sparse matrix in the compressed sparse row format,
+/// with one row per ray and one column per CFD sample.
+/// The column index is the index of the sample in the CFD csv file.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct OplOperator {
+    indptr: Vec<usize>,
+    indices: Vec<usize>,
+    values: Vec<f64>,
+    // optical path length of the ray samples outside the CFD domain
+    offset: Vec<f64>,
+    mask: Vec<bool>,
+    n_cfd_sample: usize,
+    out_of_domain: OutOfDomainCount,
+}
+impl OplOperator {
+    /// Returns the number of rays
+    pub fn n_ray(&self) -> usize {
+        self.offset.len()
+    }
+    /// Returns the number of non-zero elements
+    pub fn nnz(&self) -> usize {
+        self.values.len()
+    }
+    /// Returns the number of ray samples outside the CFD domain per leg
+    pub fn out_of_domain(&self) -> &OutOfDomainCount {
+        &self.out_of_domain
+    }
+    /// Returns the rays optical path length from the CFD samples `refraction_index`
+    pub fn opl(&self, refraction_index: &[f64]) -> Result<Vec<f64>> {
+        if refraction_index.len() < self.n_cfd_sample {
+            return Err(Error::OperatorSize {
+                expected: self.n_cfd_sample,
+                found: refraction_index.len(),
+            });
+        }
+        Ok(self
+            .indptr
+            .par_windows(2)
+            .zip(&self.offset)
+            .map(|(bounds, offset)| {
+                self.indices[bounds[0]..bounds[1]]
+                    .iter()
+                    .zip(&self.values[bounds[0]..bounds[1]])
+                    .fold(*offset, |opl, (&index, value)| {
+                        opl + value * refraction_index[index]
+                    })
+            })
+            .collect())
+    }
+    /// Returns the OPD from the CFD samples `refraction_index`
+    pub fn opd(&self, refraction_index: &[f64]) -> Result<Opd> {
+        Opd::from_opl(self.opl(refraction_index)?, self.mask.clone())
+    }
+    /// Returns the OPD from the CFD samples at the given `wavelength` in micron
+    ///
+    /// The CFD samples must come from a CFD data set with the same mesh
+    /// than the one used to compute the operator
+    pub fn ray_trace(
+        &self,
+        cfd_samples: &[TemperatureVelocityField],
+        model: &dyn RefractiveIndexModel,
+        wavelength: f64,
+    ) -> Result<Opd> {
+        let mut refraction_index = vec![0f64; self.n_cfd_sample.max(cfd_samples.len())];
+        for sample in cfd_samples {
+            if let Some(x) = refraction_index.get_mut(sample.index()) {
+                *x = model.refraction_index(sample, wavelength);
+            }
+        }
+        self.opd(&refraction_index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        gmt::Source, testing, DelaunayInterpolation, GridOptions, NearestNeighbor, VoxelGrid,
+    };
+    use rstar::PointDistance;
+
+    fn ray_tracer() -> RayTracer {
+        RayTracer::gmt(12, Source::on_axis())
+            .unwrap()
+            .shepard_radius(3.)
+    }
+
+    #[test]
+    fn thread_count_invariance() {
+        let cfd_data = testing::dome();
+        let delaunay = DelaunayInterpolation::new(&cfd_data).unwrap();
+        let interpolators: [&dyn Interpolator; 2] = [&ShepardInterpolation::new(3.), &delaunay];
+        for interpolator in interpolators {
+            let (opd, _) = ray_tracer()
+                .ray_trace_with(&cfd_data, interpolator)
+                .unwrap();
+            for n_thread in [1, 3] {
+                let (other, _) = ray_tracer()
+                    .n_thread(n_thread)
+                    .ray_trace_with(&cfd_data, interpolator)
+                    .unwrap();
+                assert_eq!(opd.mean.to_bits(), other.mean.to_bits());
+                assert!(opd
+                    .values
+                    .iter()
+                    .zip(&other.values)
+                    .all(|(a, b)| a.to_bits() == b.to_bits()));
+            }
+        }
+    }
+
+    #[test]
+    fn empty_pupil() {
+        let cfd_data = RTree::bulk_load(testing::lattice([50.; 3], [52.; 3], 1., |_| 285.));
+        let result = ray_tracer()
+            .interpolator(DelaunayInterpolation::new(&cfd_data).unwrap())
+            .out_of_domain(OutOfDomain::Mask)
+            .ray_trace(&cfd_data);
+        assert!(matches!(result, Err(Error::EmptyPupil)));
+    }
+
+    #[test]
+    fn clamp_without_samples() {
+        let cfd_data = RTree::new();
+        let result = ray_tracer().ray_trace(&cfd_data);
+        assert!(matches!(result, Err(Error::OutOfDomain { ray: 0, .. })));
+    }
+
+    #[test]
+    fn shepard_outside_bounding_box() {
+        // the CFD samples do not cover the rays, the Shepard interpolation falls back
+        // to the nearest neighbor without applying the out-of-domain policy
+        let cfd_data = RTree::bulk_load(testing::lattice([-2.; 3], [2.; 3], 1., |_| 285.));
+        let (_, out_of_domain) = ray_tracer()
+            .out_of_domain(OutOfDomain::Error)
+            .ray_trace_with(&cfd_data, &ShepardInterpolation::new(0.5))
+            .unwrap();
+        assert_eq!(out_of_domain.total(), 0);
+    }
+
+    #[test]
+    fn wavelength_dispersion() {
+        let cfd_data = testing::dome();
+        let ray_tracer = ray_tracer();
+        let opds = ray_tracer
+            .ray_trace_wavelengths(&cfd_data, &[0.5, 1.])
+            .unwrap();
+        let opd = ray_tracer.ray_trace(&cfd_data).unwrap();
+        assert_eq!(opds[0].mean.to_bits(), opd.mean.to_bits());
+        // Gladstone-Dale dispersion factor
+        let dispersion = |wavelength: f64| 1. + 0.00752 / (wavelength * wavelength);
+        let ratio = dispersion(1.) / dispersion(0.5);
+        assert!((opds[1].mean - ratio * opds[0].mean).abs() < 1e-12 * opds[0].mean);
+        for (a, b) in opds[0].values.iter().zip(&opds[1].values) {
+            assert!((b - ratio * a).abs() < 1e-12 * opds[0].mean);
+        }
+    }
+
+    #[test]
+    fn leg_breakdown() {
+        let cfd_data = testing::dome();
+        let ray_tracer = ray_tracer();
+        let opd = ray_tracer.ray_trace(&cfd_data).unwrap();
+        let breakdown = ray_tracer.ray_trace_legs(&cfd_data).unwrap();
+        assert_eq!(breakdown.legs.len(), ray_tracer.n_leg());
+        assert_eq!(breakdown.total.mean.to_bits(), opd.mean.to_bits());
+        assert_eq!(breakdown.total.values, opd.values);
+        let mean: f64 = breakdown.legs.iter().map(|leg| leg.mean).sum();
+        assert!((mean - opd.mean).abs() < 1e-12 * opd.mean);
+        assert!(breakdown.legs.iter().all(|leg| leg.mask == opd.mask));
+        for (i, value) in opd.values.iter().enumerate() {
+            let sum: f64 = breakdown.legs.iter().map(|leg| leg.values[i]).sum();
+            assert!((sum - value).abs() < 1e-12 * opd.mean);
+        }
+    }
+
+    /// Checks the OPD from the OPL operator against the OPD from the ray tracing
+    fn assert_operator(ray_tracer: RayTracer, cfd_data: &RTree<TemperatureVelocityField>) {
+        let opd = ray_tracer.ray_trace(cfd_data).unwrap();
+        let operator = ray_tracer.opl_operator(cfd_data).unwrap();
+        let samples: Vec<_> = cfd_data.iter().cloned().collect();
+        let other = operator
+            .ray_trace(&samples, &GladstoneDale::default(), 0.5)
+            .unwrap();
+        assert_eq!(operator.n_ray(), opd.values.len());
+        assert_eq!(other.mask, opd.mask);
+        assert!((other.mean - opd.mean).abs() < 1e-12 * opd.mean);
+        for (a, b) in opd.values.iter().zip(&other.values) {
+            assert!((a - b).abs() < 1e-12 * opd.mean);
+        }
+    }
+
+    #[test]
+    fn operator_shepard() {
+        assert_operator(ray_tracer(), &testing::dome());
+    }
+
+    #[test]
+    fn operator_delaunay() {
+        let cfd_data = testing::dome();
+        let delaunay = DelaunayInterpolation::new(&cfd_data).unwrap();
+        assert_operator(ray_tracer().interpolator(delaunay), &cfd_data);
+    }
+
+    #[test]
+    fn operator_clamp() {
+        // the rays leave the convex hull and are clamped to the nearest CFD samples
+        let cfd_data = RTree::bulk_load(testing::lattice([-6.; 3], [6.; 3], 1., |[x, y, z]| {
+            285. + 0.1 * x - 0.2 * y + 0.3 * z
+        }));
+        let delaunay = DelaunayInterpolation::new(&cfd_data).unwrap();
+        let ray_tracer = ray_tracer().interpolator(delaunay);
+        assert!(
+            ray_tracer
+                .opl_operator(&cfd_data)
+                .unwrap()
+                .out_of_domain()
+                .total()
+                > 0
+        );
+        assert_operator(ray_tracer, &cfd_data);
+    }
+
+    #[test]
+    fn operator_voxel_grid() {
+        let cfd_data = testing::dome();
+        let grid = VoxelGrid::from_rtree(&cfd_data, GridOptions::default().resolution(2.)).unwrap();
+        let result = ray_tracer().interpolator(grid).opl_operator(&cfd_data);
+        assert!(matches!(result, Err(Error::OperatorInterpolator(_))));
+    }
+
+    /// Ray tracing loop of the first version of the crate: incremental stepping,
+    /// a single accumulator over all the legs and the Shepard interpolation
+    /// of the refraction index computed as Σ r<sup>-2</sup> n / Σ r<sup>-2</sup>
+    fn legacy_opl(
+        ray_tracer: &RayTracer,
+        shepard_radius: f64,
+        i: usize,
+        cfd_data: &RTree<TemperatureVelocityField>,
+    ) -> f64 {
+        let shepard = |query_point: &[f64; 3]| -> Option<f64> {
+            let samples =
+                cfd_data.locate_within_distance(*query_point, shepard_radius * shepard_radius);
+            let mut num = None;
+            let mut denom = None;
+            for sample in samples {
+                let d2 = sample.distance_2(query_point);
+                if d2 > 0f64 {
+                    let rbf = d2.recip();
+                    *num.get_or_insert(0f64) += rbf * sample.refraction_index();
+                    *denom.get_or_insert(0f64) += rbf;
+                } else {
+                    return Some(sample.refraction_index());
+                }
+            }
+            match (num, denom) {
+                (Some(num), Some(denom)) => Some(num / denom),
+                _ => cfd_data
+                    .nearest_neighbor(query_point)
+                    .map(|nn| nn.refraction_index()),
+            }
+        };
+        let mut opl = 0f64;
         for k in 0..3 {
-            #[cfg(feature = "linya")]
-            writeln!(progress.stderr(), "Ray trace #{}/3", k + 1)
-                .expect("failed to wite to stderr");
-            #[cfg(feature = "linya")]
-            progress.set_and_draw(&bar, 0);
-            //dbg!(k);
-            // Getting the range to the next surface
-            let mut delta_s = self.xyz[k + 1].column(2) - self.xyz[k].column(2);
+            let mut delta_s = ray_tracer.xyz[k + 1].column(2) - ray_tracer.xyz[k].column(2);
             delta_s
                 .iter_mut()
-                .zip(self.klm[k].column(2).iter())
+                .zip(ray_tracer.klm[k].column(2).iter())
                 .for_each(|(ds, &mask)| *ds /= mask);
             let max = delta_s.max();
-            let n_h = (max / self.step_length).ceil() as usize;
-            delta_s /= (n_h - 1) as f64; // Upsampling the range
-
-            let mut xyz = self.xyz[k].clone();
+            let n_h = (max / ray_tracer.step_length).ceil() as usize;
+            delta_s /= (n_h - 1) as f64;
+            let ds = delta_s[i];
+            let mut xyz = [0, 1, 2].map(|j| ray_tracer.xyz[k][(i, j)]);
             for _ in 0..n_h {
-                #[cfg(feature = "linya")]
-                progress.inc_and_draw(&bar, 1000 / n_h);
-                // Ray tracing to the next layer: v = u + s_i k ()
-                xyz.column_iter_mut()
-                    .zip(self.klm[k].column_iter())
-                    .for_each(|(mut u, k)| {
-                        //u += k * &delta_s
-                        u.iter_mut()
-                            .zip(k.iter())
-                            .zip(delta_s.iter())
-                            .for_each(|((u, &k), &ds)| *u += k * ds);
-                    });
-                //let z = xyz.row(0)[2];
-                //dbg!(z);
+                xyz.iter_mut()
+                    .zip(ray_tracer.klm[k].row(i).iter())
+                    .for_each(|(u, &k)| *u += k * ds);
+                if let Some(x) = shepard(&xyz) {
+                    opl += x * ds;
+                }
+            }
+        }
+        opl
+    }
 
-                // interpolating through CFD temperature field
-                #[cfg(feature = "nearest")]
-                let delta_opl = xyz.row_iter().zip(delta_s.iter()).filter_map(|(row, &ds)| {
-                    let xyz: Vec<f64> = row.iter().cloned().collect();
-                    cfd_data
-                        .nearest_neighbor(&[xyz[0], xyz[1], xyz[2]])
-                        .map(|nn| nn.refraction_index() * ds)
-                });
-                #[cfg(feature = "shepard")]
-                let delta_opl = xyz.row_iter().zip(delta_s.iter()).filter_map(|(row, &ds)| {
-                    let xyz: Vec<f64> = row.iter().cloned().collect();
-                    cfd_data
-                        .shepard(&[xyz[0], xyz[1], xyz[2]], self.shepard_radius2)
-                        .map(|x| x * ds)
-                });
-                opl.iter_mut()
-                    .zip(delta_opl)
-                    .for_each(|(opl, d_opl)| *opl += d_opl);
+    /// Returns the total optical path length of each ray
+    fn ray_opls(
+        ray_tracer: &RayTracer,
+        cfd_data: &RTree<TemperatureVelocityField>,
+    ) -> Vec<(f64, Vec<usize>)> {
+        let legs = ray_tracer.legs(ray_tracer.step_length);
+        (0..ray_tracer.n_sample())
+            .map(|i| {
+                let (opl, out_of_domain) = ray_tracer
+                    .ray_opl(
+                        i,
+                        &legs,
+                        cfd_data,
+                        ray_tracer.interpolator.as_ref(),
+                        &[ray_tracer.wavelength],
+                    )
+                    .unwrap();
+                (opl.unwrap()[0], out_of_domain)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn riemann_regression() {
+        let cfd_data = testing::dome();
+        let ray_tracer = ray_tracer();
+        // the interpolation weights are normalized before summing the refraction index,
+        // so the optical path lengths match the first version up to the rounding errors
+        for (i, (opl, out_of_domain)) in ray_opls(&ray_tracer, &cfd_data).into_iter().enumerate() {
+            assert!(out_of_domain.iter().all(|&n| n == 0));
+            let legacy = legacy_opl(&ray_tracer, 3., i, &cfd_data);
+            assert!((opl - legacy).abs() < 1e-14 * legacy, "ray #{i}");
+        }
+        // OPD of the first version
+        let opd = ray_tracer.ray_trace(&cfd_data).unwrap();
+        assert_eq!(opd.values.len(), 72);
+        assert!((opd.mean - 1.0129735888173853e-2).abs() < 1e-14 * opd.mean);
+        assert!((opd.values[0] - 3.1328737428490405e-5).abs() < 1e-14 * opd.mean);
+        assert!((opd.values[71] + 1.655200349473182e-5).abs() < 1e-14 * opd.mean);
+    }
+
+    #[test]
+    fn step_convergence() {
+        let cfd_data = testing::dome();
+        let convergence = ray_tracer()
+            .ray_tracing_step(0.5)
+            .step_convergence(&cfd_data, 3)
+            .unwrap();
+        let steps: Vec<_> = convergence.iter().map(|c| c.step).collect();
+        assert_eq!(steps, [0.5, 0.25, 0.125]);
+        let opds: Vec<_> = steps
+            .iter()
+            .map(|&step| {
+                ray_tracer()
+                    .ray_tracing_step(step)
+                    .ray_trace(&cfd_data)
+                    .unwrap()
+            })
+            .collect();
+        assert!(convergence[0].change.is_none());
+        for (k, (c, opd)) in convergence.iter().zip(&opds).enumerate() {
+            assert_eq!(c.rms, rms(opd.values.iter().copied()));
+            if k > 0 {
+                // same exit pupil mask, the OPDs having a zero mean
+                let change = rms(opds[k - 1]
+                    .values
+                    .iter()
+                    .zip(&opd.values)
+                    .map(|(a, b)| a - b));
+                assert!((c.change.unwrap() - change).abs() < 1e-9 * change);
             }
         }
+        assert!(convergence[2].change < convergence[1].change);
+    }
 
-        let mean_opl = opl.iter().cloned().sum::<f64>() / opl.len() as f64;
-        //dbg!(mean_opl);
-        let zeroed_opl = opl.into_iter().map(|x| x - mean_opl);
-        Opd {
-            mean: mean_opl,
-            values: zeroed_opl.collect(),
-            mask: self.mask.clone(),
+    #[test]
+    fn masked_opd_pairs() {
+        let a = Opd {
+            mean: 0.,
+            values: vec![1., 2., 3.],
+            mask: vec![true, true, false, true],
+        };
+        let b = Opd {
+            mean: 0.,
+            values: vec![5., 7., 9.],
+            mask: vec![false, true, true, true],
+        };
+        // the rays #1 and #3 are within both masks
+        let pairs: Vec<_> = masked_pairs(&a, &b).collect();
+        assert_eq!(pairs, [(-0.5, -2.), (0.5, 2.)]);
+        assert_eq!(rms([3., 4.].into_iter()), 12.5f64.sqrt());
+    }
+
+    #[test]
+    fn quadrature_exactness() {
+        for quadrature in [Quadrature::Trapezoidal, Quadrature::Simpson] {
+            for length in [1., 2.5, 7.3] {
+                let n = quadrature.n_interval(length, 0.4);
+                let ds = quadrature.interval(length, n);
+                let integral = |f: &dyn Fn(f64) -> f64| -> f64 {
+                    quadrature.nodes(n).map(|(s, w)| w * f(s * ds) * ds).sum()
+                };
+                assert!((integral(&|_| 3.) - 3. * length).abs() < 1e-12);
+                let expected = 3. * length - length * length;
+                assert!((integral(&|s| 3. - 2. * s) - expected).abs() < 1e-12);
+            }
+        }
+    }
+
+    /// Returns the length of each leg of ray #`i`
+    fn leg_lengths(ray_tracer: &RayTracer, i: usize) -> Vec<f64> {
+        ray_tracer
+            .legs(ray_tracer.step_length)
+            .iter()
+            .map(|(delta_s, n)| delta_s[i] * *n as f64)
+            .collect()
+    }
+
+    #[test]
+    fn constant_field() {
+        let cfd_data = RTree::bulk_load(testing::lattice([50.; 3], [52.; 3], 1., |_| 285.));
+        for quadrature in [Quadrature::Trapezoidal, Quadrature::Simpson] {
+            let ray_tracer = ray_tracer()
+                .quadrature(quadrature)
+                .interpolator(DelaunayInterpolation::new(&cfd_data).unwrap())
+                .out_of_domain(OutOfDomain::Ambient(2e-4));
+            for (i, (opl, _)) in ray_opls(&ray_tracer, &cfd_data).into_iter().enumerate() {
+                let expected = 2e-4 * leg_lengths(&ray_tracer, i).iter().sum::<f64>();
+                assert!((opl - expected).abs() < 1e-12 * expected, "{quadrature:?}");
+            }
+        }
+    }
+
+    /// Refraction index proportional to the temperature
+    struct Linear;
+    impl RefractiveIndexModel for Linear {
+        fn refraction_index(&self, sample: &TemperatureVelocityField, _wavelength: f64) -> f64 {
+            1e-6 * sample.temperature()
+        }
+    }
+
+    #[test]
+    fn linear_field() {
+        let temperature = |[x, y, z]: [f64; 3]| 285. + 0.1 * x - 0.2 * y + 0.05 * z;
+        let cfd_data = RTree::bulk_load(testing::lattice(
+            [-14., -14., -8.],
+            [14., 14., 26.],
+            2.,
+            temperature,
+        ));
+        let options = GridOptions::default().resolution(2.);
+        for quadrature in [Quadrature::Trapezoidal, Quadrature::Simpson] {
+            let grid = VoxelGrid::from_rtree_with(&cfd_data, options, &NearestNeighbor).unwrap();
+            let ray_tracer = ray_tracer()
+                .quadrature(quadrature)
+                .refractive_index_model(Linear)
+                .interpolator(grid);
+            for (i, (opl, out_of_domain)) in
+                ray_opls(&ray_tracer, &cfd_data).into_iter().enumerate()
+            {
+                assert!(out_of_domain.iter().all(|&n| n == 0));
+                let expected: f64 = leg_lengths(&ray_tracer, i)
+                    .into_iter()
+                    .enumerate()
+                    .map(|(k, length)| {
+                        let midpoint = [0, 1, 2].map(|j| {
+                            ray_tracer.xyz[k][(i, j)] + 0.5 * length * ray_tracer.klm[k][(i, j)]
+                        });
+                        1e-6 * temperature(midpoint) * length
+                    })
+                    .sum();
+                assert!((opl - expected).abs() < 1e-12 * expected, "{quadrature:?}");
+            }
         }
     }
 }
@@ -0,0 +1,213 @@
+use super::TemperatureVelocityField;
+use serde::{Deserialize, Serialize};
+
+/// Interface to the models of the air index of refraction
+pub trait RefractiveIndexModel: Send + Sync {
+    /// Returns the index of refraction minus one of a CFD sample at the given `wavelength` in micron
+    fn refraction_index(&self, sample: &TemperatureVelocityField, wavelength: f64) -> f64;
+    /// Returns a description of the model
+    fn description(&self) -> String {
+        std::any::type_name::<Self>().to_string()
+    }
+}
+
+/// Gladstone-Dale like refraction index model
+///
+/// The index of refraction is given by
+/// 7.76e-7 P (1 + 0.00752 / λ<sup>2</sup>) / T
+/// with `P` the reference pressure in Pa, `λ` the wavelength in micron and `T` the temperature in K.
+/// The pressure of the CFD samples is ignored
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GladstoneDale {
+    /// reference pressure [Pa]
+    pub pressure: f64,
+}
+impl Default for GladstoneDale {
+    fn default() -> Self {
+        Self { pressure: 75e3 }
+    }
+}
+impl RefractiveIndexModel for GladstoneDale {
+    fn description(&self) -> String {
+        format!("{self:?}")
+    }
+    fn refraction_index(&self, sample: &TemperatureVelocityField, wavelength: f64) -> f64 {
+        7.76e-7 * self.pressure * (1. + 0.00752 / (wavelength * wavelength)) / sample.temperature()
+    }
+}
+
+/// Saturation vapor pressure of water in Pa at temperature `t` in K (Davis, 1992)
+fn saturation_vapor_pressure(t: f64) -> f64 {
+    (1.2378847e-5 * t * t - 1.9121316e-2 * t + 33.93711047 - 6.3431645e3 / t).exp()
+}
+
+/// Edlén refraction index model
+///
+/// Implements the Edlén equation as revised by Birch and Downs (Metrologia, 1993 & 1994).
+/// The pressure of the CFD samples is used if it has been loaded, see [CsvSchema::with_pressure](crate::CsvSchema::with_pressure),
+/// otherwise the model pressure is used
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Edlen {
+    /// pressure of the CFD samples without pressure [Pa]
+    pub pressure: f64,
+    /// relative humidity within [0,1]
+    pub humidity: f64,
+}
+impl Default for Edlen {
+    fn default() -> Self {
+        Self {
+            pressure: 75e3,
+            humidity: 0.,
+        }
+    }
+}
+impl RefractiveIndexModel for Edlen {
+    fn description(&self) -> String {
+        format!("{self:?}")
+    }
+    fn refraction_index(&self, sample: &TemperatureVelocityField, wavelength: f64) -> f64 {
+        let sigma2 = (wavelength * wavelength).recip();
+        let p = sample.pressure().unwrap_or(self.pressure);
+        let t = sample.temperature() - 273.15;
+        // standard air
+        let n_s = 1e-8 * (8342.54 + 2406147. / (130. - sigma2) + 15998. / (38.9 - sigma2));
+        // dry air at (p,t)
+        let n_tp =
+            p * n_s / 96095.43 * (1. + 1e-8 * (0.601 - 0.00972 * t) * p) / (1. + 0.003661 * t);
+        // water vapor partial pressure
+        let f = self.humidity * saturation_vapor_pressure(sample.temperature());
+        n_tp - f * (3.7345 - 0.0401 * sigma2) * 1e-10
+    }
+}
+
+/// Ciddor refraction index model
+///
+/// Implements the Ciddor equations (Applied Optics, 1996).
+/// The pressure of the CFD samples is used if it has been loaded, see [CsvSchema::with_pressure](crate::CsvSchema::with_pressure),
+/// otherwise the model pressure is used
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Ciddor {
+    /// pressure of the CFD samples without pressure [Pa]
+    pub pressure: f64,
+    /// relative humidity within [0,1]
+    pub humidity: f64,
+    /// CO<sub>2</sub> concentration [ppm]
+    pub co2: f64,
+}
+impl Default for Ciddor {
+    fn default() -> Self {
+        Self {
+            pressure: 75e3,
+            humidity: 0.,
+            co2: 450.,
+        }
+    }
+}
+impl Ciddor {
+    /// Compressibility of moist air at pressure `p` [Pa], temperature `t` [K]
+    /// and water vapor molar fraction `x_w`
+    fn compressibility(p: f64, t: f64, x_w: f64) -> f64 {
+        let tc = t - 273.15;
+        let (a0, a1, a2) = (1.58123e-6, -2.9331e-8, 1.1043e-10);
+        let (b0, b1) = (5.707e-6, -2.051e-8);
+        let (c0, c1) = (1.9898e-4, -2.376e-6);
+        let (d, e) = (1.83e-11, -0.765e-8);
+        let pt = p / t;
+        1. - pt * (a0 + a1 * tc + a2 * tc * tc + (b0 + b1 * tc) * x_w + (c0 + c1 * tc) * x_w * x_w)
+            + pt * pt * (d + e * x_w * x_w)
+    }
+}
+impl RefractiveIndexModel for Ciddor {
+    fn description(&self) -> String {
+        format!("{self:?}")
+    }
+    fn refraction_index(&self, sample: &TemperatureVelocityField, wavelength: f64) -> f64 {
+        const R: f64 = 8.314510;
+        const M_W: f64 = 0.018015;
+        let sigma2 = (wavelength * wavelength).recip();
+        let p = sample.pressure().unwrap_or(self.pressure);
+        let t = sample.temperature();
+        let tc = t - 273.15;
+        // standard dry air with CO2 correction
+        let n_as = 1e-8 * (5792105. / (238.0185 - sigma2) + 167917. / (57.362 - sigma2));
+        let n_axs = n_as * (1. + 0.534e-6 * (self.co2 - 450.));
+        // standard water vapor
+        let n_ws = 1.022e-8
+            * (295.235 + 2.6422 * sigma2 - 0.032380 * sigma2 * sigma2
+                + 0.004028 * sigma2 * sigma2 * sigma2);
+        // dry air molar mass
+        let m_a = 1e-3 * (28.9635 + 12.011e-6 * (self.co2 - 400.));
+        // water vapor molar fraction
+        let enhancement = 1.00062 + 3.14e-8 * p + 5.6e-7 * tc * tc;
+        let x_w = enhancement * self.humidity * saturation_vapor_pressure(t) / p;
+        // densities
+        let rho_axs = 101325. * m_a / (Self::compressibility(101325., 288.15, 0.) * R * 288.15);
+        let rho_ws = 1333. * M_W / (Self::compressibility(1333., 293.15, 1.) * R * 293.15);
+        let z = Self::compressibility(p, t, x_w);
+        let rho_a = p * m_a * (1. - x_w) / (z * R * t);
+        let rho_w = p * M_W * x_w / (z * R * t);
+        n_axs * rho_a / rho_axs + n_ws * rho_w / rho_ws
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Refraction index at 633nm of dry air with 450ppm of CO<sub>2</sub>
+    /// for (temperature [C], pressure [kPa], Ciddor, Edlén),
+    /// from the NIST refractive index of air calculator test data
+    const DRY_AIR: [(f64, f64, f64, f64); 6] = [
+        (20., 101.325, 1.000271800, 1.000271799),
+        (20., 60., 1.000160924, 1.000160920),
+        (20., 120., 1.000321916, 1.000321918),
+        (50., 100., 1.000243285, 1.000243270),
+        (5., 100., 1.000282756, 1.000282750),
+        (-40., 100., 1.000337580, 1.000337471),
+    ];
+
+    fn sample(temperature: f64) -> TemperatureVelocityField {
+        TemperatureVelocityField::new(temperature + 273.15, None, [0.; 3], 0)
+    }
+
+    #[test]
+    fn ciddor_reference_values() {
+        for (t, p, n, _) in DRY_AIR {
+            let model = Ciddor {
+                pressure: p * 1e3,
+                ..Default::default()
+            };
+            assert!((1. + model.refraction_index(&sample(t), 0.633) - n).abs() < 1e-9);
+        }
+        // 50C, 120kPa and 100% relative humidity
+        let model = Ciddor {
+            pressure: 120e3,
+            humidity: 1.,
+            co2: 450.,
+        };
+        assert!((1. + model.refraction_index(&sample(50.), 0.633) - 1.000287924).abs() < 1e-9);
+    }
+
+    #[test]
+    fn sample_pressure() {
+        let model = Edlen::default();
+        let sample = sample(20.).with_pressure(Some(101.325e3));
+        assert!((1. + model.refraction_index(&sample, 0.633) - DRY_AIR[0].3).abs() < 1e-9);
+        let model = Ciddor::default();
+        assert!((1. + model.refraction_index(&sample, 0.633) - DRY_AIR[0].2).abs() < 1e-9);
+    }
+
+    #[test]
+    fn edlen_reference_values() {
+        for (t, p, _, n) in DRY_AIR {
+            let model = Edlen {
+                pressure: p * 1e3,
+                humidity: 0.,
+            };
+            assert!((1. + model.refraction_index(&sample(t), 0.633) - n).abs() < 1e-9);
+        }
+    }
+}
@@ -0,0 +1,526 @@
+use super::{Error, Result, TemperatureVelocityField};
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+
+/// Physical units of the CFD csv columns
+///
+/// The units are (de)serialized as their symbols, e.g. `"m/s"`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Unit {
+    Kelvin,
+    Celsius,
+    Fahrenheit,
+    Meter,
+    Centimeter,
+    Millimeter,
+    Foot,
+    Inch,
+    MeterPerSecond,
+    KilometerPerHour,
+    FootPerSecond,
+    Pascal,
+    HectoPascal,
+    KiloPascal,
+    Millibar,
+    Bar,
+    Atmosphere,
+    KilogramPerCubicMeter,
+    GramPerCubicCentimeter,
+}
+
+/// Physical quantities of the CFD csv columns
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quantity {
+    Temperature,
+    Length,
+    Velocity,
+    Pressure,
+    Density,
+}
+
+impl Unit {
+    /// Returns the physical quantity measured in this unit
+    pub fn quantity(&self) -> Quantity {
+        use Unit::*;
+        match self {
+            Kelvin | Celsius | Fahrenheit => Quantity::Temperature,
+            Meter | Centimeter | Millimeter | Foot | Inch => Quantity::Length,
+            MeterPerSecond | KilometerPerHour | FootPerSecond => Quantity::Velocity,
+            Pascal | HectoPascal | KiloPascal | Millibar | Bar | Atmosphere => Quantity::Pressure,
+            KilogramPerCubicMeter | GramPerCubicCentimeter => Quantity::Density,
+        }
+    }
+    /// Returns the unit symbol
+    pub fn symbol(&self) -> &'static str {
+        use Unit::*;
+        match self {
+            Kelvin => "K",
+            Celsius => "°C",
+            Fahrenheit => "°F",
+            Meter => "m",
+            Centimeter => "cm",
+            Millimeter => "mm",
+            Foot => "ft",
+            Inch => "in",
+            MeterPerSecond => "m/s",
+            KilometerPerHour => "km/h",
+            FootPerSecond => "ft/s",
+            Pascal => "Pa",
+            HectoPascal => "hPa",
+            KiloPascal => "kPa",
+            Millibar => "mbar",
+            Bar => "bar",
+            Atmosphere => "atm",
+            KilogramPerCubicMeter => "kg/m^3",
+            GramPerCubicCentimeter => "g/cm^3",
+        }
+    }
+    /// Converts `value` to SI units (K, m, m/s, Pa and kg/m<sup>3</sup>)
+    pub fn to_si(&self, value: f64) -> f64 {
+        use Unit::*;
+        match self {
+            Kelvin | Meter | MeterPerSecond | Pascal | KilogramPerCubicMeter => value,
+            Celsius => value + 273.15,
+            Fahrenheit => (value - 32.) * 5. / 9. + 273.15,
+            Centimeter => value * 1e-2,
+            Millimeter => value * 1e-3,
+            Foot => value * 0.3048,
+            Inch => value * 0.0254,
+            KilometerPerHour => value / 3.6,
+            FootPerSecond => value * 0.3048,
+            HectoPascal | Millibar => value * 1e2,
+            KiloPascal => value * 1e3,
+            Bar => value * 1e5,
+            Atmosphere => value * 101325.,
+            GramPerCubicCentimeter => value * 1e3,
+        }
+    }
+}
+impl FromStr for Unit {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        use Unit::*;
+        Ok(match s.trim() {
+            "K" => Kelvin,
+            "°C" | "C" | "degC" => Celsius,
+            "°F" | "F" | "degF" => Fahrenheit,
+            "m" => Meter,
+            "cm" => Centimeter,
+            "mm" => Millimeter,
+            "ft" => Foot,
+            "in" => Inch,
+            "m/s" => MeterPerSecond,
+            "km/h" => KilometerPerHour,
+            "ft/s" => FootPerSecond,
+            "Pa" => Pascal,
+            "hPa" => HectoPascal,
+            "kPa" => KiloPascal,
+            "mbar" => Millibar,
+            "bar" => Bar,
+            "atm" => Atmosphere,
+            "kg/m^3" | "kg/m3" => KilogramPerCubicMeter,
+            "g/cm^3" | "g/cm3" => GramPerCubicCentimeter,
+            _ => return Err(Error::Schema(format!("unknown unit {s:?}"))),
+        })
+    }
+}
+impl TryFrom<String> for Unit {
+    type Error = Error;
+
+    fn try_from(symbol: String) -> Result<Self> {
+        symbol.parse()
+    }
+}
+impl From<Unit> for String {
+    fn from(unit: Unit) -> Self {
+        unit.symbol().to_string()
+    }
+}
+
+/// CFD csv column
+///
+/// A column is (de)serialized either as a header with the unit in parentheses, e.g. `"X (mm)"`,
+/// or as a header and a unit, e.g. `{ header = "X", unit = "mm" }`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "ColumnConfig", into = "ColumnConfig")]
+pub struct Column {
+    /// column header
+    pub header: String,
+    /// column unit
+    pub unit: Unit,
+}
+impl Column {
+    /// Creates a new column
+    pub fn new<S: Into<String>>(header: S, unit: Unit) -> Self {
+        Self {
+            header: header.into(),
+            unit,
+        }
+    }
+}
+impl FromStr for Column {
+    type Err = Error;
+
+    /// Parses a column from a header with the unit in parentheses, e.g. `"X (mm)"`
+    fn from_str(header: &str) -> Result<Self> {
+        let unit = header
+            .rsplit_once('(')
+            .and_then(|(_, unit)| unit.strip_suffix(')'))
+            .ok_or_else(|| Error::Schema(format!("no unit found in {header:?}")))?;
+        Ok(Self::new(header, unit.parse()?))
+    }
+}
+
+/// Serialized [Column]
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum ColumnConfig {
+    Header(String),
+    Column { header: String, unit: Unit },
+}
+impl TryFrom<ColumnConfig> for Column {
+    type Error = Error;
+
+    fn try_from(column: ColumnConfig) -> Result<Self> {
+        match column {
+            ColumnConfig::Header(header) => header.parse(),
+            ColumnConfig::Column { header, unit } => Ok(Self::new(header, unit)),
+        }
+    }
+}
+impl From<Column> for ColumnConfig {
+    fn from(column: Column) -> Self {
+        match column.header.parse::<Column>() {
+            Ok(parsed) if parsed == column => Self::Header(column.header),
+            _ => Self::Column {
+                header: column.header,
+                unit: column.unit,
+            },
+        }
+    }
+}
+impl fmt::Display for Column {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} [{:?}]", self.header, self.unit)
+    }
+}
+
+/// Mapping between the CFD csv columns and the CFD sample fields
+///
+/// The temperature and the coordinates are required
+/// while the velocity, the pressure and the density are optional.
+/// The default schema matches the STAR-CCM+ optvol exports
+/// without the pressure and the density columns,
+/// see [with_pressure](CsvSchema::with_pressure) and [with_density](CsvSchema::with_density).
+///
+/// The pressure of the CFD samples is used by the [Edlen](crate::Edlen)
+/// and [Ciddor](crate::Ciddor) models instead of their configured pressure,
+/// the density is loaded for inspection only
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CsvSchema {
+    pub temperature: Column,
+    pub x: Column,
+    pub y: Column,
+    pub z: Column,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub velocity: Option<Column>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pressure: Option<Column>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub density: Option<Column>,
+}
+impl Default for CsvSchema {
+    fn default() -> Self {
+        Self {
+            temperature: Column::new("Temperature (K)", Unit::Kelvin),
+            x: Column::new("X (m)", Unit::Meter),
+            y: Column::new("Y (m)", Unit::Meter),
+            z: Column::new("Z (m)", Unit::Meter),
+            velocity: Some(Column::new(
+                "Velocity: Magnitude (m/s)",
+                Unit::MeterPerSecond,
+            )),
+            pressure: None,
+            density: None,
+        }
+    }
+}
+impl CsvSchema {
+    /// Sets the pressure column, e.g. `Column::new("Absolute Pressure (Pa)", Unit::Pascal)`
+    pub fn with_pressure(mut self, column: Column) -> Self {
+        self.pressure = Some(column);
+        self
+    }
+    /// Sets the density column, e.g. `Column::new("Density (kg/m^3)", Unit::KilogramPerCubicMeter)`
+    pub fn with_density(mut self, column: Column) -> Self {
+        self.density = Some(column);
+        self
+    }
+    /// Checks that the column units match the sample fields
+    pub fn validate(&self) -> Result<()> {
+        let columns = [
+            (Some(&self.temperature), Quantity::Temperature),
+            (Some(&self.x), Quantity::Length),
+            (Some(&self.y), Quantity::Length),
+            (Some(&self.z), Quantity::Length),
+            (self.velocity.as_ref(), Quantity::Velocity),
+            (self.pressure.as_ref(), Quantity::Pressure),
+            (self.density.as_ref(), Quantity::Density),
+        ];
+        for (column, quantity) in columns {
+            if let Some(column) = column.filter(|c| c.unit.quantity() != quantity) {
+                return Err(Error::Schema(format!(
+                    "column {column} is not a {quantity:?}"
+                )));
+            }
+        }
+        Ok(())
+    }
+    /// Returns a fingerprint of the columns mapping
+    ///
+    /// The fingerprint is recorded in the binary cache of the csv files
+    /// so that a cache written with another schema is not used, see [binary](crate::binary)
+    pub fn fingerprint(&self) -> u64 {
+        let columns = [
+            Some(&self.temperature),
+            Some(&self.x),
+            Some(&self.y),
+            Some(&self.z),
+            self.velocity.as_ref(),
+            self.pressure.as_ref(),
+            self.density.as_ref(),
+        ];
+        // 64 bits FNV-1a hash, stable across builds unlike the std hasher
+        columns
+            .into_iter()
+            .flat_map(|column| {
+                match column {
+                    Some(column) => format!("{}\u{1f}{:?}\u{1e}", column.header, column.unit),
+                    None => "\u{1e}".to_string(),
+                }
+                .into_bytes()
+            })
+            .fold(0xcbf29ce484222325, |hash, byte| {
+                (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+            })
+    }
+    /// Locates the schema columns in the csv `headers`
+    ///
+    /// The velocity column is ignored if it is not found
+    /// whereas the opted-in pressure and density columns must be found
+    pub(crate) fn locate(&self, headers: &csv::StringRecord) -> Result<ColumnIndices> {
+        self.validate()?;
+        let find = |column: &Column| {
+            headers
+                .iter()
+                .position(|header| header.trim() == column.header)
+                .map(|i| (i, column.unit))
+        };
+        let required = |column: &Column| {
+            find(column).ok_or_else(|| Error::MissingColumn {
+                column: column.header.clone(),
+                available: headers.iter().map(|h| h.to_string()).collect(),
+            })
+        };
+        Ok(ColumnIndices {
+            temperature: required(&self.temperature)?,
+            xyz: [required(&self.x)?, required(&self.y)?, required(&self.z)?],
+            velocity: self.velocity.as_ref().and_then(find),
+            pressure: self.pressure.as_ref().map(required).transpose()?,
+            density: self.density.as_ref().map(required).transpose()?,
+        })
+    }
+}
+
+/// Indices and units of the schema columns in a csv file
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ColumnIndices {
+    temperature: (usize, Unit),
+    xyz: [(usize, Unit); 3],
+    velocity: Option<(usize, Unit)>,
+    pressure: Option<(usize, Unit)>,
+    density: Option<(usize, Unit)>,
+}
+impl ColumnIndices {
+    /// Parses a csv record into a CFD sample
+    pub(crate) fn sample(
+        &self,
+        headers: &csv::StringRecord,
+        record: &csv::StringRecord,
+        index: usize,
+    ) -> Result<TemperatureVelocityField> {
+        let optional = |column: Option<(usize, Unit)>| -> Result<Option<f64>> {
+            match column.and_then(|(i, unit)| record.get(i).map(|value| (i, unit, value.trim()))) {
+                Some((i, unit, value)) if !value.is_empty() => value
+                    .parse::<f64>()
+                    .map(|value| Some(unit.to_si(value)))
+                    .map_err(|_| Error::Value {
+                        column: headers.get(i).unwrap_or_default().to_string(),
+                        value: value.to_string(),
+                    }),
+                _ => Ok(None),
+            }
+        };
+        let required = |column: (usize, Unit)| -> Result<f64> {
+            optional(Some(column))?.ok_or_else(|| Error::Value {
+                column: headers.get(column.0).unwrap_or_default().to_string(),
+                value: String::new(),
+            })
+        };
+        Ok(TemperatureVelocityField::new(
+            required(self.temperature)?,
+            optional(self.velocity)?,
+            [
+                required(self.xyz[0])?,
+                required(self.xyz[1])?,
+                required(self.xyz[2])?,
+            ],
+            index,
+        )
+        .with_pressure(optional(self.pressure)?)
+        .with_density(optional(self.density)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(values: &[&str]) -> csv::StringRecord {
+        csv::StringRecord::from(values.to_vec())
+    }
+
+    #[test]
+    fn optional_columns() {
+        let headers = record(&[
+            "Absolute Pressure (Pa)",
+            "Density (kg/m^3)",
+            "Temperature (K)",
+            "X (m)",
+            "Y (m)",
+            "Z (m)",
+        ]);
+        let values = record(&["75000", "1.2", "285", "1", "2", "3"]);
+        let sample = CsvSchema::default()
+            .locate(&headers)
+            .unwrap()
+            .sample(&headers, &values, 0)
+            .unwrap();
+        assert_eq!(sample.temperature(), 285.);
+        assert_eq!(sample.coordinates(), [1., 2., 3.]);
+        assert_eq!(sample.velocity(), None);
+        assert_eq!(sample.pressure(), None);
+        assert_eq!(sample.density(), None);
+        let sample = CsvSchema::default()
+            .with_pressure(Column::new("Absolute Pressure (Pa)", Unit::Pascal))
+            .with_density(Column::new("Density (kg/m^3)", Unit::KilogramPerCubicMeter))
+            .locate(&headers)
+            .unwrap()
+            .sample(&headers, &values, 0)
+            .unwrap();
+        assert_eq!(sample.pressure(), Some(75e3));
+        assert_eq!(sample.density(), Some(1.2));
+    }
+
+    #[test]
+    fn units() {
+        let headers = record(&["T (°C)", "x (mm)", "y (ft)", "z (in)", "p (hPa)"]);
+        let schema = CsvSchema {
+            temperature: "T (°C)".parse().unwrap(),
+            x: "x (mm)".parse().unwrap(),
+            y: "y (ft)".parse().unwrap(),
+            z: "z (in)".parse().unwrap(),
+            velocity: None,
+            pressure: None,
+            density: None,
+        }
+        .with_pressure("p (hPa)".parse().unwrap());
+        let sample = schema
+            .locate(&headers)
+            .unwrap()
+            .sample(&headers, &record(&["10", "1000", "1", "1", "750"]), 0)
+            .unwrap();
+        assert!((sample.temperature() - 283.15).abs() < 1e-12);
+        assert_eq!(sample.coordinates(), [1., 0.3048, 0.0254]);
+        assert_eq!(sample.pressure(), Some(75e3));
+    }
+
+    #[test]
+    fn missing_column() {
+        let headers = record(&["Temperature (K)", "X (m)", "Y (m)"]);
+        match CsvSchema::default().locate(&headers) {
+            Err(Error::MissingColumn { column, available }) => {
+                assert_eq!(column, "Z (m)");
+                assert_eq!(available, ["Temperature (K)", "X (m)", "Y (m)"]);
+            }
+            _ => panic!("expected a missing column error"),
+        }
+    }
+
+    #[test]
+    fn fingerprint() {
+        let schema = CsvSchema::default();
+        assert_eq!(schema.fingerprint(), CsvSchema::default().fingerprint());
+        let with_pressure = schema
+            .clone()
+            .with_pressure(Column::new("Absolute Pressure (Pa)", Unit::Pascal));
+        assert_ne!(schema.fingerprint(), with_pressure.fingerprint());
+        let in_kpa = schema
+            .clone()
+            .with_pressure(Column::new("Absolute Pressure (Pa)", Unit::KiloPascal));
+        assert_ne!(with_pressure.fingerprint(), in_kpa.fingerprint());
+        let without_velocity = CsvSchema {
+            velocity: None,
+            ..schema.clone()
+        };
+        assert_ne!(schema.fingerprint(), without_velocity.fingerprint());
+    }
+
+    #[test]
+    fn missing_opted_in_column() {
+        let headers = record(&[
+            "Temperature (K)",
+            "X (m)",
+            "Y (m)",
+            "Z (m)",
+            "Density (kg/m^3)",
+        ]);
+        let schema = CsvSchema::default()
+            .with_pressure(Column::new("Absolute Pressure (Pa)", Unit::Pascal))
+            .with_density(Column::new("Density (kg/m^3)", Unit::KilogramPerCubicMeter));
+        match schema.locate(&headers) {
+            Err(Error::MissingColumn { column, .. }) => {
+                assert_eq!(column, "Absolute Pressure (Pa)")
+            }
+            _ => panic!("expected a missing column error"),
+        }
+    }
+
+    #[test]
+    fn serde() {
+        let schema: CsvSchema = toml::from_str(
+            r#"
+            x = "x (mm)"
+            y = { header = "y", unit = "ft" }
+            pressure = "p (hPa)"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(schema.x, Column::new("x (mm)", Unit::Millimeter));
+        assert_eq!(schema.y, Column::new("y", Unit::Foot));
+        assert_eq!(schema.z, CsvSchema::default().z);
+        assert_eq!(
+            schema.pressure,
+            Some(Column::new("p (hPa)", Unit::HectoPascal))
+        );
+        assert_eq!(schema.density, None);
+        assert_eq!(
+            toml::from_str::<CsvSchema>(&toml::to_string(&schema).unwrap()).unwrap(),
+            schema
+        );
+        assert!(toml::from_str::<CsvSchema>(r#"x = "x (furlong)""#).is_err());
+    }
+}
@@ -0,0 +1,534 @@
+//! Storage backends for the CFD data, the ray tracing parameters and the OPD files
+//!
+//! A storage is selected at runtime from a URL with [from_url]:
+//!  - `file:///path/to/object` or `path/to/object`: local file system,
+//!  - `s3://bucket/key` or `s3://bucket/key?region=us-east-2`: AWS S3 bucket (requires the `s3` feature),
+//!    if the region is not given it is read from the `AWS_REGION` or `AWS_DEFAULT_REGION` environment variables.
+//!
+//! [MemoryStorage] is not addressable from a URL, it is meant to be passed directly to the loaders.
+//!
+//! The S3 objects are streamed by [Storage::reader] while they are downloaded,
+//! so a compressed CFD csv file is decoded without holding the whole object in memory.
+
+use super::{Error, Result};
+use async_trait::async_trait;
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{BufReader, Cursor, Read},
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+
+/// Interface to the storage backends
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Returns the content of the object `key`
+    fn get(&self, key: &str) -> Result<Vec<u8>>;
+    /// Writes `data` into the object `key`
+    fn put(&self, key: &str, data: &[u8]) -> Result<()>;
+    /// Returns the sorted keys of the objects starting with `prefix`
+    fn list(&self, prefix: &str) -> Result<Vec<String>>;
+    /// Returns a reader of the object `key`
+    fn reader(&self, key: &str) -> Result<Box<dyn Read + Send>> {
+        Ok(Box::new(Cursor::new(self.get(key)?)))
+    }
+    /// Returns the path of the object `key` on the local file system, if any
+    fn local_path(&self, _key: &str) -> Option<PathBuf> {
+        None
+    }
+    /// Returns the content of the object `key`
+    async fn get_async(&self, key: &str) -> Result<Vec<u8>> {
+        self.get(key)
+    }
+    /// Writes `data` into the object `key`
+    async fn put_async(&self, key: &str, data: &[u8]) -> Result<()> {
+        self.put(key, data)
+    }
+    /// Returns the sorted keys of the objects starting with `prefix`
+    async fn list_async(&self, prefix: &str) -> Result<Vec<String>> {
+        self.list(prefix)
+    }
+}
+
+/// Storage location given by a URL
+#[derive(Debug, PartialEq)]
+#[cfg_attr(not(feature = "s3"), allow(dead_code))]
+pub(crate) enum Location<'a> {
+    Local(&'a str),
+    S3 {
+        bucket: &'a str,
+        key: &'a str,
+        region: Option<&'a str>,
+    },
+}
+impl<'a> Location<'a> {
+    /// Parses a storage `url`
+    pub(crate) fn parse(url: &'a str) -> Result<Self> {
+        match url.split_once("://") {
+            None => Ok(Self::Local(url)),
+            Some(("file", path)) => Ok(Self::Local(path)),
+            Some(("s3", path)) => {
+                let (path, region) = match path.split_once("?region=") {
+                    Some((path, region)) => (path, Some(region)),
+                    None => (path, None),
+                };
+                let (bucket, key) = path.split_once('/').unwrap_or((path, ""));
+                Ok(Self::S3 {
+                    bucket,
+                    key,
+                    region,
+                })
+            }
+            Some((scheme, _)) => Err(Error::Storage(format!(
+                "unsupported storage {scheme:?} in {url:?}"
+            ))),
+        }
+    }
+}
+
+/// Returns the storage and the object key given by `url`
+pub fn from_url(url: &str) -> Result<(Box<dyn Storage>, String)> {
+    match Location::parse(url)? {
+        Location::Local(path) => Ok((Box::new(LocalStorage::default()), path.to_string())),
+        #[cfg(feature = "s3")]
+        Location::S3 {
+            bucket,
+            key,
+            region,
+        } => {
+            let storage = match region {
+                Some(region) => S3Storage::with_region(bucket, region)?,
+                None => S3Storage::new(bucket)?,
+            };
+            Ok((Box::new(storage), key.to_string()))
+        }
+        #[cfg(not(feature = "s3"))]
+        Location::S3 { .. } => Err(Error::Storage(format!(
+            "unsupported storage \"s3\" in {url:?}, the s3 feature is not enabled"
+        ))),
+    }
+}
+
+/// Local file system storage
+///
+/// The object keys are paths relative to the storage root
+#[derive(Debug, Default, Clone)]
+pub struct LocalStorage {
+    root: PathBuf,
+}
+impl LocalStorage {
+    /// Creates a new local storage within the `root` directory
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+}
+#[async_trait]
+impl Storage for LocalStorage {
+    fn get(&self, key: &str) -> Result<Vec<u8>> {
+        Ok(fs::read(self.root.join(key))?)
+    }
+    /// Writes `data` into the object `key`, creating the parent directories if needed
+    fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+        let path = self.root.join(key);
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        Ok(fs::write(path, data)?)
+    }
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let (dir, stem) = match prefix.rsplit_once('/') {
+            Some((dir, stem)) => (format!("{dir}/"), stem),
+            None => (String::new(), prefix),
+        };
+        let mut keys = vec![];
+        for entry in fs::read_dir(self.root.join(&dir).join("."))? {
+            let entry = entry?;
+            if let Some(name) = entry.file_name().to_str().filter(|n| n.starts_with(stem)) {
+                keys.push(format!("{dir}{name}"));
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+    fn reader(&self, key: &str) -> Result<Box<dyn Read + Send>> {
+        Ok(Box::new(BufReader::new(File::open(self.root.join(key))?)))
+    }
+    fn local_path(&self, key: &str) -> Option<PathBuf> {
+        Some(self.root.join(key))
+    }
+}
+
+/// In-memory storage
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    objects: RwLock<BTreeMap<String, Vec<u8>>>,
+}
+impl MemoryStorage {
+    /// Creates a new empty in-memory storage
+    pub fn new() -> Self {
+        Default::default()
+    }
+    /// Adds the object `key`
+    pub fn insert<S: Into<String>>(mut self, key: S, data: Vec<u8>) -> Self {
+        self.objects
+            .get_mut()
+            .expect("poisoned memory storage")
+            .insert(key.into(), data);
+        self
+    }
+}
+#[async_trait]
+impl Storage for MemoryStorage {
+    fn get(&self, key: &str) -> Result<Vec<u8>> {
+        self.objects
+            .read()
+            .expect("poisoned memory storage")
+            .get(key)
+            .cloned()
+            .ok_or_else(|| Error::Storage(format!("object {key:?} not found")))
+    }
+    fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+        self.objects
+            .write()
+            .expect("poisoned memory storage")
+            .insert(key.to_string(), data.to_vec());
+        Ok(())
+    }
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        Ok(self
+            .objects
+            .read()
+            .expect("poisoned memory storage")
+            .keys()
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect())
+    }
+}
+
+/// AWS S3 storage
+#[cfg(feature = "s3")]
+pub struct S3Storage {
+    bucket: s3::bucket::Bucket,
+}
+#[cfg(feature = "s3")]
+impl S3Storage {
+    /// Creates a new S3 storage for the `bucket` name
+    ///
+    /// The region is read from the `AWS_REGION` or `AWS_DEFAULT_REGION` environment variables
+    pub fn new(bucket: &str) -> Result<Self> {
+        let region = std::env::var("AWS_REGION")
+            .or_else(|_| std::env::var("AWS_DEFAULT_REGION"))
+            .map_err(|_| {
+                Error::Storage("`AWS_REGION` environment variable is not set".to_string())
+            })?;
+        Self::with_region(bucket, &region)
+    }
+    /// Creates a new S3 storage for the `bucket` name in the given `region`
+    pub fn with_region(bucket: &str, region: &str) -> Result<Self> {
+        use s3::creds::Credentials;
+        let credentials = Credentials::default().map_err(s3::error::S3Error::Credentials)?;
+        Ok(Self {
+            bucket: s3::bucket::Bucket::new(bucket, region.parse()?, credentials)?,
+        })
+    }
+}
+#[cfg(feature = "s3")]
+#[async_trait]
+impl Storage for S3Storage {
+    fn get(&self, key: &str) -> Result<Vec<u8>> {
+        block_on(self.get_async(key))
+    }
+    fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+        block_on(self.put_async(key, data))
+    }
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        block_on(self.list_async(prefix))
+    }
+    /// Returns a reader of the object `key`, streaming the object while it is downloaded
+    ///
+    /// The object status is checked before the object is downloaded in a background thread,
+    /// at most [S3_STREAM_CHUNKS] chunks of the object are buffered ahead of the reader
+    fn reader(&self, key: &str) -> Result<Box<dyn Read + Send>> {
+        let (_, code) = block_on(async { Ok(self.bucket.head_object(key).await?) })?;
+        if code != 200 {
+            return Err(Error::Storage(format!(
+                "failed to get s3://{}/{key} (HTTP {code})",
+                self.bucket.name
+            )));
+        }
+        let (sender, receiver) = std::sync::mpsc::sync_channel(S3_STREAM_CHUNKS);
+        let bucket = self.bucket.clone();
+        let key = key.to_string();
+        std::thread::spawn(move || {
+            let mut writer = ChannelWriter(sender.clone());
+            let status = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .map_err(|e| e.to_string())
+                .and_then(|runtime| {
+                    runtime
+                        .block_on(bucket.get_object_stream(&key, &mut writer))
+                        .map_err(|e| e.to_string())
+                });
+            let error = match status {
+                Ok(200) => return,
+                Ok(code) => format!("failed to get s3://{}/{key} (HTTP {code})", bucket.name),
+                Err(e) => format!("failed to get s3://{}/{key}: {e}", bucket.name),
+            };
+            // the reader may have been dropped already
+            let _ = sender.send(Err(std::io::Error::other(error)));
+        });
+        Ok(Box::new(ChannelReader {
+            receiver,
+            chunk: Cursor::new(vec![]),
+        }))
+    }
+    async fn get_async(&self, key: &str) -> Result<Vec<u8>> {
+        let (data, code) = self.bucket.get_object(key).await?;
+        if code != 200 {
+            return Err(Error::Storage(format!(
+                "failed to get s3://{}/{key} (HTTP {code})",
+                self.bucket.name
+            )));
+        }
+        Ok(data)
+    }
+    async fn put_async(&self, key: &str, data: &[u8]) -> Result<()> {
+        let (_, code) = self.bucket.put_object(key, data).await?;
+        if code != 200 {
+            return Err(Error::Storage(format!(
+                "failed to put s3://{}/{key} (HTTP {code})",
+                self.bucket.name
+            )));
+        }
+        Ok(())
+    }
+    async fn list_async(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut keys: Vec<_> = self
+            .bucket
+            .list(prefix.to_string(), None)
+            .await?
+            .into_iter()
+            .flat_map(|res| res.contents.into_iter().map(|object| object.key))
+            .collect();
+        keys.sort();
+        Ok(keys)
+    }
+}
+
+/// Maximum number of S3 object chunks buffered ahead of a [ChannelReader]
+#[cfg(feature = "s3")]
+pub const S3_STREAM_CHUNKS: usize = 64;
+
+/// Asynchronous writer sending the chunks of a S3 object to a [ChannelReader]
+///
+/// The writer runs on its own download thread and blocks it when the reader falls behind
+#[cfg(feature = "s3")]
+struct ChannelWriter(std::sync::mpsc::SyncSender<std::io::Result<Vec<u8>>>);
+#[cfg(feature = "s3")]
+impl tokio::io::AsyncWrite for ChannelWriter {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        std::task::Poll::Ready(
+            self.0
+                .send(Ok(buf.to_vec()))
+                .map(|_| buf.len())
+                .map_err(|_| std::io::ErrorKind::BrokenPipe.into()),
+        )
+    }
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+    fn poll_shutdown(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+/// Blocking reader of the chunks of a S3 object sent by a [ChannelWriter]
+#[cfg(feature = "s3")]
+struct ChannelReader {
+    receiver: std::sync::mpsc::Receiver<std::io::Result<Vec<u8>>>,
+    chunk: Cursor<Vec<u8>>,
+}
+#[cfg(feature = "s3")]
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let n = self.chunk.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            match self.receiver.recv() {
+                Ok(chunk) => self.chunk = Cursor::new(chunk?),
+                // the download thread is done
+                Err(_) => return Ok(0),
+            }
+        }
+    }
+}
+
+/// Runs a S3 request to completion from a blocking context
+///
+/// Within a current thread tokio runtime, where [tokio::task::block_in_place] is not allowed,
+/// the request runs to completion on a new thread
+#[cfg(feature = "s3")]
+fn block_on<T, F>(future: F) -> Result<T>
+where
+    T: Send,
+    F: std::future::Future<Output = Result<T>> + Send,
+{
+    use tokio::runtime::{Builder, Handle, RuntimeFlavor};
+    let run = |future: F| -> Result<T> {
+        Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| Error::Storage(format!("failed to build tokio runtime: {e}")))?
+            .block_on(future)
+    };
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(|| handle.block_on(future))
+        }
+        Ok(_) => std::thread::scope(|scope| {
+            scope
+                .spawn(|| run(future))
+                .join()
+                .unwrap_or_else(|e| std::panic::resume_unwind(e))
+        }),
+        Err(_) => run(future),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        gmt::{Gmt, Source},
+        testing, OpdFile, RunConfig,
+    };
+
+    #[test]
+    fn memory_storage() {
+        let storage = MemoryStorage::new()
+            .insert("optvol/optvol_1.csv.gz", vec![1])
+            .insert("optvol/optvol_0.csv.gz", vec![0]);
+        storage.put("opd/optvol_0.bin", &[2, 3]).unwrap();
+        assert_eq!(storage.get("optvol/optvol_1.csv.gz").unwrap(), [1]);
+        assert_eq!(storage.get("opd/optvol_0.bin").unwrap(), [2, 3]);
+        assert!(matches!(
+            storage.get("optvol/optvol_2.csv.gz"),
+            Err(Error::Storage(_))
+        ));
+        assert_eq!(
+            storage.list("optvol/").unwrap(),
+            ["optvol/optvol_0.csv.gz", "optvol/optvol_1.csv.gz"]
+        );
+        let mut data = vec![];
+        storage
+            .reader("opd/optvol_0.bin")
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        assert_eq!(data, [2, 3]);
+        assert_eq!(storage.local_path("opd/optvol_0.bin"), None);
+    }
+
+    #[test]
+    fn urls() {
+        assert_eq!(
+            Location::parse("file:///data/optvol.csv.gz").unwrap(),
+            Location::Local("/data/optvol.csv.gz")
+        );
+        assert_eq!(
+            Location::parse("data/optvol.csv.gz").unwrap(),
+            Location::Local("data/optvol.csv.gz")
+        );
+        assert_eq!(
+            Location::parse("s3://gmto.cfd.2022/CASES/optvol?region=us-east-2").unwrap(),
+            Location::S3 {
+                bucket: "gmto.cfd.2022",
+                key: "CASES/optvol",
+                region: Some("us-east-2")
+            }
+        );
+        assert_eq!(
+            Location::parse("s3://cfd.archive").unwrap(),
+            Location::S3 {
+                bucket: "cfd.archive",
+                key: "",
+                region: None
+            }
+        );
+        assert!(matches!(
+            Location::parse("gs://bucket/key"),
+            Err(Error::Storage(_))
+        ));
+        let (storage, key) = from_url("file:///data/optvol.csv.gz").unwrap();
+        assert_eq!(key, "/data/optvol.csv.gz");
+        assert_eq!(
+            storage.local_path(&key),
+            Some(PathBuf::from("/data/optvol.csv.gz"))
+        );
+    }
+
+    #[test]
+    fn pipeline() {
+        let dir =
+            std::env::temp_dir().join(format!("cfd_raytrace-pipeline-{}", std::process::id()));
+        let url = format!("file://{}", dir.display());
+        // CFD samples in the GMT OSS frame
+        let samples = testing::lattice([-14., -14., -6.], [14., 14., 30.], 2., |[x, y, z]| {
+            285. + (x / 3.).sin() + 0.5 * (y / 5.).cos() + 0.05 * z
+        });
+        let storage = LocalStorage::new(&dir);
+        storage
+            .put("optvol/optvol_0.csv.gz", &testing::csv_gz(&samples))
+            .unwrap();
+        Gmt::default()
+            .rays(12, Source::on_axis())
+            .unwrap()
+            .to_npz_url(format!("{url}/rays.npz"))
+            .unwrap();
+        let config = RunConfig::from_toml(&format!(
+            r#"
+            [input]
+            cfd = "{url}/optvol/optvol"
+            cfd_index = 0
+            rays = "{url}/rays.npz"
+
+            [ray_tracing]
+            interpolation = {{ method = "shepard", radius = 3 }}
+
+            [output]
+            url = "{url}/opd"
+            name = "{{n_px}}/{{stem}}.bin"
+            "#
+        ))
+        .unwrap();
+        let (opd_file, key) = config.run().unwrap();
+        assert_eq!(key, format!("{}/opd/12/optvol_0.bin", dir.display()));
+        assert!(opd_file.opd.mean.is_finite() && opd_file.opd.mean != 0.);
+        let saved = OpdFile::load(format!("file://{key}")).unwrap();
+        assert_eq!(saved.opd.values, opd_file.opd.values);
+        let metadata = saved.metadata.unwrap();
+        assert_eq!(metadata.n_px, 12);
+        assert_eq!(
+            metadata.cfd_file.as_deref(),
+            Some(format!("{}/optvol/optvol_0.csv.gz", dir.display()).as_str())
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,114 @@
+//! Synthetic CFD data for the unit tests
+
+use super::TemperatureVelocityField;
+use flate2::{write::GzEncoder, Compression};
+use rstar::RTree;
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    io::Write,
+};
+
+/// Returns the CFD samples on a regular lattice from `lower` to `upper` with the given `spacing`,
+/// the temperature being given by `temperature` at each sample location
+pub(crate) fn lattice<F>(
+    lower: [f64; 3],
+    upper: [f64; 3],
+    spacing: f64,
+    temperature: F,
+) -> Vec<TemperatureVelocityField>
+where
+    F: Fn([f64; 3]) -> f64,
+{
+    let n: Vec<usize> = (0..3)
+        .map(|i| ((upper[i] - lower[i]) / spacing).round() as usize + 1)
+        .collect();
+    let mut samples = Vec::with_capacity(n.iter().product());
+    for k in 0..n[2] {
+        for j in 0..n[1] {
+            for i in 0..n[0] {
+                let xyz = [
+                    lower[0] + i as f64 * spacing,
+                    lower[1] + j as f64 * spacing,
+                    lower[2] + k as f64 * spacing,
+                ];
+                let index = samples.len();
+                samples.push(TemperatureVelocityField::new(
+                    temperature(xyz),
+                    Some(1.),
+                    xyz,
+                    index,
+                ));
+            }
+        }
+    }
+    samples
+}
+
+/// Returns a smooth temperature field over the GMT ray tracing volume
+pub(crate) fn dome() -> RTree<TemperatureVelocityField> {
+    RTree::bulk_load(lattice(
+        [-14., -14., -8.],
+        [14., 14., 26.],
+        2.,
+        |[x, y, z]| 285. + (x / 3.).sin() + 0.5 * (y / 5.).cos() + 0.05 * z,
+    ))
+}
+
+/// Returns the gzip compressed csv file of the CFD `samples` with the [CsvSchema](crate::CsvSchema) default headers
+pub(crate) fn csv_gz(samples: &[TemperatureVelocityField]) -> Vec<u8> {
+    let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+    writeln!(
+        gz,
+        "Temperature (K),X (m),Y (m),Z (m),Velocity: Magnitude (m/s)"
+    )
+    .unwrap();
+    for sample in samples {
+        let [x, y, z] = sample.coordinates();
+        writeln!(
+            gz,
+            "{},{x},{y},{z},{}",
+            sample.temperature(),
+            sample.velocity().unwrap_or_default()
+        )
+        .unwrap();
+    }
+    gz.finish().unwrap()
+}
+
+/// Heap allocator counting the bytes allocated by the current thread
+pub(crate) struct CountingAllocator;
+thread_local! {
+    static ALLOCATED: Cell<isize> = const { Cell::new(0) };
+    static PEAK: Cell<isize> = const { Cell::new(0) };
+}
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let _ = ALLOCATED.try_with(|allocated| {
+                allocated.set(allocated.get() + layout.size() as isize);
+                let _ = PEAK.try_with(|peak| peak.set(peak.get().max(allocated.get())));
+            });
+        }
+        ptr
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        let _ =
+            ALLOCATED.try_with(|allocated| allocated.set(allocated.get() - layout.size() as isize));
+    }
+}
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Returns the result of `f` with the peak and the retained heap allocations of `f`
+/// on the current thread in bytes
+pub(crate) fn heap_usage<T, F: FnOnce() -> T>(f: F) -> (T, usize, usize) {
+    let start = ALLOCATED.with(|allocated| allocated.get());
+    PEAK.with(|peak| peak.set(start));
+    let value = f();
+    let peak = PEAK.with(|peak| peak.get()) - start;
+    let retained = ALLOCATED.with(|allocated| allocated.get()) - start;
+    (value, peak.max(0) as usize, retained.max(0) as usize)
+}
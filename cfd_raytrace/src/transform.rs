@@ -0,0 +1,150 @@
+use nalgebra::{Isometry3, Point3, Translation3, UnitQuaternion, Vector3};
+
+// M1 vertex z coordinate in OSS reference frame
+const OSS_M1_VERTEX: f64 = 3.9;
+
+/// Rigid body transform from the CFD frame to the ray tracing frame
+///
+/// The transform is a rotation followed by a translation
+/// and it is applied to the CFD samples coordinates when they are loaded.
+/// The default transform is the [GMT OSS](RigidTransform::oss) transform
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RigidTransform(Isometry3<f64>);
+impl Default for RigidTransform {
+    fn default() -> Self {
+        Self::oss()
+    }
+}
+impl RigidTransform {
+    /// Creates a new transform from a `translation` and a `rotation`
+    ///
+    /// The rotation is given as a rotation axis scaled by the rotation angle in radians
+    pub fn new(translation: [f64; 3], rotation: [f64; 3]) -> Self {
+        Self(Isometry3::new(translation.into(), rotation.into()))
+    }
+    /// Identity transform, the CFD frame is the ray tracing frame
+    pub fn identity() -> Self {
+        Self(Isometry3::identity())
+    }
+    /// Translation only transform
+    pub fn translation(translation: [f64; 3]) -> Self {
+        Self(Translation3::from(translation).into())
+    }
+    /// Rotation only transform from the Euler angles `roll`, `pitch` and `yaw` in radians
+    ///
+    /// The rotations are applied in the order roll, pitch and yaw
+    /// around the x, y and z axes, respectively
+    pub fn euler_angles(roll: f64, pitch: f64, yaw: f64) -> Self {
+        Self(Isometry3::from_parts(
+            Translation3::identity(),
+            UnitQuaternion::from_euler_angles(roll, pitch, yaw),
+        ))
+    }
+    /// GMT OSS transform
+    ///
+    /// The CFD frame is the GMT OSS frame and the ray tracing frame origin is at M1 vertex
+    pub fn oss() -> Self {
+        Self::translation([0., 0., -OSS_M1_VERTEX])
+    }
+    /// Telescope pointing transform
+    ///
+    /// The CFD frame is a dome fixed frame with its origin at the GMT OSS origin,
+    /// the z axis toward zenith and the azimuth angle measured from the x axis toward the y axis.
+    /// The CFD samples are rotated into the frame of the telescope pointing
+    /// at the `zenith` and `azimuth` angles in radians and the ray tracing frame origin is at M1 vertex
+    pub fn pointing(zenith: f64, azimuth: f64) -> Self {
+        Self::euler_angles(0., 0., -azimuth)
+            .then(Self::euler_angles(0., -zenith, 0.))
+            .then(Self::oss())
+    }
+    /// Returns the transform applying `self` followed by `other`
+    pub fn then(self, other: Self) -> Self {
+        Self(other.0 * self.0)
+    }
+    /// Returns the inverse transform
+    pub fn inverse(&self) -> Self {
+        Self(self.0.inverse())
+    }
+    /// Returns the rotation matrix in row major order
+    pub fn rotation_matrix(&self) -> [[f64; 3]; 3] {
+        self.0
+            .rotation
+            .to_rotation_matrix()
+            .into_inner()
+            .transpose()
+            .into()
+    }
+    /// Returns the translation vector
+    pub fn translation_vector(&self) -> [f64; 3] {
+        self.0.translation.vector.into()
+    }
+    /// Transforms a point from the CFD frame to the ray tracing frame
+    pub fn transform_point(&self, point: [f64; 3]) -> [f64; 3] {
+        self.0.transform_point(&Point3::from(point)).into()
+    }
+    /// Transforms a vector from the CFD frame to the ray tracing frame
+    pub fn transform_vector(&self, vector: [f64; 3]) -> [f64; 3] {
+        self.0.transform_vector(&Vector3::from(vector)).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: [f64; 3], b: [f64; 3]) {
+        let error = a
+            .iter()
+            .zip(b)
+            .map(|(a, b)| (a - b).abs())
+            .fold(0., f64::max);
+        assert!(error < 1e-14, "{a:?} != {b:?}");
+    }
+
+    #[test]
+    fn pointing() {
+        let (zenith, azimuth) = (30f64.to_radians(), 90f64.to_radians());
+        let transform = RigidTransform::pointing(zenith, azimuth);
+        // rotated by -90deg around z: (2,-1,3), then by -30deg around y
+        let (sin, cos) = zenith.sin_cos();
+        assert_close(
+            transform.transform_point([1., 2., 3.]),
+            [
+                2. * cos - 3. * sin,
+                -1.,
+                2. * sin + 3. * cos - OSS_M1_VERTEX,
+            ],
+        );
+        // the pointing direction is the ray tracing frame z axis
+        let direction = [
+            zenith.sin() * azimuth.cos(),
+            zenith.sin() * azimuth.sin(),
+            zenith.cos(),
+        ];
+        assert_close(transform.transform_vector(direction), [0., 0., 1.]);
+        assert_close(
+            RigidTransform::pointing(0., 1.2).transform_point([0., 0., 10.]),
+            [0., 0., 10. - OSS_M1_VERTEX],
+        );
+    }
+
+    #[test]
+    fn inverse() {
+        let transform = RigidTransform::pointing(0.5, 1.)
+            .then(RigidTransform::new([0.1, -0.2, 0.3], [0.01, 0.02, -0.03]));
+        for identity in [
+            transform.then(transform.inverse()),
+            transform.inverse().then(transform),
+        ] {
+            for (row, expected) in identity.rotation_matrix().into_iter().zip([
+                [1., 0., 0.],
+                [0., 1., 0.],
+                [0., 0., 1.],
+            ]) {
+                assert_close(row, expected);
+            }
+            assert_close(identity.translation_vector(), [0.; 3]);
+            assert_close(identity.transform_point([1., 2., 3.]), [1., 2., 3.]);
+        }
+    }
+}